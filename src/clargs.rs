@@ -1,16 +1,339 @@
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+use crate::crypt::crypt_syncer::DeletePolicy;
+use crate::crypt::index::Layout;
+use crate::encoder::cryptor::X25519_KEY_LEN;
+use crate::encoder::padder::Padding;
+use crate::hasher::Kdf;
+use crate::key_source::parse_x25519_key;
+use crate::key_source::KeySource;
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "csync")]
 pub struct Opts {
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+
+    /// the file or directory to encrypt; required unless there is a subcommand
     #[structopt(parse(from_os_str))]
-    pub source: PathBuf,
+    pub source: Option<PathBuf>,
 
+    /// the directory in which the encrypted tree is stored, created if it does not exist; required
+    /// unless there is a subcommand
     #[structopt(short = "o", long = "out", parse(from_os_str))]
-    pub out_dir: PathBuf,
+    pub out_dir: Option<PathBuf>,
 
     /// watch for changes in `source`, and sync when changes are detected
     #[structopt(short = "w", long = "watch")]
     pub watch: bool,
+
+    /// what to do with the ciphertexts of files that were deleted or renamed in `source`; `mirror`
+    /// removes them, `archive` keeps them forever
+    #[structopt(
+        long = "delete",
+        default_value = "mirror",
+        raw(possible_values = r#"&["mirror", "archive"]"#)
+    )]
+    pub delete_policy: DeletePolicy,
+
+    /// print what the sync would do, without writing anything
+    #[structopt(short = "n", long = "dry-run", conflicts_with = "watch")]
+    pub dry_run: bool,
+
+    /// encrypt the content of files to this public key, as printed by `keygen`, so that only its
+    /// identity can decrypt them; may be given more than once
+    #[structopt(
+        short = "r",
+        long = "recipient",
+        parse(try_from_str = "parse_x25519_key"),
+        raw(number_of_values = "1")
+    )]
+    pub recipients: Vec<[u8; X25519_KEY_LEN]>,
+
+    /// decrypt the content of files that were encrypted to public keys with the identity in this
+    /// file, as written by `keygen`
+    #[structopt(long = "identity", parse(from_os_str), raw(global = "true"))]
+    pub identity_file: Option<PathBuf>,
+
+    /// read the key from this file instead of prompting for a password
+    #[structopt(
+        long = "key-file",
+        parse(from_os_str),
+        raw(
+            global = "true",
+            conflicts_with_all = r#"&["key_env", "key_fd", "key_command"]"#
+        )
+    )]
+    pub key_file: Option<PathBuf>,
+
+    /// read the key from this environment variable instead of prompting for a password
+    #[structopt(
+        long = "key-env",
+        raw(global = "true", conflicts_with_all = r#"&["key_fd", "key_command"]"#)
+    )]
+    pub key_env: Option<String>,
+
+    /// read the key from this inherited file descriptor instead of prompting for a password
+    #[structopt(
+        long = "key-fd",
+        raw(global = "true", conflicts_with = r#""key_command""#)
+    )]
+    pub key_fd: Option<i32>,
+
+    /// read the key from the stdout of this shell command instead of prompting for a password,
+    /// e.g. `pass show backup`
+    #[structopt(long = "key-command", raw(global = "true"))]
+    pub key_command: Option<String>,
+}
+
+impl Opts {
+    /// # Returns
+    ///
+    /// Where to read the key from; the tty, unless told otherwise.
+    pub fn key_source(&self) -> KeySource {
+        match (
+            &self.key_file,
+            &self.key_env,
+            self.key_fd,
+            &self.key_command,
+        ) {
+            (Some(path), _, _, _) => KeySource::File(path.clone()),
+            (_, Some(var), _, _) => KeySource::Env(var.clone()),
+            (_, _, Some(fd), _) => KeySource::Fd(fd),
+            (_, _, _, Some(command)) => KeySource::Command(command.clone()),
+            _ => KeySource::Prompt,
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// create the config of a new encrypted directory, with a random salt for the key; done by the
+    /// first sync otherwise, with the default KDF
+    #[structopt(name = "init")]
+    Init {
+        /// the directory in which the encrypted tree will be stored, created if it does not exist
+        #[structopt(parse(from_os_str))]
+        out_dir: PathBuf,
+
+        /// the KDF that stretches the key; argon2id and scrypt are memory-hard, pbkdf2 is only
+        /// there for compatibility
+        #[structopt(
+            long = "kdf",
+            default_value = "argon2id",
+            raw(possible_values = "&Kdf::NAMES")
+        )]
+        kdf: String,
+
+        /// memory the KDF uses, in MiB; 64 for argon2id and 128 for scrypt by default, and at
+        /// least a quarter of that
+        #[structopt(long = "kdf-memory")]
+        memory_mib: Option<u32>,
+
+        /// passes over the memory for argon2id, 3 by default, or iterations for pbkdf2, 131072 by
+        /// default, and at least 32768
+        #[structopt(long = "kdf-time")]
+        time_cost: Option<u32>,
+
+        /// lanes for argon2id, 4 by default, or `p` for scrypt, 1 by default
+        #[structopt(long = "kdf-parallelism")]
+        parallelism: Option<u32>,
+
+        /// how to pad the content of files before encrypting it, so that the size of a ciphertext
+        /// gives away less about that of its file; padme adds at most 12%, power-of-two up to 100%
+        #[structopt(
+            long = "padding",
+            default_value = "none",
+            raw(possible_values = "&Padding::NAMES")
+        )]
+        padding: String,
+
+        /// how to lay out the encrypted tree; tree mirrors the directories of the source, with
+        /// encrypted names, and buckets stores every file under a random id in fixed buckets,
+        /// with the real tree only in an encrypted index, so that its shape does not show
+        #[structopt(
+            long = "layout",
+            default_value = "tree",
+            raw(possible_values = "&Layout::NAMES")
+        )]
+        layout: String,
+    },
+
+    /// generate an identity to encrypt to with `--recipient`, and print its public key
+    #[structopt(name = "keygen")]
+    Keygen {
+        /// the file to write the secret key to; must not exist
+        #[structopt(short = "o", long = "out", parse(from_os_str))]
+        out: PathBuf,
+    },
+
+    /// change the key of an encrypted directory, without re-encrypting anything but its master key
+    #[structopt(name = "passwd")]
+    Passwd {
+        /// the encrypted directory, i.e. the `out_dir` of some sync
+        #[structopt(parse(from_os_str))]
+        out_dir: PathBuf,
+
+        #[structopt(flatten)]
+        new_key: NewKeyOpts,
+    },
+
+    /// encrypt every file and name of an encrypted directory again under a new master key, e.g.
+    /// after a key was compromised; resumes where it left off if it was interrupted
+    #[structopt(name = "rekey")]
+    Rekey {
+        /// the encrypted directory, i.e. the `out_dir` of some sync
+        #[structopt(parse(from_os_str))]
+        out_dir: PathBuf,
+
+        #[structopt(flatten)]
+        new_key: NewKeyOpts,
+    },
+
+    /// add, list or remove the keys that unlock an encrypted directory
+    #[structopt(name = "slot")]
+    Slot {
+        #[structopt(subcommand)]
+        command: SlotCommand,
+    },
+
+    /// rebuild the plaintext tree from an encrypted directory
+    #[structopt(name = "restore")]
+    Restore {
+        /// the encrypted directory, i.e. the `out_dir` of some sync
+        #[structopt(parse(from_os_str))]
+        source: PathBuf,
+
+        /// the directory in which the plaintext tree is rebuilt, created if it does not exist
+        #[structopt(short = "o", long = "out", parse(from_os_str))]
+        out_dir: PathBuf,
+
+        /// restore only this file or directory, given as its plaintext path in the encrypted
+        /// directory, e.g. as listed by `ls`
+        #[structopt(short = "p", long = "path", parse(from_os_str))]
+        path: Option<PathBuf>,
+    },
+
+    /// check that an encrypted directory decrypts back to its source, byte for byte
+    #[structopt(name = "verify")]
+    Verify {
+        /// the file or directory that was synced
+        #[structopt(parse(from_os_str))]
+        source: PathBuf,
+
+        /// the directory in which the encrypted tree is stored
+        #[structopt(short = "o", long = "out", parse(from_os_str))]
+        out_dir: PathBuf,
+    },
+
+    /// check every ciphertext in an encrypted directory against the integrity manifest of its
+    /// last sync, without decrypting anything, and print its root; two encrypted directories hold
+    /// the same ciphertexts if and only if their roots are equal
+    #[structopt(name = "root")]
+    Root {
+        /// the encrypted directory, i.e. the `out_dir` of some sync
+        #[structopt(parse(from_os_str))]
+        out_dir: PathBuf,
+    },
+
+    /// list what is in an encrypted directory, with decrypted names
+    #[structopt(name = "ls")]
+    Ls {
+        /// the encrypted directory, i.e. the `out_dir` of some sync
+        #[structopt(parse(from_os_str))]
+        source: PathBuf,
+
+        /// print one path per line, with its original size, if known, and its ciphertext size,
+        /// instead of a tree
+        #[structopt(short = "l", long = "long")]
+        long: bool,
+    },
+
+    /// decrypt a single file from an encrypted directory to stdout
+    #[structopt(name = "cat")]
+    Cat {
+        /// the encrypted directory, i.e. the `out_dir` of some sync
+        #[structopt(parse(from_os_str))]
+        source: PathBuf,
+
+        /// the plaintext path of the file in the encrypted directory, e.g. as listed by `ls`
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum SlotCommand {
+    /// add a key that unlocks the encrypted directory, given a key that already does
+    #[structopt(name = "add")]
+    Add {
+        /// the encrypted directory, i.e. the `out_dir` of some sync
+        #[structopt(parse(from_os_str))]
+        out_dir: PathBuf,
+
+        /// e.g. whose key it is
+        #[structopt(short = "l", long = "label", default_value = "")]
+        label: String,
+
+        #[structopt(flatten)]
+        new_key: NewKeyOpts,
+    },
+
+    /// list the key slots of the encrypted directory, without reading any key
+    #[structopt(name = "list")]
+    List {
+        /// the encrypted directory, i.e. the `out_dir` of some sync
+        #[structopt(parse(from_os_str))]
+        out_dir: PathBuf,
+    },
+
+    /// remove a key slot, given a key that unlocks the encrypted directory
+    #[structopt(name = "remove")]
+    Remove {
+        /// the encrypted directory, i.e. the `out_dir` of some sync
+        #[structopt(parse(from_os_str))]
+        out_dir: PathBuf,
+
+        /// the id of the slot, as listed by `slot list`
+        id: u32,
+
+        /// rekey the encrypted directory right after, as `rekey` does, so that whoever held the
+        /// slot cannot decrypt it even with a copy of the old master key; other slots are dropped
+        #[structopt(long = "rotate")]
+        rotate: bool,
+
+        // only read with `--rotate`
+        #[structopt(flatten)]
+        new_key: NewKeyOpts,
+    },
+}
+
+/// Where to read a new key from, for commands that set one.
+#[derive(StructOpt, Debug)]
+pub struct NewKeyOpts {
+    /// read the new key from this file instead of prompting for a new password
+    #[structopt(
+        long = "new-key-file",
+        parse(from_os_str),
+        conflicts_with = "new_key_env"
+    )]
+    pub new_key_file: Option<PathBuf>,
+
+    /// read the new key from this environment variable instead of prompting for a new password
+    #[structopt(long = "new-key-env")]
+    pub new_key_env: Option<String>,
+}
+
+impl NewKeyOpts {
+    /// # Returns
+    ///
+    /// Where to read the new key from; the tty, unless told otherwise.
+    pub fn key_source(&self) -> KeySource {
+        match (&self.new_key_file, &self.new_key_env) {
+            (Some(path), _) => KeySource::File(path.clone()),
+            (_, Some(var)) => KeySource::Env(var.clone()),
+            _ => KeySource::Prompt,
+        }
+    }
 }
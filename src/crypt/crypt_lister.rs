@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::fs::canonicalize;
+use std::fs::symlink_metadata;
+use std::io::Error;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::crypt::crypt_restorer::*;
+use crate::crypt::index::*;
+use crate::crypt::key_schedule::*;
+use crate::crypt::manifest::*;
+use crate::crypt::name_cipher::*;
+use crate::util::*;
+
+/// Lists what is in an encrypted tree, without decrypting anything but the names.
+#[derive(Debug)]
+pub struct CryptLister {
+    layout: Layout,    // how the encrypted tree is laid out
+    names: NameCipher, // how basenames are encrypted
+    source: PathBuf,   // path to the encrypted dir, i.e. the `out_dir` of some sync
+}
+
+/// A single path in the encrypted tree.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListEntry {
+    pub path: PathBuf, // plaintext, relative to the root of the tree
+    // ciphertext, relative to the encrypted dir; empty for directories in `Layout::Buckets`, which
+    // only exist in the index
+    pub target: PathBuf,
+    pub is_dir: bool,
+    pub size: u64, // of the ciphertext
+    // of the plaintext as of when it was synced, if the manifest knows it
+    pub opt_original_size: Option<u64>,
+}
+
+impl CryptLister {
+    /// Decrypt the name of every path in the encrypted tree.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `keys`: the keys of the tree
+    ///
+    /// # Returns
+    ///
+    /// An entry for every path that could be decrypted, sorted by plaintext path, so that every
+    /// directory comes right before what is in it.
+    pub fn list(&self, keys: &Keys) -> Result<Vec<ListEntry>, Error> {
+        let manifest = Manifest::load(&self.source, keys)?;
+
+        let mut entries: Vec<ListEntry> = match self.layout {
+            Layout::Tree => path_plaintexts(&self.source, keys, self.names)?
+                .into_iter()
+                .filter_map(|(target, path)| {
+                    let metadata = symlink_metadata(self.source.join(&target)).ok()?;
+                    Some(ListEntry {
+                        opt_original_size: manifest.entries.get(&path).map(|entry| entry.size),
+                        is_dir: metadata.is_dir(),
+                        size: match metadata.is_dir() {
+                            true => 0,
+                            false => metadata.len(),
+                        },
+                        path,
+                        target,
+                    })
+                })
+                .collect(),
+            // files whose ciphertext is gone are left out, as they would be above
+            Layout::Buckets => Index::load(&self.source, keys)?
+                .entries
+                .into_iter()
+                .filter_map(|(path, index_entry)| {
+                    let (target, is_dir, size) = match index_entry.target() {
+                        Some(target) => {
+                            let size = symlink_metadata(self.source.join(&target)).ok()?.len();
+                            (target, false, size)
+                        }
+                        None => (PathBuf::new(), true, 0),
+                    };
+                    Some(ListEntry {
+                        opt_original_size: manifest.entries.get(&path).map(|entry| entry.size),
+                        path,
+                        target,
+                        is_dir,
+                        size,
+                    })
+                })
+                .collect(),
+        };
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    /// # Parameters
+    ///
+    /// 1. `source`: the encrypted dir, i.e. the `out_dir` of some sync
+    pub fn new(source: &Path) -> Result<Self, Error> {
+        let source = canonicalize(source)?;
+        if !source.is_dir() {
+            return Err(err!("`{:?}` is not a directory", source));
+        }
+
+        Ok(Self {
+            layout: Layout::Tree,
+            names: NameCipher::default(),
+            source,
+        })
+    }
+
+    /// Find what is where as `layout` says; `Layout::Tree` unless set otherwise.
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Decrypt basenames as `names` says; `NameCipher::AesSiv` unless set otherwise.
+    pub fn with_name_cipher(mut self, names: NameCipher) -> Self {
+        self.names = names;
+        self
+    }
+}
+
+/// Format `entries` like `tree` does, one basename per line, with directories ending in `/`.
+///
+/// # Parameters
+///
+/// 1. `entries`: what `CryptLister::list` returned
+pub fn format_tree(entries: &[ListEntry]) -> String {
+    // the children of each directory, in order; the roots are the children of ""
+    let mut children: HashMap<&Path, Vec<&ListEntry>> = HashMap::new();
+    entries.iter().for_each(|entry| {
+        let parent = entry.path.parent().unwrap_or(Path::new(""));
+        children.entry(parent).or_default().push(entry);
+    });
+
+    fn format_children(
+        dir: &Path,
+        prefix: &str,
+        children: &HashMap<&Path, Vec<&ListEntry>>,
+        lines: &mut Vec<String>,
+    ) {
+        let entries = match children.get(dir) {
+            Some(entries) => entries,
+            None => return,
+        };
+
+        for (i, entry) in entries.iter().enumerate() {
+            let is_last = i + 1 == entries.len();
+            let basename = entry.path.file_name().unwrap_or_default().to_string_lossy();
+            let suffix = if entry.is_dir { "/" } else { "" };
+            // the roots hang off nothing
+            let (branch, indent) = match (dir.as_os_str().is_empty(), is_last) {
+                (true, _) => ("", ""),
+                (false, false) => ("├── ", "│   "),
+                (false, true) => ("└── ", "    "),
+            };
+            lines.push(format!("{}{}{}{}", prefix, branch, basename, suffix));
+            format_children(
+                &entry.path,
+                &format!("{}{}", prefix, indent),
+                children,
+                lines,
+            );
+        }
+    }
+
+    let mut lines = Vec::new();
+    format_children(Path::new(""), "", &children, &mut lines);
+    lines.join("\n")
+}
+
+/// Format `entries` one path per line, each with the size it had when it was synced, or `-` if
+/// that is unknown, and the size of its ciphertext.
+///
+/// # Parameters
+///
+/// 1. `entries`: what `CryptLister::list` returned
+pub fn format_long(entries: &[ListEntry]) -> String {
+    entries
+        .iter()
+        .map(|entry| {
+            let original_size = match (entry.is_dir, entry.opt_original_size) {
+                (false, Some(size)) => size.to_string(),
+                _ => String::from("-"),
+            };
+            let suffix = if entry.is_dir { "/" } else { "" };
+            format!(
+                "{:>12} {:>12}  {}{}",
+                original_size,
+                entry.size,
+                entry.path.display(),
+                suffix
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::crypt_syncer::*;
+    use crate::crypt::test_util::*;
+
+    #[test]
+    fn list_then_format() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&[
+            ("dir/subdir/file", Some("file")),
+            ("dir/other", Some("other")),
+            ("last", Some("")),
+        ])?;
+        let out_dir = synced(&source, &keys)?;
+
+        let entries = CryptLister::new(out_dir.path())?.list(&keys)?;
+        let paths: Vec<_> = entries.iter().map(|entry| entry.path.clone()).collect();
+        let expected: Vec<_> = [
+            "source",
+            "source/dir",
+            "source/dir/other",
+            "source/dir/subdir",
+            "source/dir/subdir/file",
+            "source/last",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(expected, paths);
+
+        let file = &entries[4];
+        assert!(!file.is_dir);
+        assert_eq!(Some(4), file.opt_original_size);
+        let target = out_dir.path().join(&file.target);
+        assert_eq!(symlink_metadata(target)?.len(), file.size);
+
+        let expected_tree = "source/\n\
+                             ├── dir/\n\
+                             │   ├── other\n\
+                             │   └── subdir/\n\
+                             │       └── file\n\
+                             └── last";
+        assert_eq!(expected_tree, format_tree(&entries));
+
+        let long = format_long(&entries);
+        let first_line = long.lines().next().unwrap();
+        assert_eq!(format!("{:>12} {:>12}  source/", "-", 0), first_line);
+        assert!(long
+            .lines()
+            .any(|line| line.starts_with(&format!("{:>12} ", 5))));
+        Ok(())
+    }
+
+    #[test]
+    fn list_buckets() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&[("dir/empty", None), ("dir/file", Some("file"))])?;
+
+        let out_dir = mktemp_dir("", "", None)?;
+        CryptSyncer::new(&source, out_dir.path())?
+            .with_layout(Layout::Buckets)
+            .sync(&keys)?;
+
+        // the tree comes from the index, as `out_dir` holds nothing but buckets
+        let entries = CryptLister::new(out_dir.path())?
+            .with_layout(Layout::Buckets)
+            .list(&keys)?;
+        let expected_tree = "source/\n\
+                             └── dir/\n    \
+                             ├── empty/\n    \
+                             └── file";
+        assert_eq!(expected_tree, format_tree(&entries));
+
+        let file = &entries[3];
+        assert_eq!((false, Some(4)), (file.is_dir, file.opt_original_size));
+        let target = out_dir.path().join(&file.target);
+        assert_eq!(symlink_metadata(target)?.len(), file.size);
+        assert_eq!(PathBuf::new(), entries[2].target);
+        Ok(())
+    }
+}
@@ -0,0 +1,629 @@
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::canonicalize;
+use std::fs::create_dir_all;
+use std::fs::read_dir;
+use std::fs::read_to_string;
+use std::fs::rename;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::crypt::crypt_encoder::*;
+use crate::crypt::crypt_restorer::*;
+use crate::crypt::crypt_syncer::*;
+use crate::crypt::file_header::*;
+use crate::crypt::index::*;
+use crate::crypt::integrity::*;
+use crate::crypt::key_schedule::*;
+use crate::crypt::manifest::*;
+use crate::crypt::name_cipher::*;
+use crate::crypt::repo_config::*;
+use crate::hasher::*;
+use crate::util::*;
+
+/// Name of the directory in `METADATA_DIR` that holds a rekey in progress, so that it can be
+/// resumed if it is interrupted; along with the new config and manifest, it holds the rekeyed tree
+/// in one of the directories below, whichever says how far the rekey got.
+pub const REKEY_DIR: &str = "rekey";
+
+const JOURNAL_FILE: &str = "journal"; // what was staged so far, see `Journal`
+
+const STAGED_DIR: &str = "staged"; // the rekeyed tree, while it is being built
+const NEW_DIR: &str = "new"; // the rekeyed tree, once it is complete
+const SWAP_DIR: &str = "swap"; // the rekeyed tree, once the old one is out of the way
+const OLD_DIR: &str = "old"; // the old tree, until the rekey is done
+
+/// Re-encrypts an encrypted tree under a new master key, straight from the old ciphertexts to the
+/// new ones, so that the plaintext is never written anywhere.
+#[derive(Debug)]
+pub struct CryptRekeyer {
+    contents: ContentCipher, // how the content of files is encrypted before the rekey
+    // whether the tree has an integrity manifest, as its config says, so that one that is missing
+    // was removed
+    has_integrity: bool,
+    layout: Layout, // how the encrypted tree is laid out, before and after the rekey
+    names: NameCipher, // how basenames are encrypted before the rekey
+    out_dir: PathBuf, // path to the dir in which the encrypted tree is stored
+}
+
+impl CryptRekeyer {
+    /// Encrypt every file and basename in `out_dir` again under `new_keys`, then replace the
+    /// config of the tree with `new_config`, resuming the rekey in progress, if any.
+    ///
+    /// The rekeyed tree is built next to the old one, in `REKEY_DIR`, so until it is complete, the
+    /// old tree is left as it is, and an interrupted rekey leaves behind nothing but what it had
+    /// rekeyed so far, which is not done again when it is resumed. Only then is the old tree
+    /// swapped for the new one, by renaming its top-level paths, and the config replaced last, as
+    /// it says which key the tree is under.
+    ///
+    /// Ciphertexts that were encrypted to public keys do not depend on the key, so they are moved
+    /// along as they are.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `keys`: the keys of the tree
+    /// 1. `new_config`: the config of the rekeyed tree, e.g. from `RepoConfig::rekey`, or `pending`
+    ///    if a rekey is in progress
+    /// 1. `new_keys`: the keys of the rekeyed tree, as `new_config` derives them
+    pub fn rekey(
+        &self,
+        keys: &Keys,
+        new_config: &RepoConfig,
+        new_keys: &Keys,
+    ) -> Result<(), Error> {
+        let rekey_dir = self.rekey_dir();
+        if !rekey_dir.join(CONFIG_FILE).exists() {
+            new_config.store_file(&rekey_dir.join(CONFIG_FILE))?;
+        }
+        if !rekey_dir.join(NEW_DIR).exists() && !rekey_dir.join(SWAP_DIR).exists() {
+            self.stage(keys, new_keys, new_config.names)?;
+        }
+        if rekey_dir.join(NEW_DIR).exists() {
+            self.retire()?;
+        }
+        self.swap(new_keys)
+    }
+
+    /// # Returns
+    ///
+    /// The config of the rekey in progress, if any, which is what it has to be resumed with. What
+    /// is left of a rekey that got as far as replacing the config, and so is done, is removed.
+    pub fn pending(&self) -> Result<Option<RepoConfig>, Error> {
+        let rekey_dir = self.rekey_dir();
+        match RepoConfig::load_file(&rekey_dir.join(CONFIG_FILE))? {
+            Some(config) => Ok(Some(config)),
+            None => remove_path(&rekey_dir).map(|_| None),
+        }
+    }
+
+    /// Build the rekeyed tree in `STAGED_DIR`, along with the rekeyed manifest and index, and the
+    /// integrity manifest of them all, then mark it as complete by renaming it to `NEW_DIR`.
+    ///
+    /// Nothing is staged unless every path in the old tree can be decrypted, as whatever cannot
+    /// would be lost, nor unless it matches its integrity manifest, if any, as the rekeyed tree
+    /// gets one that vouches for whatever was staged. Every ciphertext is hashed as it is staged,
+    /// and the hash kept in the `Journal`, so files that are in `STAGED_DIR` already are skipped
+    /// only if they are still what was staged there.
+    fn stage(&self, keys: &Keys, new_keys: &Keys, new_names: NameCipher) -> Result<(), Error> {
+        if let Some(integrity) =
+            IntegrityManifest::load_expected(&self.out_dir, keys, self.has_integrity)?
+        {
+            let num_tampered = integrity.check(&self.out_dir)?.len();
+            if num_tampered > 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "{} ciphertext(s) in `{:?}` do not match its integrity manifest; see \
+                         `verify`",
+                        num_tampered, self.out_dir
+                    ),
+                ));
+            }
+        }
+
+        let rekey_dir = self.rekey_dir();
+        let staged_dir = rekey_dir.join(STAGED_DIR);
+        create_dir_all(&staged_dir)?;
+        let arena = mktemp_dir("arena", "", Some(&rekey_dir))?;
+        let (journal, journaled) = Journal::open(&rekey_dir.join(JOURNAL_FILE), new_keys)?;
+
+        let (enc_to_plain, num_undecryptable) = match self.layout {
+            Layout::Tree => {
+                let enc_to_plain = path_plaintexts(&self.out_dir, keys, self.names)?;
+                let metadata_dir = self.out_dir.join(METADATA_DIR);
+                let num_undecryptable = find(&self.out_dir)
+                    .filter_map(Result::ok)
+                    .filter(|path| !path.starts_with(&metadata_dir) && !is_sidecar(path))
+                    .filter_map(|path| path.strip_prefix(&self.out_dir).map(Path::to_path_buf).ok())
+                    .filter(|enc_path| !enc_path.as_os_str().is_empty())
+                    .filter(|enc_path| !enc_to_plain.contains_key(enc_path))
+                    .count();
+                (enc_to_plain, num_undecryptable)
+            }
+            Layout::Buckets => {
+                let index = Index::load(&self.out_dir, keys)?;
+                (index.subtree(None)?.1, index.unindexed(&self.out_dir).len())
+            }
+        };
+        if num_undecryptable > 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} path(s) in `{:?}` cannot be decrypted, and would be lost by rekeying it",
+                    num_undecryptable, self.out_dir
+                ),
+            ));
+        }
+
+        // the random ids of files in `Layout::Buckets` have nothing to do with the key
+        let enc_to_new: HashMap<&PathBuf, PathBuf> = enc_to_plain
+            .par_iter()
+            .map(|(enc_path, plain_path)| match self.layout {
+                Layout::Tree => Ok((enc_path, path_ciphertext(plain_path, new_keys, new_names)?)),
+                Layout::Buckets => Ok((enc_path, enc_path.clone())),
+            })
+            .collect::<Result<_, Error>>()?;
+
+        // sidecars are written again every time, so they are hashed every time
+        let mut leaves: BTreeMap<PathBuf, String> = enc_to_new
+            .par_iter()
+            .map(|(enc_path, new_path)| {
+                let target = staged_dir.join(new_path);
+                target.parent().map_or(Ok(()), create_dir_all)?;
+                if self.layout == Layout::Buckets {
+                    return Ok(None);
+                }
+                write_sidecar(&target, &enc_to_plain[*enc_path], new_keys, new_names)?;
+                if self.out_dir.join(enc_path).is_dir() {
+                    create_dir_all(&target)?;
+                }
+                sidecar_path(&target)
+                    .map(|sidecar| {
+                        let rel_path = sidecar.strip_prefix(&staged_dir).map_err(io_err)?;
+                        Ok((rel_path.to_path_buf(), hash_file(&sidecar)?))
+                    })
+                    .transpose()
+            })
+            .filter_map(Result::transpose)
+            .collect::<Result<_, Error>>()?;
+
+        let (staged, failures): (Vec<_>, Vec<_>) = enc_to_new
+            .par_iter()
+            .filter(|(enc_path, _)| self.out_dir.join(enc_path).is_file())
+            .map(|(enc_path, new_path)| -> Result<(PathBuf, String), Error> {
+                let (source, target) = (self.out_dir.join(enc_path), staged_dir.join(new_path));
+                // rekeyed before the rekey was interrupted, and left alone since
+                if let Some(hash) = journaled.get(new_path) {
+                    if target.is_file() && hash_file(&target)? == *hash {
+                        return Ok((new_path.to_path_buf(), hash.clone()));
+                    }
+                }
+
+                let hash = reseal_file(
+                    &source,
+                    &target,
+                    arena.path(),
+                    &keys.contents,
+                    &new_keys.contents,
+                    self.contents,
+                )
+                .map_err(|err| err!("failed to rekey `{:?}`: {}", source, err))?;
+                journal.record(new_path, &hash)?;
+                Ok((new_path.to_path_buf(), hash))
+            })
+            .partition(Result::is_ok);
+
+        failures
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .for_each(|err| eprintln!("{}", err));
+        if !failures.is_empty() {
+            return Err(err!("failed to rekey {} file(s)", failures.len()));
+        }
+        leaves.extend(staged.into_iter().filter_map(Result::ok));
+
+        let metadata_files = [
+            (
+                manifest_path(&self.out_dir),
+                MANIFEST_FILE,
+                &keys.manifest,
+                &new_keys.manifest,
+            ),
+            (
+                index_path(&self.out_dir),
+                INDEX_FILE,
+                &keys.index,
+                &new_keys.index,
+            ),
+        ];
+        for (path, name, key, new_key) in metadata_files.iter() {
+            if path.exists() {
+                let hash = reseal_file(
+                    path,
+                    &rekey_dir.join(name),
+                    arena.path(),
+                    key,
+                    new_key,
+                    ContentCipher::Sealed,
+                )?;
+                leaves.insert(Path::new(METADATA_DIR).join(name), hash);
+            }
+        }
+        IntegrityManifest::with_leaves(leaves)
+            .store_file(&rekey_dir.join(INTEGRITY_FILE), new_keys)?;
+        rename(staged_dir, rekey_dir.join(NEW_DIR))
+    }
+
+    /// Move the old tree out of the way, into `OLD_DIR`, then mark the rekeyed tree as ready to
+    /// take its place by renaming it to `SWAP_DIR`.
+    fn retire(&self) -> Result<(), Error> {
+        let rekey_dir = self.rekey_dir();
+        let old_dir = rekey_dir.join(OLD_DIR);
+        create_dir_all(&old_dir)?;
+        for entry in read_dir(&self.out_dir)? {
+            let entry = entry?;
+            if entry.file_name() != METADATA_DIR {
+                rename(entry.path(), old_dir.join(entry.file_name()))?;
+            }
+        }
+        rename(rekey_dir.join(NEW_DIR), rekey_dir.join(SWAP_DIR))
+    }
+
+    /// Move the rekeyed tree, then the rekeyed manifest and index, then the integrity manifest of
+    /// what was staged, then the new config in place, and remove whatever is left of the rekey,
+    /// including the old tree.
+    fn swap(&self, new_keys: &Keys) -> Result<(), Error> {
+        let rekey_dir = self.rekey_dir();
+        let mut integrity =
+            IntegrityManifest::load_file(&rekey_dir.join(INTEGRITY_FILE), new_keys)?.ok_or_else(
+                || {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "the rekey of `{:?}` in progress has no integrity manifest, so it was \
+                         tampered with",
+                            self.out_dir
+                        ),
+                    )
+                },
+            )?;
+        for entry in read_dir(rekey_dir.join(SWAP_DIR))? {
+            let entry = entry?;
+            rename(entry.path(), self.out_dir.join(entry.file_name()))?;
+        }
+
+        for (name, path) in [
+            (MANIFEST_FILE, manifest_path(&self.out_dir)),
+            (INDEX_FILE, index_path(&self.out_dir)),
+        ]
+        .iter()
+        {
+            if rekey_dir.join(name).exists() {
+                rename(rekey_dir.join(name), path)?;
+            }
+        }
+        integrity.store(&self.out_dir, new_keys)?;
+        rename(rekey_dir.join(CONFIG_FILE), config_path(&self.out_dir))?;
+        remove_path(&rekey_dir)
+    }
+
+    #[inline]
+    fn rekey_dir(&self) -> PathBuf {
+        self.out_dir.join(METADATA_DIR).join(REKEY_DIR)
+    }
+
+    /// # Parameters
+    ///
+    /// 1. `out_dir`: the directory in which the encrypted tree is stored
+    pub fn new(out_dir: &Path) -> Result<Self, Error> {
+        let out_dir = canonicalize(out_dir)?;
+        if !out_dir.is_dir() {
+            return Err(err!("`{:?}` is not a directory", out_dir));
+        }
+
+        Ok(Self {
+            contents: ContentCipher::default(),
+            has_integrity: true,
+            layout: Layout::Tree,
+            names: NameCipher::default(),
+            out_dir,
+        })
+    }
+
+    /// Refuse to rekey a tree whose integrity manifest is missing if its config says that it
+    /// `has_integrity`; `true` unless set otherwise.
+    pub fn with_integrity(mut self, has_integrity: bool) -> Self {
+        self.has_integrity = has_integrity;
+        self
+    }
+
+    /// Find what is where as `layout` says; `Layout::Tree` unless set otherwise. The rekey leaves
+    /// the layout as it is.
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Decrypt basenames as `names` says; `NameCipher::AesSiv` unless set otherwise. How they are
+    /// encrypted after the rekey is up to the new config.
+    pub fn with_name_cipher(mut self, names: NameCipher) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Decrypt the content of files as `contents` says; `ContentCipher::Sealed` unless set
+    /// otherwise. Files are encrypted with `seal` after the rekey.
+    pub fn with_content_cipher(mut self, contents: ContentCipher) -> Self {
+        self.contents = contents;
+        self
+    }
+}
+
+/// # Returns
+///
+/// Whether a rekey of the encrypted tree in `out_dir` is in progress, in which case nothing else
+/// should change it until the rekey is resumed and done.
+pub fn rekey_in_progress(out_dir: &Path) -> bool {
+    out_dir
+        .join(METADATA_DIR)
+        .join(REKEY_DIR)
+        .join(CONFIG_FILE)
+        .exists()
+}
+
+/// Encrypt `source`, which is encrypted as `contents` says, again under `new_key` into `arena`,
+/// then move the result to `target`, so that `target` only ever exists once it is complete.
+///
+/// # Returns
+///
+/// The hash of the new ciphertext, see `hasher::hash_file`, as it was written.
+fn reseal_file(
+    source: &Path,
+    target: &Path,
+    arena: &Path,
+    key: &[u8],
+    new_key: &[u8],
+    contents: ContentCipher,
+) -> Result<String, Error> {
+    let mut arena_file = mktemp_file("", "", Some(arena))?;
+    reseal(File::open(source)?, key, new_key, contents)?.write_all_to(arena_file.as_file_mut())?;
+    let hash = hash_file(arena_file.path())?;
+
+    arena_file
+        .persist(target)
+        .map(|_| hash)
+        .map_err(|err| err.error)
+}
+
+/// The hash of every file that a rekey staged so far, appended to `JOURNAL_FILE` as soon as the
+/// file is staged, along with the MAC of the leaf under the new keys, so that a resumed rekey can
+/// tell what it staged itself from whatever was put in `STAGED_DIR` since.
+struct Journal<'a> {
+    file: Mutex<File>,
+    new_keys: &'a Keys,
+}
+
+impl<'a> Journal<'a> {
+    /// Open the journal at `path`, creating it if there is none yet.
+    ///
+    /// # Returns
+    ///
+    /// The journal, along with the hash of every file in it, keyed by its path relative to
+    /// `STAGED_DIR`, the last one for files that were staged more than once. Lines that do not
+    /// match their MAC, e.g. as the rekey was interrupted while writing one, are left out, so that
+    /// their files are staged again.
+    fn open(path: &Path, new_keys: &'a Keys) -> Result<(Self, BTreeMap<PathBuf, String>), Error> {
+        let journaled = match path.exists() {
+            true => read_to_string(path)?
+                .lines()
+                .filter_map(|line| {
+                    let mut fields = line.splitn(3, ' ');
+                    match (fields.next(), fields.next(), fields.next()) {
+                        (Some(mac), Some(hash), Some(rel_path))
+                            if verify_leaf_mac(new_keys, Path::new(rel_path), hash, mac) =>
+                        {
+                            Some((PathBuf::from(rel_path), String::from(hash)))
+                        }
+                        _ => None,
+                    }
+                })
+                .collect(),
+            false => BTreeMap::new(),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let journal = Self {
+            file: Mutex::new(file),
+            new_keys,
+        };
+        Ok((journal, journaled))
+    }
+
+    /// Record that the file at `rel_path`, relative to `STAGED_DIR`, was staged with `hash`.
+    fn record(&self, rel_path: &Path, hash: &str) -> Result<(), Error> {
+        let mac = leaf_mac(self.new_keys, rel_path, hash);
+        let line = format!("{} {} {}\n", mac, hash, rel_path.to_string_lossy());
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::test_util::*;
+    use crate::encoder::padder::Padding;
+    use std::fs::read;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    // as cheap as the floors allow, for tests
+    const KDF: Kdf = Kdf::Pbkdf2Sha512 {
+        num_iter: PBKDF2_MIN_NUM_ITER,
+    };
+
+    /// Sync a small tree into a new encrypted dir, laid out as `layout` says.
+    ///
+    /// # Returns
+    ///
+    /// The temp dir of the source, the source, the encrypted dir, and its config.
+    fn init_synced(layout: Layout) -> Result<(TempDir, PathBuf, TempDir, RepoConfig), Error> {
+        let (src_dir, source) = test_source(&[
+            ("file", Some("file")),
+            ("dir/subdir/nested", Some("nested")),
+            (&format!("dir/{}", "l".repeat(200)), Some("long")),
+        ])?;
+        let out_dir = mktemp_dir("", "", None)?;
+        let config = RepoConfig::init(out_dir.path(), KDF, b"old", Padding::None, layout)?;
+        CryptSyncer::new(&source, out_dir.path())?
+            .with_layout(layout)
+            .sync(&config.derive_keys(b"old")?)?;
+        Ok((src_dir, source, out_dir, config))
+    }
+
+    #[test]
+    fn rekey_then_restore() -> Result<(), Error> {
+        for &layout in [Layout::Tree, Layout::Buckets].iter() {
+            let (_src_dir, source, out_dir, config) = init_synced(layout)?;
+            let keys = config.derive_keys(b"old")?;
+            let new_config = config.rekey(b"old", b"new")?;
+            let new_keys = new_config.derive_keys(b"new")?;
+
+            let rekeyer = CryptRekeyer::new(out_dir.path())?.with_layout(layout);
+            assert_eq!(None, rekeyer.pending()?);
+            rekeyer.rekey(&keys, &new_config, &new_keys)?;
+            assert!(!rekey_in_progress(out_dir.path()));
+            assert!(!out_dir.path().join(METADATA_DIR).join(REKEY_DIR).exists());
+
+            assert_eq!(Some(new_config), RepoConfig::load(out_dir.path())?);
+            assert_restores(&source, out_dir.path(), &new_keys, layout)?;
+            assert_eq!(3, Manifest::load(out_dir.path(), &new_keys)?.entries.len());
+            let integrity = IntegrityManifest::load(out_dir.path(), &new_keys)?.unwrap();
+            assert!(integrity.check(out_dir.path())?.is_empty());
+
+            // every name, content and index changed, so the old master key opens nothing
+            let plain_dir = mktemp_dir("", "", None)?;
+            let restorer =
+                CryptRestorer::new(out_dir.path(), plain_dir.path())?.with_layout(layout);
+            assert!(restorer.restore(&keys).is_err());
+            assert!(Manifest::load(out_dir.path(), &keys).is_err());
+            assert!(IntegrityManifest::load(out_dir.path(), &keys).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_trees_are_migrated() -> Result<(), Error> {
+        let (_src_dir, source) =
+            test_source(&[("file", Some("file")), ("dir/nested", Some("nested"))])?;
+        let out_dir = mktemp_dir("", "", None)?;
+        let config = RepoConfig::legacy();
+        let keys = config.derive_keys(b"old")?;
+        CryptSyncer::new(&source, out_dir.path())?
+            .with_integrity(config.integrity)
+            .with_name_cipher(config.names)
+            .with_content_cipher(config.contents)
+            .sync(&keys)?;
+
+        let plain_dir = mktemp_dir("", "", None)?;
+        CryptRestorer::new(out_dir.path(), plain_dir.path())?
+            .with_name_cipher(config.names)
+            .with_content_cipher(config.contents)
+            .restore(&keys)?;
+        assert_eq!(
+            read_tree(&source),
+            read_tree(&plain_dir.path().join("source"))
+        );
+
+        let new_config = RepoConfig::new(KDF, b"new", Padding::None, Layout::Tree)?;
+        let new_keys = new_config.derive_keys(b"new")?;
+        CryptRekeyer::new(out_dir.path())?
+            .with_integrity(config.integrity)
+            .with_name_cipher(config.names)
+            .with_content_cipher(config.contents)
+            .rekey(&keys, &new_config, &new_keys)?;
+        assert_eq!(Some(new_config), RepoConfig::load(out_dir.path())?);
+        assert_restores(&source, out_dir.path(), &new_keys, Layout::Tree)
+    }
+
+    #[test]
+    fn interrupted_rekey_resumes() -> Result<(), Error> {
+        // interrupted once the rekeyed tree was partly built, then once the old tree was retired
+        for &retired in [false, true].iter() {
+            let (_src_dir, source, out_dir, config) = init_synced(Layout::Tree)?;
+            let keys = config.derive_keys(b"old")?;
+            let new_config = config.rekey(b"old", b"new")?;
+            let new_keys = new_config.derive_keys(b"new")?;
+
+            let rekeyer = CryptRekeyer::new(out_dir.path())?;
+            let rekey_dir = rekeyer.rekey_dir();
+            new_config.store_file(&rekey_dir.join(CONFIG_FILE))?;
+            rekeyer.stage(&keys, &new_keys, new_config.names)?;
+            match retired {
+                false => {
+                    let file =
+                        path_ciphertext(Path::new("source/file"), &new_keys, new_config.names)?;
+                    remove_path(&rekey_dir.join(NEW_DIR).join(file))?;
+                    rename(rekey_dir.join(NEW_DIR), rekey_dir.join(STAGED_DIR))?;
+
+                    // the old tree is untouched until the rekeyed one is complete
+                    assert_restores(&source, out_dir.path(), &keys, Layout::Tree)?;
+                }
+                true => rekeyer.retire()?,
+            }
+            assert!(rekey_in_progress(out_dir.path()));
+
+            let rekeyer = CryptRekeyer::new(out_dir.path())?;
+            let pending = rekeyer.pending()?.unwrap();
+            assert_eq!(new_config, pending);
+            rekeyer.rekey(&keys, &pending, &new_keys)?;
+            assert_eq!(Some(new_config), RepoConfig::load(out_dir.path())?);
+            assert_restores(&source, out_dir.path(), &new_keys, Layout::Tree)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn resumed_rekey_restages_tampered_files() -> Result<(), Error> {
+        let (_src_dir, source, out_dir, config) = init_synced(Layout::Tree)?;
+        let keys = config.derive_keys(b"old")?;
+        let new_config = config.rekey(b"old", b"new")?;
+        let new_keys = new_config.derive_keys(b"new")?;
+
+        let rekeyer = CryptRekeyer::new(out_dir.path())?;
+        let rekey_dir = rekeyer.rekey_dir();
+        new_config.store_file(&rekey_dir.join(CONFIG_FILE))?;
+        rekeyer.stage(&keys, &new_keys, new_config.names)?;
+        rename(rekey_dir.join(NEW_DIR), rekey_dir.join(STAGED_DIR))?;
+
+        // whoever can write to `out_dir` swaps a staged file for another one, and adds one
+        let staged_dir = rekey_dir.join(STAGED_DIR);
+        let file = path_ciphertext(Path::new("source/file"), &new_keys, new_config.names)?;
+        let nested = path_ciphertext(
+            Path::new("source/dir/subdir/nested"),
+            &new_keys,
+            new_config.names,
+        )?;
+        write(staged_dir.join(&file), read(staged_dir.join(&nested))?)?;
+        write(staged_dir.join("planted"), "planted")?;
+
+        rekeyer.rekey(&keys, &rekeyer.pending()?.unwrap(), &new_keys)?;
+        assert_restores(&source, out_dir.path(), &new_keys, Layout::Tree)?;
+
+        // the integrity manifest vouches only for what the rekey staged itself
+        let integrity = IntegrityManifest::load(out_dir.path(), &new_keys)?.unwrap();
+        assert_eq!(
+            vec![(PathBuf::from("planted"), Tampering::Unknown)],
+            integrity.check(out_dir.path())?
+        );
+        Ok(())
+    }
+}
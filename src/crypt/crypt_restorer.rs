@@ -0,0 +1,484 @@
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::canonicalize;
+use std::fs::create_dir_all;
+use std::fs::File;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+use crate::crypt::crypt_encoder::*;
+use crate::crypt::crypt_syncer::*;
+use crate::crypt::file_header::*;
+use crate::crypt::index::*;
+use crate::crypt::key_schedule::*;
+use crate::crypt::name_cipher::*;
+use crate::encoder::cryptor::X25519_KEY_LEN;
+use crate::util::*;
+
+#[derive(Debug)]
+pub struct CryptRestorer {
+    // some temp location where the decrypted files will be stored before
+    // being moved to their final locations; lives in `out_dir` so that the
+    // final `rename` never crosses filesystems
+    arena: TempDir,
+    contents: ContentCipher, // how the content of files is encrypted
+    layout: Layout,          // how the encrypted tree is laid out
+    names: NameCipher,       // how basenames are encrypted
+    // the secret key to decrypt contents with, if they were encrypted to public keys
+    opt_identity: Option<[u8; X25519_KEY_LEN]>,
+    opt_path: Option<PathBuf>, // the plaintext path of the only subtree to restore, if any
+    out_dir: PathBuf,          // path to the dir in which the plaintext tree is rebuilt
+    source: PathBuf,           // path to the encrypted dir, i.e. the `out_dir` of some sync
+}
+
+impl CryptRestorer {
+    /// Rebuild the plaintext tree from the encrypted tree in `source`, i.e. undo what
+    /// `CryptSyncer::sync` did, or just the subtree set with `with_path`. Existing files in
+    /// `out_dir` are never overwritten.
+    ///
+    /// Just like syncing, failing to decrypt a file does not stop the others from being restored;
+    /// every failure is reported to stderr, and the restore as a whole fails if there was at least
+    /// one.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `keys`: the keys of the tree
+    pub fn restore(&self, keys: &Keys) -> Result<(), Error> {
+        let opt_path = self.opt_path.as_deref();
+        let (plain_dirs, enc_to_plain) = match self.layout {
+            Layout::Tree => {
+                let (dirs, files): (HashMap<_, _>, HashMap<_, _>) =
+                    subtree_plaintexts(&self.source, opt_path, keys, self.names)?
+                        .into_iter()
+                        .partition(|(enc_path, _)| self.source.join(enc_path).is_dir());
+                (dirs.into_values().collect(), files)
+            }
+            Layout::Buckets => Index::load(&self.source, keys)?.subtree(opt_path)?,
+        };
+
+        // recreate the directory structure in `out_dir`, including the ancestors of the subtree
+        if let Some(parent) = opt_path.and_then(Path::parent) {
+            create_dir_all(self.out_dir.join(parent))?;
+        }
+        plain_dirs
+            .par_iter()
+            .map(|plain_path| create_dir_all(self.out_dir.join(plain_path)))
+            .collect::<Result<(), Error>>()?;
+
+        let failures: Vec<Error> = enc_to_plain
+            .par_iter()
+            .map(|(enc_path, plain_path)| {
+                (self.source.join(enc_path), self.out_dir.join(plain_path))
+            })
+            .map(|(source, target)| {
+                self.decrypt_file(&source, &target, keys)
+                    .map_err(|err| err!("failed to restore `{:?}`: {}", target, err))
+            })
+            .filter_map(Result::err)
+            .collect();
+
+        failures.iter().for_each(|err| eprintln!("{}", err));
+        match failures.len() {
+            0 => Ok(()),
+            num_failures => Err(err!("failed to restore {} file(s)", num_failures)),
+        }
+    }
+
+    /// Decrypt and decompress `source` into the arena, then move the result to `target`.
+    fn decrypt_file(&self, source: &Path, target: &Path, keys: &Keys) -> Result<(), Error> {
+        let mut arena_file = mktemp_file("", "", Some(self.arena.path()))?;
+        open_content(
+            File::open(source)?,
+            &keys.contents,
+            self.opt_identity.as_ref(),
+            self.contents,
+        )?
+        .write_all_to(arena_file.as_file_mut())?;
+
+        arena_file
+            .persist_noclobber(target)
+            .map(|_| ())
+            .map_err(|err| err.error)
+    }
+
+    /// # Parameters
+    ///
+    /// 1. `source`: the encrypted dir, i.e. the `out_dir` of some sync
+    /// 1. `out_dir`: the directory in which the plaintext tree will be rebuilt; must exist
+    pub fn new(source: &Path, out_dir: &Path) -> Result<Self, Error> {
+        let source = canonicalize(source)?;
+        if !source.is_dir() {
+            return Err(err!("`{:?}` is not a directory", source));
+        }
+
+        let out_dir = canonicalize(out_dir)?;
+        if !out_dir.is_dir() {
+            return Err(err!("`{:?}` is not a directory", out_dir));
+        }
+        if out_dir.starts_with(&source) {
+            return Err(err!("`{:?}` cannot be inside `{:?}`", out_dir, source));
+        }
+
+        let arena = mktemp_dir(".csync-arena", "", Some(&out_dir))?;
+        Ok(Self {
+            arena,
+            contents: ContentCipher::default(),
+            layout: Layout::Tree,
+            names: NameCipher::default(),
+            opt_identity: None,
+            opt_path: None,
+            out_dir,
+            source,
+        })
+    }
+
+    /// Restore only the file or directory at `path`, along with everything below it, instead of
+    /// the whole tree; it ends up at `out_dir/path`, just like it would with the whole tree.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `path`: plaintext path relative to the root of the tree, i.e. starting with the basename
+    ///    of the synced source, as listed by `csync ls`
+    pub fn with_path(mut self, path: &Path) -> Self {
+        self.opt_path = Some(path.to_path_buf());
+        self
+    }
+
+    /// Find what is where as `layout` says; `Layout::Tree` unless set otherwise.
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Decrypt basenames as `names` says; `NameCipher::AesSiv` unless set otherwise.
+    pub fn with_name_cipher(mut self, names: NameCipher) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Decrypt the content of files as `contents` says; `ContentCipher::Sealed` unless set
+    /// otherwise.
+    pub fn with_content_cipher(mut self, contents: ContentCipher) -> Self {
+        self.contents = contents;
+        self
+    }
+
+    /// Decrypt the content of files that were encrypted to public keys with `identity`, the
+    /// secret key of one of them.
+    pub fn with_identity(mut self, identity: [u8; X25519_KEY_LEN]) -> Self {
+        self.opt_identity = Some(identity);
+        self
+    }
+}
+
+/// Make a mapping from each path in the encrypted tree in `source` to its plaintext form; the
+/// inverse of what `CryptSyncer` derives when syncing.
+///
+/// # Parameters
+///
+/// 1. `source`: the encrypted dir, i.e. the `out_dir` of some sync
+/// 2. `keys`: the keys of the tree
+/// 3. `names`: how the basenames of the tree are encrypted
+///
+/// # Returns
+///
+/// A mapping from paths relative to `source` to their plaintext counterparts, where each basename
+/// is decrypted with the key derived from its already decrypted parent, and sidecar files are left
+/// out, as long names are resolved through them. Failing to decrypt a basename is reported to
+/// stderr, and the subtree in question is left out, as e.g. a cloud storage client may have put
+/// files of its own in `source`; failing to decrypt every top-level basename is an error, though,
+/// as it almost certainly means the key is wrong.
+pub fn path_plaintexts(
+    source: &Path,
+    keys: &Keys,
+    names: NameCipher,
+) -> Result<HashMap<PathBuf, PathBuf>, Error> {
+    subtree_plaintexts(source, None, keys, names)
+}
+
+/// Like `path_plaintexts`, but only for a single subtree, so that the rest of the tree is never
+/// even walked.
+///
+/// # Parameters
+///
+/// 1. `source`: the encrypted dir, i.e. the `out_dir` of some sync
+/// 1. `opt_plain_root`: plaintext path of the root of the subtree, relative to the root of the
+///    tree; `None` for the whole tree
+/// 2. `keys`: the keys of the tree
+/// 3. `names`: how the basenames of the tree are encrypted
+pub fn subtree_plaintexts(
+    source: &Path,
+    opt_plain_root: Option<&Path>,
+    keys: &Keys,
+    names: NameCipher,
+) -> Result<HashMap<PathBuf, PathBuf>, Error> {
+    let mut enc_to_plain: HashMap<PathBuf, PathBuf> = HashMap::new();
+
+    // the ciphertext of the root of the subtree is derived, rather than found by decrypting
+    let walk_root = match opt_plain_root {
+        Some(plain_root) => {
+            let enc_root = source.join(enc_path_of(source, plain_root, keys, names)?);
+            let enc_rel_root = enc_root.strip_prefix(source).map_err(io_err)?;
+            enc_to_plain.insert(enc_rel_root.to_path_buf(), plain_root.to_path_buf());
+            enc_root
+        }
+        None => source.to_path_buf(),
+    };
+
+    // pre-order traversal, so every parent is decrypted before its children
+    let skip_metadata_dir = opt_plain_root.is_none();
+    let entries = walker(&walk_root)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| {
+            !skip_metadata_dir || entry.depth() != 1 || entry.file_name() != METADATA_DIR
+        });
+
+    // (whether any top-level basename was decrypted, the first error in decrypting one)
+    let (mut any_top_level, mut opt_top_level_err) = (false, None);
+    for entry in entries {
+        let enc_path = entry.map_err(io_err)?.into_path();
+        if is_sidecar(&enc_path) {
+            continue; // read along with the long name it belongs to
+        }
+        let enc_rel_path = enc_path.strip_prefix(source).map_err(io_err)?;
+        let enc_parent = enc_rel_path.parent().unwrap_or(Path::new(""));
+
+        let plain_parent = match enc_parent.as_os_str().is_empty() {
+            true => PathBuf::new(),
+            false => match enc_to_plain.get(enc_parent) {
+                Some(plain_parent) => plain_parent.clone(),
+                None => continue, // the parent failed to decrypt
+            },
+        };
+
+        let plain_basename = stored_ciphertext(&enc_path).and_then(|ciphertext| {
+            let key = parent_derived_hash(Some(&plain_parent), keys)?;
+            decrypt_basename(&ciphertext, &key, names)
+        });
+
+        let top_level = plain_parent.as_os_str().is_empty();
+        match plain_basename {
+            Ok(basename) => {
+                any_top_level |= top_level;
+                enc_to_plain.insert(enc_rel_path.to_path_buf(), plain_parent.join(basename));
+            }
+            Err(err) => {
+                eprintln!("`{:?}`: {}", enc_path, err);
+                if top_level && opt_top_level_err.is_none() {
+                    opt_top_level_err = Some(err);
+                }
+            }
+        }
+    }
+
+    match (any_top_level, opt_top_level_err) {
+        (false, Some(err)) => Err(err),
+        _ => Ok(enc_to_plain),
+    }
+}
+
+/// Decrypt and decompress the single file at `plain_path` in the encrypted tree in `source` into
+/// `sink`, without going through the rest of the tree.
+///
+/// # Parameters
+///
+/// 1. `source`: the encrypted dir, i.e. the `out_dir` of some sync
+/// 1. `plain_path`: plaintext path of the file, relative to the root of the tree
+/// 1. `keys`: the keys of the tree
+/// 1. `names`: how the basenames of the tree are encrypted
+/// 1. `contents`: how the content of files in the tree is encrypted
+/// 1. `layout`: how the tree is laid out
+/// 1. `opt_identity`: the secret key to decrypt the content with, if it was encrypted to public keys
+/// 1. `sink`: where the plaintext goes
+#[allow(clippy::too_many_arguments)]
+pub fn cat<W>(
+    source: &Path,
+    plain_path: &Path,
+    keys: &Keys,
+    names: NameCipher,
+    contents: ContentCipher,
+    layout: Layout,
+    opt_identity: Option<&[u8; X25519_KEY_LEN]>,
+    sink: &mut W,
+) -> Result<(), Error>
+where
+    W: Write,
+{
+    // `None` for directories
+    let opt_target = match layout {
+        Layout::Tree => Some(source.join(enc_path_of(source, plain_path, keys, names)?))
+            .filter(|target| !target.is_dir()),
+        Layout::Buckets => match Index::load(source, keys)?.entries.get(plain_path) {
+            Some(entry) => entry.target().map(|target| source.join(target)),
+            None => return Err(not_found(source, plain_path)),
+        },
+    };
+    let target = opt_target.ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("`{:?}` is a directory", plain_path),
+        )
+    })?;
+
+    open_content(File::open(&target)?, &keys.contents, opt_identity, contents)?
+        .write_all_to(sink)
+        .map(|_| ())
+}
+
+/// # Returns
+///
+/// The ciphertext of `plain_path`, relative to `source`, as long as there is something there.
+fn enc_path_of(
+    source: &Path,
+    plain_path: &Path,
+    keys: &Keys,
+    names: NameCipher,
+) -> Result<PathBuf, Error> {
+    let enc_path = path_ciphertext(plain_path, keys, names)?;
+    match source.join(&enc_path).symlink_metadata() {
+        Ok(_) => Ok(enc_path),
+        Err(_) => Err(not_found(source, plain_path)),
+    }
+}
+
+#[inline]
+fn not_found(source: &Path, plain_path: &Path) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!("`{:?}` is not in `{:?}`", plain_path, source),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::test_util::*;
+    use crate::encoder::cryptor::generate_x25519;
+    use crate::hasher::*;
+    use std::fs::create_dir_all;
+    use std::fs::read;
+    use std::fs::write;
+
+    #[test]
+    fn sync_then_restore() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&[
+            ("dir/subdir/file", Some("file")),
+            ("dir/empty", Some("")),
+            ("other", Some("other")),
+            ("empty_dir", None),
+        ])?;
+        let enc_dir = synced(&source, &keys)?;
+        assert_restores(&source, enc_dir.path(), &keys, Layout::Tree)
+    }
+
+    #[test]
+    fn wrong_key_fails() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let wrong_key_hash = hash_custom("aoisjfk2".as_bytes(), None, Some(1 << 8));
+        let wrong_keys = Keys::derive(&wrong_key_hash, KeySchedule::Hkdf)?;
+        let (_src_dir, source) = test_source(&[("file", Some("file"))])?;
+        let enc_dir = synced(&source, &keys)?;
+
+        let out_dir = mktemp_dir("", "", None)?;
+        let restorer = CryptRestorer::new(enc_dir.path(), out_dir.path())?;
+        assert!(restorer.restore(&wrong_keys).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn stray_top_level_entries_are_skipped() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&[("file", Some("file"))])?;
+        let enc_dir = synced(&source, &keys)?;
+
+        // e.g. left there by cloud storage clients
+        write(enc_dir.path().join("desktop.ini"), "[.ShellClassInfo]")?;
+        create_dir_all(enc_dir.path().join(".dropbox.cache"))?;
+        assert_restores(&source, enc_dir.path(), &keys, Layout::Tree)
+    }
+
+    #[test]
+    fn restore_or_cat_a_single_path() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&[
+            ("sub/dir/nested/file", Some("file")),
+            ("sub/dir/config", Some("config")),
+            ("sub/other", Some("other")),
+        ])?;
+        let enc_dir = synced(&source, &keys)?;
+
+        let out_dir = mktemp_dir("", "", None)?;
+        CryptRestorer::new(enc_dir.path(), out_dir.path())?
+            .with_path(Path::new("source/sub/dir"))
+            .restore(&keys)?;
+        let mut restored: Vec<PathBuf> = find(out_dir.path())
+            .map(Result::unwrap)
+            .filter(|path| path.is_file())
+            .map(|path| path.strip_prefix(out_dir.path()).unwrap().to_path_buf())
+            .collect();
+        restored.sort();
+        let expected: Vec<PathBuf> = ["source/sub/dir/config", "source/sub/dir/nested/file"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(expected, restored);
+
+        let out_dir = mktemp_dir("", "", None)?;
+        CryptRestorer::new(enc_dir.path(), out_dir.path())?
+            .with_path(Path::new("source/sub/other"))
+            .restore(&keys)?;
+        let other = read(out_dir.path().join("source/sub/other"))?;
+        assert_eq!(b"other".to_vec(), other);
+
+        let cat_path = |rel_path: &str, out: &mut Vec<u8>| {
+            cat(
+                enc_dir.path(),
+                Path::new(rel_path),
+                &keys,
+                NameCipher::AesSiv,
+                ContentCipher::Sealed,
+                Layout::Tree,
+                None,
+                out,
+            )
+        };
+        let mut config = Vec::new();
+        cat_path("source/sub/dir/config", &mut config)?;
+        assert_eq!(b"config".to_vec(), config);
+
+        let missing = cat_path("source/gone", &mut Vec::new());
+        assert_eq!(ErrorKind::NotFound, missing.unwrap_err().kind());
+        let dir = cat_path("source/sub", &mut Vec::new());
+        assert_eq!(ErrorKind::InvalidInput, dir.unwrap_err().kind());
+        Ok(())
+    }
+
+    #[test]
+    fn recipient_files_need_the_identity() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (identity, recipient) = generate_x25519()?;
+        let (_src_dir, source) = test_source(&[("file", Some("file"))])?;
+        let enc_dir = mktemp_dir("", "", None)?;
+        CryptSyncer::new(&source, enc_dir.path())?
+            .with_recipients(vec![recipient])
+            .sync(&keys)?;
+
+        // the key alone decrypts the names, but not the content
+        let out_dir = mktemp_dir("", "", None)?;
+        let restorer = CryptRestorer::new(enc_dir.path(), out_dir.path())?;
+        assert!(restorer.restore(&keys).is_err());
+
+        let out_dir = mktemp_dir("", "", None)?;
+        CryptRestorer::new(enc_dir.path(), out_dir.path())?
+            .with_identity(identity)
+            .restore(&keys)?;
+        assert_eq!(b"file".to_vec(), read(out_dir.path().join("source/file"))?);
+        Ok(())
+    }
+}
@@ -1,196 +1,989 @@
+use rayon::iter::Either;
 use rayon::iter::ParallelBridge;
 use rayon::prelude::*;
-use std::cmp::Eq;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::ffi::OsStr;
+use std::fs::canonicalize;
 use std::fs::create_dir_all;
 use std::fs::metadata;
-use std::fs::rename;
 use std::fs::File;
-use std::hash::Hash;
-use std::hash::Hasher;
 use std::io::Error;
-use std::io::ErrorKind;
 use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
-use std::str;
-use std::sync::Arc;
-use std::time::Duration;
+use std::str::FromStr;
+use std::sync::Mutex;
 use std::time::SystemTime;
-use tempfile::TempDir;
 
-use crate::encoder::cryptor::*;
-use crate::encoder::text_encoder::*;
-use crate::encoder::zstd_encoder::*;
+use crate::crypt::crypt_encoder::*;
+use crate::crypt::file_header::*;
+use crate::crypt::index::*;
+use crate::crypt::integrity::*;
+use crate::crypt::key_schedule::*;
+use crate::crypt::manifest::*;
+use crate::crypt::name_cipher::*;
+use crate::crypt::sync_plan::*;
+use crate::encoder::cryptor::X25519_KEY_LEN;
+use crate::encoder::padder::Padding;
 use crate::hasher::*;
 use crate::util::*;
 
+/// Name of the directory in `out_dir` that holds everything that is not a ciphertext of some
+/// source file, e.g. the arena. Encrypted basenames are text-encoded and never start with a `.`,
+/// so this can never collide with them.
+pub const METADATA_DIR: &str = ".csync";
+
+/// What to do with the ciphertexts of paths that are no longer in `source`, e.g. because they
+/// were deleted or renamed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeletePolicy {
+    Mirror,  // remove them, so that `out_dir` mirrors `source`
+    Archive, // never remove anything, so that `out_dir` keeps every file that was ever synced
+}
+
+impl FromStr for DeletePolicy {
+    type Err = Error;
+
+    fn from_str(policy: &str) -> Result<Self, Error> {
+        match policy {
+            "mirror" => Ok(DeletePolicy::Mirror),
+            "archive" => Ok(DeletePolicy::Archive),
+            _ => Err(err!("`{}` is not one of `mirror`, `archive`", policy)),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct CryptSyncer {
-    // some temp location where the encrypted files will be stored before
-    // being moved to their final locations
-    arena: TempDir,
-    source: PathBuf, // path to the source file/dir
+    contents: ContentCipher, // how the content of files is encrypted
+    delete_policy: DeletePolicy,
+    // whether the tree has an integrity manifest already, as its config says, so that one that is
+    // missing was removed
+    has_integrity: bool,
+    layout: Layout, // how the tree is laid out in `out_dir`
+    // loaded on the first sync, as the key is needed to decrypt it, then kept
+    // around so that repeated syncs, e.g. when watching, don't reload it
+    manifest: Mutex<Option<Manifest>>,
+    names: NameCipher, // how basenames are encrypted
+    out_dir: PathBuf,  // path to the dir in which the encrypted tree is stored
+    padding: Padding,  // how the content of files is padded before it is encrypted
+    // public keys to encrypt the content of files to, instead of the key, if any
+    recipients: Vec<[u8; X25519_KEY_LEN]>,
+    source: PathBuf, // path to the source file/dir, canonicalized
 }
 
-impl<'a> CryptSyncer {
-    /// 1. for the root cfile,
-    pub fn sync(&self, out_dir: &Path, key_hash: &[u8]) -> Result<(), Error> {
-        assert!(out_dir.exists());
-        assert!(out_dir.is_dir());
+impl CryptSyncer {
+    /// Encrypt `source` into `out_dir`, preserving the directory structure; the same as applying
+    /// the plan from `plan`.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `keys`: the keys of the tree
+    pub fn sync(&self, keys: &Keys) -> Result<(), Error> {
+        self.apply(&self.plan(keys)?, keys)
+    }
+
+    /// Sync only the given paths in `source`, along with everything below them; the same as
+    /// applying the plan from `plan_paths`.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `paths`: absolute paths in `source` that changed
+    /// 1. `keys`: the keys of the tree
+    pub fn sync_paths(&self, paths: &HashSet<PathBuf>, keys: &Keys) -> Result<(), Error> {
+        self.apply(&self.plan_paths(paths, keys)?, keys)
+    }
+
+    /// Work out what it takes to sync `source` into `out_dir`, without writing anything.
+    ///
+    /// Files whose size and mtime match the manifest of the last sync are left out, as are files
+    /// whose content hash still matches, so only files that actually changed are re-encrypted.
+    ///
+    /// With `DeletePolicy::Mirror`, ciphertexts that no longer correspond to any path in `source`,
+    /// e.g. because it was deleted or renamed since the last sync, are planned to be removed.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `keys`: the keys of the tree
+    pub fn plan(&self, keys: &Keys) -> Result<SyncPlan, Error> {
+        let paths = [self.source.clone()].iter().cloned().collect();
+        self.plan_paths(&paths, keys)
+    }
+
+    /// Work out what it takes to sync only the given paths in `source`, along with everything
+    /// below them, so that a few changes can be synced without going through the whole tree.
+    ///
+    /// Paths that exist are planned to be encrypted into `out_dir`, replacing their old
+    /// ciphertexts, and with `DeletePolicy::Mirror`, the ciphertexts of paths that no longer exist
+    /// are planned to be removed, as are those in the encrypted subtrees of the given directories
+    /// that match no path in them; a rename is just both at once. Paths outside of `source` are
+    /// ignored.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `paths`: absolute paths in `source` that changed
+    /// 1. `keys`: the keys of the tree
+    pub fn plan_paths(&self, paths: &HashSet<PathBuf>, keys: &Keys) -> Result<SyncPlan, Error> {
+        let mut manifest_guard = self.manifest.lock().map_err(io_err)?;
+        if manifest_guard.is_none() {
+            *manifest_guard = Some(Manifest::load(&self.out_dir, keys)?);
+        }
+        let entries = &manifest_guard.as_ref().unwrap().entries;
+
+        let src_root = source_root(&self.source);
+        let (existing, removed): (Vec<_>, Vec<_>) = outermost_paths(paths)
+            .into_iter()
+            .filter(|path| path.starts_with(&self.source))
+            .partition(|path| path.symlink_metadata().is_ok());
+        if self.layout == Layout::Buckets {
+            return self.plan_buckets(&existing, &removed, entries, keys);
+        }
+
         let src_to_target = {
-            let src_to_target_basename = basename_ciphertexts(&self.source, key_hash);
+            // ancestors are needed as well, as every ciphertext path goes through theirs
+            let paths = existing.iter().flat_map(|root| {
+                root.ancestors()
+                    .skip(1)
+                    .take_while(|ancestor| ancestor.starts_with(&self.source))
+                    .map(|ancestor| Ok(ancestor.to_path_buf()))
+                    .chain(find(root))
+            });
+            let src_to_target_basename = basename_ciphertexts(src_root, paths, keys, self.names);
             path_ciphertexts(&src_to_target_basename)
         };
 
-        // create the directory structure in `out_dir`
-        min_mkdir_set(&self.source)
-            .into_par_iter()
-            .map(|dir_path| src_to_target.get(&dir_path)) // encrypt
-            .map(Option::unwrap)
-            .map(|dir_path| out_dir.join(dir_path))   // put it in out_dir
-            .map(create_dir_all)                      // create
-            .for_each(Result::unwrap); // exit early
+        let scope = existing
+            .iter()
+            .chain(&removed)
+            .filter_map(|root| root.strip_prefix(src_root).ok())
+            .map(Path::to_path_buf)
+            .collect();
 
-        src_to_target
+        let create_dirs = src_to_target
             .par_iter()
-            .filter(|(source, _)| source.is_file())
-            .map(|(source, target_basename)| {
-                let arena_basename = arena_basename(source)?;
-                let arena_path = self.arena.path().join(arena_basename);
-                let target = out_dir.join(target_basename);
-                Ok((source, arena_path, target))
+            .filter(|(rel_path, _)| src_root.join(rel_path).is_dir())
+            .filter(|(_, target)| !self.out_dir.join(target).is_dir())
+            .map(|(rel_path, target)| PlannedPath {
+                path: rel_path.clone(),
+                target: target.clone(),
+                size: 0,
+            })
+            .collect();
+
+        let mut plan = SyncPlan {
+            create_dirs,
+            scope,
+            ..SyncPlan::default()
+        };
+
+        // (the file, whether its ciphertext exists, whether it changed, its new manifest entry)
+        let recipients = recipients_fingerprint(&self.recipients);
+        let files: Vec<(PlannedPath, bool, bool, Option<ManifestEntry>)> = src_to_target
+            .par_iter()
+            .map(|(rel_path, target)| (rel_path, target, src_root.join(rel_path)))
+            .filter(|(_, _, source)| source.is_file())
+            .filter(|(_, _, source)| existing.iter().any(|root| source.starts_with(root)))
+            .map(|(rel_path, target, source)| {
+                let target_exists = self.out_dir.join(target).is_file();
+                let opt_entry = entries.get(rel_path).filter(|_| target_exists);
+                let (changed, opt_new_entry) =
+                    plan_file(&source, opt_entry, self.padding, &recipients);
+                let planned = PlannedPath {
+                    path: rel_path.clone(),
+                    target: target.clone(),
+                    size: metadata(&source)
+                        .map(|metadata| metadata.len())
+                        .unwrap_or(0),
+                };
+                (planned, target_exists, changed, opt_new_entry)
+            })
+            .collect();
+        for (planned, target_exists, changed, opt_new_entry) in files {
+            if let Some(entry) = opt_new_entry {
+                plan.entries.insert(planned.path.clone(), entry);
+            }
+            match (changed, target_exists) {
+                (false, _) => (),
+                (true, false) => plan.add.push(planned),
+                (true, true) => plan.update.push(planned),
+            }
+        }
+
+        if self.delete_policy == DeletePolicy::Mirror {
+            plan.delete = self.plan_deletions(&existing, &removed, &src_to_target, keys)?;
+        }
+
+        plan.sort();
+        Ok(plan)
+    }
+
+    /// `plan_paths` for `Layout::Buckets`, where the index, rather than `out_dir`, says where the
+    /// ciphertext of each path is, and which paths used to be in `source`.
+    ///
+    /// New files get a fresh random id, and files that changed keep theirs. Directories are only
+    /// planned to be added to the index, as they have no ciphertext. Paths that became something
+    /// else, e.g. a file that became a directory, are always planned to be removed, along with
+    /// everything below them, and with `DeletePolicy::Mirror`, so are paths that no longer exist,
+    /// and on a sync of the whole of `source`, whatever the index does not point to.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `existing`: the outermost changed paths in `source`
+    /// 1. `removed`: the outermost changed paths that used to be in `source`
+    /// 1. `entries`: the manifest entries of the last sync
+    /// 1. `keys`: the keys of the tree
+    fn plan_buckets(
+        &self,
+        existing: &[PathBuf],
+        removed: &[PathBuf],
+        entries: &HashMap<PathBuf, ManifestEntry>,
+        keys: &Keys,
+    ) -> Result<SyncPlan, Error> {
+        let index = Index::load(&self.out_dir, keys)?;
+        let src_root = source_root(&self.source);
+        let recipients = recipients_fingerprint(&self.recipients);
+
+        // ancestors are needed as well, as the index has to know every directory there is
+        let rel_paths: HashSet<PathBuf> = existing
+            .iter()
+            .flat_map(|root| {
+                root.ancestors()
+                    .skip(1)
+                    .take_while(|ancestor| ancestor.starts_with(&self.source))
+                    .map(|ancestor| Ok(ancestor.to_path_buf()))
+                    .chain(find(root))
+            })
+            .filter_map(|path| -> Option<PathBuf> {
+                let path = match path {
+                    Ok(path) => path,
+                    Err(err) => return eprintln_then_none!("{}", err),
+                };
+                match path.strip_prefix(src_root).ok()?.to_str() {
+                    Some(rel_path) => Some(PathBuf::from(rel_path)),
+                    None => eprintln_then_none!("`{:?}` contains non utf8 chars", path),
+                }
+            })
+            .collect();
+
+        let scope = existing
+            .iter()
+            .chain(removed)
+            .filter_map(|root| root.strip_prefix(src_root).ok())
+            .map(Path::to_path_buf)
+            .collect();
+
+        let create_dirs = rel_paths
+            .par_iter()
+            .filter(|rel_path| src_root.join(rel_path).is_dir())
+            .filter(|rel_path| index.entries.get(*rel_path) != Some(&IndexEntry::Dir))
+            .map(|rel_path| PlannedPath {
+                path: rel_path.clone(),
+                target: PathBuf::new(),
+                size: 0,
+            })
+            .collect();
+
+        let mut plan = SyncPlan {
+            create_dirs,
+            scope,
+            ..SyncPlan::default()
+        };
+
+        // (the file, whether its ciphertext exists, whether it changed, its new manifest entry)
+        let files = rel_paths
+            .par_iter()
+            .map(|rel_path| (rel_path, src_root.join(rel_path)))
+            .filter(|(_, source)| source.is_file())
+            .map(|(rel_path, source)| {
+                let (target, target_exists) = match index.entries.get(rel_path) {
+                    Some(IndexEntry::File { id }) => {
+                        let target = bucket_path(id);
+                        let target_exists = self.out_dir.join(&target).is_file();
+                        (target, target_exists)
+                    }
+                    _ => (bucket_path(&new_file_id()?), false),
+                };
+                let opt_entry = entries.get(rel_path).filter(|_| target_exists);
+                let (changed, opt_new_entry) =
+                    plan_file(&source, opt_entry, self.padding, &recipients);
+                let planned = PlannedPath {
+                    path: rel_path.clone(),
+                    target,
+                    size: metadata(&source)
+                        .map(|metadata| metadata.len())
+                        .unwrap_or(0),
+                };
+                Ok((planned, target_exists, changed, opt_new_entry))
+            })
+            .collect::<Result<Vec<(PlannedPath, bool, bool, Option<ManifestEntry>)>, Error>>()?;
+        for (planned, target_exists, changed, opt_new_entry) in files {
+            if let Some(entry) = opt_new_entry {
+                plan.entries.insert(planned.path.clone(), entry);
+            }
+            match (changed, target_exists) {
+                (false, _) => (),
+                (true, false) => plan.add.push(planned),
+                (true, true) => plan.update.push(planned),
+            }
+        }
+
+        let mirror = self.delete_policy == DeletePolicy::Mirror;
+        plan.delete = index
+            .entries
+            .par_iter()
+            .filter(|(rel_path, _)| plan.scope.iter().any(|root| rel_path.starts_with(root)))
+            .filter(|(rel_path, entry)| {
+                let source = src_root.join(rel_path);
+                let replaced = match entry {
+                    IndexEntry::Dir => source.is_file(),
+                    IndexEntry::File { .. } => source.is_dir(),
+                } || rel_path
+                    .ancestors()
+                    .skip(1)
+                    .any(|ancestor| src_root.join(ancestor).is_file());
+                replaced || (mirror && !source.exists())
+            })
+            .map(|(rel_path, entry)| match entry.target() {
+                Some(target) => PlannedDeletion {
+                    path: Some(rel_path.clone()),
+                    size: disk_usage(&self.out_dir.join(&target)),
+                    target,
+                },
+                None => PlannedDeletion {
+                    path: Some(rel_path.clone()),
+                    target: PathBuf::new(),
+                    size: 0,
+                },
+            })
+            .collect();
+
+        if mirror && existing.contains(&self.source) {
+            plan.delete
+                .extend(
+                    index
+                        .unindexed(&self.out_dir)
+                        .into_iter()
+                        .map(|target| PlannedDeletion {
+                            path: None,
+                            size: disk_usage(&self.out_dir.join(&target)),
+                            target,
+                        }),
+                );
+        }
+
+        plan.sort();
+        Ok(plan)
+    }
+
+    /// Carry out `plan`, which should come from `plan` or `plan_paths` of this syncer, with the
+    /// same key.
+    ///
+    /// Failing to encrypt a file does not stop the others from being synced; every failure is
+    /// reported to stderr, and the sync as a whole fails if there was at least one.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `plan`: what to do
+    /// 1. `keys`: the keys of the tree
+    pub fn apply(&self, plan: &SyncPlan, keys: &Keys) -> Result<(), Error> {
+        let mut manifest_guard = self.manifest.lock().map_err(io_err)?;
+        if manifest_guard.is_none() {
+            *manifest_guard = Some(Manifest::load(&self.out_dir, keys)?);
+        }
+        let manifest = manifest_guard.as_mut().unwrap();
+        let mut opt_index = match self.layout {
+            Layout::Tree => None,
+            Layout::Buckets => Some(Index::load(&self.out_dir, keys)?),
+        };
+
+        // loaded before anything changes, so that whatever changed since the last sync, e.g. a
+        // ciphertext that was tampered with, is never vouched for
+        let opt_integrity =
+            IntegrityManifest::load_expected(&self.out_dir, keys, self.has_integrity)?;
+        let mut integrity = match opt_integrity {
+            Some(integrity) => integrity,
+            None => IntegrityManifest::scan(&self.out_dir)?,
+        };
+
+        // some temp location where the encrypted files are stored before being moved to their
+        // final locations; lives in `out_dir` so that the final `rename` never crosses filesystems
+        let metadata_dir = self.out_dir.join(METADATA_DIR);
+        create_dir_all(&metadata_dir)?;
+        let arena = mktemp_dir("arena", "", Some(&metadata_dir))?;
+
+        // files that used to be directories are handled in `encrypt_file`, and this handles
+        // directories that used to be files; in `Layout::Buckets`, directories only exist in the
+        // index, and whatever they replace is in `plan.delete`
+        let create_dirs = match self.layout {
+            Layout::Tree => &plan.create_dirs[..],
+            Layout::Buckets => &[],
+        };
+        create_dirs
+            .par_iter()
+            .map(|dir| self.out_dir.join(&dir.target))
+            .filter(|target| target.is_file())
+            .map(|target| remove_path(&target))
+            .collect::<Result<(), Error>>()?;
+        create_dirs
+            .par_iter()
+            .map(|dir| {
+                // the sidecar first, so that no long name is ever there without it
+                let target = self.out_dir.join(&dir.target);
+                target.parent().map_or(Ok(()), create_dir_all)?;
+                write_sidecar(&target, &dir.path, keys, self.names)?;
+                create_dir_all(target)
+            })
+            .collect::<Result<(), Error>>()?;
+
+        let src_root = source_root(&self.source);
+        let (synced, failures): (Vec<_>, Vec<(&PathBuf, Error)>) = plan
+            .add
+            .par_iter()
+            .chain(&plan.update)
+            .map(|file| {
+                let source = src_root.join(&file.path);
+                let target = self.out_dir.join(&file.target);
+                let prepared = match self.layout {
+                    Layout::Tree => write_sidecar(&target, &file.path, keys, self.names),
+                    Layout::Buckets => target.parent().map_or(Ok(()), create_dir_all),
+                };
+                prepared
+                    .and_then(|_| self.encrypt_file(&source, &target, arena.path(), keys))
+                    .map(|hash| (file, hash))
+                    .map_err(|err| (&file.path, err!("failed to sync `{:?}`: {}", source, err)))
             })
-            .filter_map(|res_tuple: Result<_, Error>| match res_tuple {
-                Ok(tuple) => Some(tuple),
-                Err(err) => eprintln_then_none!("{}", err),
+            .partition_map(|result| match result {
+                Ok(synced) => Either::Left(synced),
+                Err(failure) => Either::Right(failure),
+            });
+
+        // only once everything else is in place, so that e.g. the old ciphertext of a renamed
+        // file is never gone before the new one is there
+        // directories in `Layout::Buckets` have nothing to remove but their index entries
+        let deleted: Vec<&PlannedDeletion> = plan
+            .delete
+            .iter()
+            .filter(|deletion| !deletion.target.as_os_str().is_empty())
+            .collect();
+        deleted
+            .par_iter()
+            .map(|deletion| {
+                let target = self.out_dir.join(&deletion.target);
+                remove_path(&target)?;
+                sidecar_path(&target).map_or(Ok(()), |sidecar| remove_path(&sidecar))
             })
-            .map(|(source, temp, target)| {
-                let mut encoder = compose_encoders!(
-                    File::open(source).unwrap(),
-                    ZstdEncoder => None,
-                    Encryptor => key_hash
-                )
-                .unwrap(); // TODO handle errors later
-                encoder.write_all_to(&mut File::create(&temp).unwrap());
-                (temp, target)
+            .collect::<Result<(), Error>>()?;
+
+        // vouch for whatever was synced, and for nothing that is gone, including files that became
+        // directories
+        plan.create_dirs.iter().for_each(|dir| {
+            integrity.leaves.remove(&dir.target);
+        });
+        deleted.iter().for_each(|deletion| {
+            integrity.remove_subtree(&deletion.target);
+            if let Some(sidecar) = sidecar_path(&deletion.target) {
+                integrity.leaves.remove(&sidecar);
+            }
+        });
+        synced.iter().for_each(|(file, hash)| {
+            integrity.remove_subtree(&file.target);
+            integrity.leaves.insert(file.target.clone(), hash.clone());
+        });
+        for planned in plan.create_dirs.iter().chain(&plan.add).chain(&plan.update) {
+            match sidecar_path(&planned.target) {
+                Some(sidecar) if self.out_dir.join(&sidecar).is_file() => {
+                    let hash = hash_file(&self.out_dir.join(&sidecar))?;
+                    integrity.leaves.insert(sidecar, hash);
+                }
+                _ => (),
+            }
+        }
+
+        // forget whatever is gone, and record whatever changed; files that failed to sync keep
+        // their old entries, as their old ciphertexts are still in place
+        let failed: HashSet<&PathBuf> = failures.iter().map(|(rel_path, _)| *rel_path).collect();
+        let kept: Vec<(PathBuf, ManifestEntry)> = failed
+            .iter()
+            .filter_map(|rel_path| {
+                manifest
+                    .entries
+                    .get(*rel_path)
+                    .map(|entry| (*rel_path, entry))
             })
-            .for_each(|(temp, target)| {
-                debug_assert!(temp.exists());
-                debug_assert!(!target.exists());
-                rename(temp, target).unwrap()
+            .map(|(rel_path, entry)| (rel_path.clone(), entry.clone()))
+            .collect();
+        plan.scope
+            .iter()
+            .for_each(|rel_root| manifest.remove_subtree(rel_root));
+        plan.entries
+            .iter()
+            .filter(|(rel_path, _)| !failed.contains(rel_path))
+            .map(|(rel_path, entry)| (rel_path.clone(), entry.clone()))
+            .chain(kept)
+            .for_each(|(rel_path, entry)| {
+                manifest.entries.insert(rel_path, entry);
             });
-        println!("src_to_target {:#?}", src_to_target);
-        find(out_dir).for_each(|x| println!("in outdir: {:?}", x));
-        todo!()
+        manifest.store(&self.out_dir, keys)?;
+        let mut metadata_files = vec![manifest_path(&self.out_dir)];
+
+        // whatever is gone first, as a path may be gone as a file and back as a directory, or the
+        // other way around; files that failed to sync keep their old entries, like above
+        if let Some(index) = opt_index.as_mut() {
+            plan.delete
+                .iter()
+                .filter_map(|deletion| deletion.path.as_ref())
+                .for_each(|rel_path| {
+                    index.entries.remove(rel_path);
+                });
+            plan.create_dirs.iter().for_each(|dir| {
+                index.entries.insert(dir.path.clone(), IndexEntry::Dir);
+            });
+            for (file, _) in &synced {
+                let id = file
+                    .target
+                    .file_name()
+                    .and_then(OsStr::to_str)
+                    .unwrap_or("");
+                let entry = IndexEntry::File { id: id.to_string() };
+                index.entries.insert(file.path.clone(), entry);
+            }
+            index.store(&self.out_dir, keys)?;
+            metadata_files.push(index_path(&self.out_dir));
+        }
+
+        for metadata_file in metadata_files {
+            integrity.leaves.insert(
+                metadata_file
+                    .strip_prefix(&self.out_dir)
+                    .map_err(io_err)?
+                    .to_path_buf(),
+                hash_file(&metadata_file)?,
+            );
+        }
+        integrity.store(&self.out_dir, keys)?;
+
+        failures.iter().for_each(|(_, err)| eprintln!("{}", err));
+        match failures.len() {
+            0 => Ok(()),
+            num_failures => Err(err!("failed to sync {} file(s)", num_failures)),
+        }
+    }
+
+    /// Find whatever has to go from `out_dir`: the ciphertexts of `removed`, and whatever is in the
+    /// encrypted subtrees of the directories in `existing` but is not the ciphertext of some path
+    /// in them, e.g. what is left of files that were deleted or renamed.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `existing`: paths in `source`
+    /// 1. `removed`: paths that used to be in `source`
+    /// 1. `src_to_target`: a mapping from every path in `existing` to its ciphertext
+    /// 1. `keys`: the keys of the tree
+    fn plan_deletions(
+        &self,
+        existing: &[PathBuf],
+        removed: &[PathBuf],
+        src_to_target: &HashMap<PathBuf, PathBuf>,
+        keys: &Keys,
+    ) -> Result<Vec<PlannedDeletion>, Error> {
+        let src_root = source_root(&self.source);
+
+        // the ciphertexts of removed paths can be derived without them existing
+        let mut deletions = removed
+            .par_iter()
+            .map(|path| -> Result<Option<PlannedDeletion>, Error> {
+                let rel_path = path.strip_prefix(src_root).map_err(io_err)?;
+                let target = path_ciphertext(rel_path, keys, self.names)?;
+                let deletion = PlannedDeletion {
+                    path: Some(rel_path.to_path_buf()),
+                    size: disk_usage(&self.out_dir.join(&target)),
+                    target,
+                };
+                Ok(Some(deletion).filter(|deletion| {
+                    self.out_dir
+                        .join(&deletion.target)
+                        .symlink_metadata()
+                        .is_ok()
+                }))
+            })
+            .filter_map(Result::transpose)
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let rel_roots: Vec<&Path> = existing
+            .iter()
+            .filter_map(|root| root.strip_prefix(src_root).ok())
+            .collect();
+        deletions.extend(
+            orphans(&self.out_dir, &rel_roots, src_to_target, keys, self.names)
+                .into_par_iter()
+                .map(|(opt_path, target)| PlannedDeletion {
+                    path: opt_path,
+                    size: disk_usage(&self.out_dir.join(&target)),
+                    target,
+                })
+                .collect::<Vec<_>>(),
+        );
+
+        Ok(deletions)
+    }
+
+    /// Compress and encrypt `source` into `arena`, then move the result to `target`, replacing
+    /// whatever was there.
+    ///
+    /// # Returns
+    ///
+    /// The hash of the ciphertext, for the integrity manifest.
+    fn encrypt_file(
+        &self,
+        source: &Path,
+        target: &Path,
+        arena: &Path,
+        keys: &Keys,
+    ) -> Result<String, Error> {
+        let mut arena_file = mktemp_file("", "", Some(arena))?;
+        seal_content(
+            File::open(source)?,
+            &keys.contents,
+            &self.recipients,
+            self.padding,
+            self.contents,
+        )?
+        .write_all_to(arena_file.as_file_mut())?;
+        let hash = hash_file(arena_file.path())?;
+
+        if target.is_dir() {
+            remove_path(target)?;
+        }
+        arena_file
+            .persist(target)
+            .map(|_| hash)
+            .map_err(|err| err.error)
+    }
+
+    /// # Parameters
+    ///
+    /// 1. `source`: the file or directory to sync
+    /// 1. `out_dir`: the directory in which the encrypted tree will be stored; created when a plan
+    ///    is first applied, if it does not exist yet
+    pub fn new(source: &Path, out_dir: &Path) -> Result<Self, Error> {
+        let source = canonicalize(source)?;
+        if source.file_name().is_none() {
+            return Err(err!("cannot sync `{:?}`, as it has no basename", source));
+        }
+
+        let out_dir = canonicalize_missing(out_dir)?;
+        if out_dir.exists() && !out_dir.is_dir() {
+            return Err(err!("`{:?}` is not a directory", out_dir));
+        }
+        if out_dir.starts_with(&source) {
+            return Err(err!("`{:?}` cannot be inside `{:?}`", out_dir, source));
+        }
+
+        Ok(CryptSyncer::new_internal(&source, &out_dir))
+    }
+
+    /// Use `delete_policy` for whatever is no longer in `source`; `DeletePolicy::Mirror` unless
+    /// set otherwise.
+    pub fn with_delete_policy(mut self, delete_policy: DeletePolicy) -> Self {
+        self.delete_policy = delete_policy;
+        self
+    }
+
+    /// Lay out the tree in `out_dir` as `layout` says; `Layout::Tree` unless set otherwise, which
+    /// must match how the tree in `out_dir` was laid out, if at all, as the config of the tree says.
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Encrypt basenames as `names` says; `NameCipher::AesSiv` unless set otherwise, which must
+    /// match how the tree in `out_dir` was encrypted, if any, as the config of the tree says.
+    pub fn with_name_cipher(mut self, names: NameCipher) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Encrypt the content of files as `contents` says; `ContentCipher::Sealed` unless set
+    /// otherwise, which must match how the tree in `out_dir` was encrypted, if any, as the config of
+    /// the tree says.
+    pub fn with_content_cipher(mut self, contents: ContentCipher) -> Self {
+        self.contents = contents;
+        self
+    }
+
+    /// Take a missing integrity manifest for one that was removed, and refuse to sync, if the config
+    /// of the tree says that it `has_integrity`, rather than for a tree that was last synced before
+    /// integrity manifests existed; `true` unless set otherwise.
+    pub fn with_integrity(mut self, has_integrity: bool) -> Self {
+        self.has_integrity = has_integrity;
+        self
+    }
+
+    /// Pad the content of every file as `padding` says before encrypting it, to hide its exact size;
+    /// `Padding::None` unless set otherwise, as the config of the tree says.
+    pub fn with_padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
     }
 
-    pub fn new(source: &Path) -> Result<Self, Error> {
-        let arena = mktemp_dir("", "", None)?;
-        Ok(CryptSyncer::new_internal(source, arena))
+    /// Encrypt the content of every file to the public keys in `recipients`, so that only their
+    /// identities can decrypt it; names and the manifest are still encrypted under the key, which
+    /// is needed to sync either way.
+    pub fn with_recipients(mut self, recipients: Vec<[u8; X25519_KEY_LEN]>) -> Self {
+        self.recipients = recipients;
+        self
     }
 
     // pass optional memo map
     #[inline]
-    fn new_internal(source: &Path, arena: TempDir) -> Self {
+    fn new_internal(source: &Path, out_dir: &Path) -> Self {
         Self {
-            arena,
+            contents: ContentCipher::default(),
+            delete_policy: DeletePolicy::Mirror,
+            has_integrity: true,
+            layout: Layout::Tree,
+            manifest: Mutex::new(None),
+            names: NameCipher::default(),
+            out_dir: out_dir.to_path_buf(),
+            padding: Padding::None,
+            recipients: Vec::new(),
             source: source.to_path_buf(),
         }
     }
 }
 
+/// Find whatever is in the encrypted subtrees of the directories in `rel_roots` but is not the
+/// ciphertext of some path in them, e.g. what is left of files that were deleted or renamed.
+///
+/// # Parameters
+///
+/// 1. `out_dir`: the directory in which the encrypted tree is stored
+/// 1. `rel_roots`: plaintext paths, relative to the parent of `source`
+/// 1. `src_to_target`: a mapping from every path in `rel_roots` to its ciphertext
+/// 1. `keys`: the keys of the tree
+/// 1. `names`: how the basenames of the tree are encrypted
+///
+/// # Returns
+///
+/// The outermost such paths, relative to `out_dir`, each with its plaintext path, if it can be
+/// decrypted.
+pub fn orphans(
+    out_dir: &Path,
+    rel_roots: &[&Path],
+    src_to_target: &HashMap<PathBuf, PathBuf>,
+    keys: &Keys,
+    names: NameCipher,
+) -> Vec<(Option<PathBuf>, PathBuf)> {
+    let targets: HashSet<PathBuf> = src_to_target
+        .values()
+        .map(|target| out_dir.join(target))
+        .collect();
+    let target_to_src: HashMap<&PathBuf, &PathBuf> = src_to_target
+        .iter()
+        .map(|(rel_path, target)| (target, rel_path))
+        .collect();
+
+    let orphans: HashSet<PathBuf> = rel_roots
+        .iter()
+        .filter_map(|rel_root| src_to_target.get(*rel_root))
+        .map(|target_root| out_dir.join(target_root))
+        .filter(|target_root| target_root.is_dir())
+        .flat_map(|target_root| find(&target_root).filter_map(Result::ok))
+        .filter(|target| !targets.contains(target))
+        // sidecars go wherever their long names go, unless they have none
+        .filter(|target| !is_sidecar(target) || !target.with_extension("").exists())
+        .collect();
+
+    outermost_paths(&orphans)
+        .par_iter()
+        .filter_map(|orphan| orphan.strip_prefix(out_dir).ok())
+        .map(|target| {
+            // the parent of an outermost orphan is always a ciphertext of a known path
+            let opt_path = target
+                .parent()
+                .and_then(|parent| target_to_src.get(&parent.to_path_buf()))
+                .and_then(|parent| {
+                    let key = parent_derived_hash(Some(parent), keys).ok()?;
+                    let ciphertext = stored_ciphertext(&out_dir.join(target)).ok()?;
+                    let basename = decrypt_basename(&ciphertext, &key, names).ok()?;
+                    Some(parent.join(basename))
+                });
+            (opt_path, target.to_path_buf())
+        })
+        .collect()
+}
+
+/// Work out whether a file changed since it was last synced, or has to be encrypted another way
+/// now, without writing anything.
+///
+/// # Parameters
+///
+/// 1. `source`: the file to check
+/// 1. `opt_entry`: the manifest entry of `source` as of the last sync, if its ciphertext is there
+/// 1. `padding`: how the content of `source` is padded now
+/// 1. `recipients`: the fingerprint of the recipients it is encrypted to now; see
+///    `recipients_fingerprint`
+///
+/// # Returns
+///
+/// Whether `source` has to be encrypted again, and its manifest entry as of now; no entry if
+/// `source` cannot be read, in which case encrypting it will fail and say why.
+fn plan_file(
+    source: &Path,
+    opt_entry: Option<&ManifestEntry>,
+    padding: Padding,
+    recipients: &str,
+) -> (bool, Option<ManifestEntry>) {
+    let (size, mtime) = match (metadata(source), modified(source)) {
+        (Ok(metadata), Ok(mtime)) => (metadata.len(), mtime),
+        _ => return (true, None),
+    };
+
+    // e.g. removed recipients must not be able to decrypt the ciphertext any longer
+    let opt_entry =
+        opt_entry.filter(|entry| entry.padding == padding && entry.recipients == recipients);
+
+    // cheap check first; hashing means reading the whole file
+    if let Some(entry) = opt_entry {
+        if entry.size == size && entry.mtime == mtime {
+            return (false, Some(entry.clone()));
+        }
+    }
+
+    let content_hash = match hash_file(source) {
+        Ok(content_hash) => content_hash,
+        Err(_) => return (true, None),
+    };
+    let changed = match opt_entry {
+        // e.g. `touch`ed, or changed then changed back
+        Some(entry) => entry.content_hash != content_hash,
+        None => true,
+    };
+
+    let entry = ManifestEntry {
+        size,
+        mtime,
+        content_hash,
+        padding,
+        recipients: recipients.to_string(),
+    };
+    (changed, Some(entry))
+}
+
+/// Like `canonicalize`, but `path` need not exist; only its closest existing ancestor does.
+fn canonicalize_missing(path: &Path) -> Result<PathBuf, Error> {
+    if path.exists() {
+        return canonicalize(path);
+    }
+
+    let basename = path
+        .file_name()
+        .ok_or(err!("failed to get the basename of `{:?}`", path))?;
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            Ok(canonicalize_missing(parent)?.join(basename))
+        }
+        _ => Ok(canonicalize(".")?.join(basename)),
+    }
+}
+
+/// # Returns
+///
+/// The directory relative to which every path in the encrypted tree is derived, i.e. the parent
+/// of `source`, so that the root of the encrypted tree is the ciphertext of the basename of
+/// `source`, regardless of where `source` is.
+#[inline]
+pub fn source_root(source: &Path) -> &Path {
+    source.parent().unwrap_or(Path::new(""))
+}
+
 /// Make a mapping from some `p: PathBuf` to its ciphertext form `c: PathBuf`.
 ///
 /// # Parameters
 ///
 /// 1. `basename_ciphertexts`: a mapping from some path to the ciphertext that will be used as its
 ///    encrypted basename
-/// 2. `key_hash`: hash of the key to use, for symmetric encryption
 ///
 /// # Returns
 ///
-/// A mapping from a path to its ciphertext that will be used as its encrypted path.
+/// A mapping from a path to its ciphertext that will be used as its encrypted path. Paths with
+/// an ancestor that has no ciphertext are left out, as they have nowhere to go.
 ///
 /// For example given a `bc = basename_ciphertexts` and some path `p = "p1/p2/p3"` will return
 /// `bc["p1"]/bc["p1/p2"]/bc["p1/p2/p3"]`.
-fn path_ciphertexts(basename_ciphertexts: &HashMap<PathBuf, String>) -> HashMap<PathBuf, PathBuf> {
+pub fn path_ciphertexts(
+    basename_ciphertexts: &HashMap<PathBuf, String>,
+) -> HashMap<PathBuf, PathBuf> {
     basename_ciphertexts
         .keys()
         .par_bridge()
-        .cloned()
-        .map(|source_path_buf| {
-            source_path_buf.components().fold(
+        .filter_map(|source_path_buf| {
+            source_path_buf.components().try_fold(
                 // initial value: tuple (acc_src, acc_enc)
                 (PathBuf::new(), PathBuf::new()),
                 //
                 |(mut acc_src, mut acc_enc), comp| match comp {
                     Component::Normal(component) => {
                         acc_src.push(component);
-                        match basename_ciphertexts.get(&acc_src) {
-                            Some(value) => acc_enc.push(value),
-                            None => (),
-                        };
-                        (acc_src, acc_enc)
+                        acc_enc.push(basename_ciphertexts.get(&acc_src)?);
+                        Some((acc_src, acc_enc))
                     }
-                    _ => (acc_src, acc_enc),
+                    _ => Some((acc_src, acc_enc)),
                 },
             )
         })
         .collect()
 }
 
-/// Make a mapping from each file in `source`, including itself, to its corresponding ciphertext
-/// that will be used to as its encrypted basename.
+/// Make a mapping from each path in `paths` to its corresponding ciphertext that will be used to
+/// as its encrypted basename.
 ///
 /// # Parameters
 ///
-/// 1. `source`: the root of the search
-/// 2. `key_hash`: hash of the key to use, for symmetric encryption
+/// 1. `src_root`: the directory relative to which the paths are derived; see `source_root`
+/// 2. `paths`: the paths in `src_root` to encrypt the basenames of
+/// 3. `keys`: the keys of the tree
+/// 4. `names`: how to encrypt the basenames
 ///
 /// # Returns
 ///
-/// Some mapping `bc` such that for some path `p = [p1, p2, ..., pn]`:
+/// Some mapping `bc` from paths relative to `src_root`, such that for some path
+/// `p = [p1, p2, ..., pn]`:
 /// ```text
-/// if p is source or p.parent == None
-///     bc[p] = encrypt(pn, key_hash)
-/// else
-///     key = hash([p1, p2, ... p_{n-1}])
-///     bc[p] = encrypt(pn, key)
+/// key = parent_derived_hash([p1, p2, ... p_{n-1}], keys)
+/// bc[p] = stored_basename(encrypt(pn, key))
 /// ```
-fn basename_ciphertexts(source: &Path, key_hash: &[u8]) -> HashMap<PathBuf, String> {
+pub fn basename_ciphertexts<I>(
+    src_root: &Path,
+    paths: I,
+    keys: &Keys,
+    names: NameCipher,
+) -> HashMap<PathBuf, String>
+where
+    I: Iterator<Item = Result<PathBuf, Error>> + Send,
+{
     // TODO standardize the error reports
-    find(source)
+    paths
         .par_bridge()
         .filter_map(|opt_path_buf| match opt_path_buf {
             // :: Result<PathBuf> -> Option<PathBuf>
             Ok(path_buf) => Some(path_buf),
             Err(err) => eprintln_then_none!("{}", err),
         })
-        .map(|path_buf| match path_buf.file_name().map(OsStr::to_str) {
-            // :: PathBuf -> Result<(PathBuf, SString)>
-            Some(Some(basesname_str)) => {
-                let opt_parent = path_buf.parent().map(Path::to_str);
-                let parent_derived_hash = match opt_parent {
-                    Some(Some(parent_str)) if &path_buf != source => {
-                        hash_custom(key_hash, Some(parent_str.as_bytes()), Some(1))
-                    }
-                    _ => Vec::from(key_hash),
-                };
-
-                let ciphertext = compose_encoders!(
-                    basesname_str.as_bytes(),
-                    Encryptor => &parent_derived_hash,
-                    TextEncoder => None
-                )?
-                .as_string()?;
-
-                Ok((path_buf, ciphertext))
+        .map(|path_buf| {
+            // :: PathBuf -> Result<(PathBuf, String)>
+            let rel_path = path_buf.strip_prefix(src_root).map_err(io_err)?;
+            match rel_path.file_name().map(OsStr::to_str) {
+                Some(Some(basename_str)) => {
+                    let key = parent_derived_hash(rel_path.parent(), keys)?;
+                    let ciphertext = encrypt_basename(basename_str, &key, names)?;
+                    Ok((rel_path.to_path_buf(), stored_basename(ciphertext)))
+                }
+                _ => Err(err!("`{:?}` contains non utf8 chars", path_buf)),
             }
-            _ => Err(err!("`{:?}` contains non utf8 chars", path_buf)),
         })
-        .filter_map(|res| match res {
+        .filter_map(|res: Result<_, Error>| match res {
             Ok(v) => Some(v),
             Err(err) => eprintln_then_none!("{}", err),
         })
@@ -202,21 +995,427 @@ fn modified(source: &Path) -> Result<SystemTime, Error> {
     metadata(source)?.modified()
 }
 
-fn arena_basename(source: &Path) -> Result<String, Error> {
-    let bytes = source.to_str().ok_or(err!("{:?}", source))?.as_bytes();
-    hash_base64_pathsafe(bytes)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypt::crypt_restorer::*;
+    use crate::crypt::crypt_verifier::*;
+    use crate::crypt::test_util::*;
+    use crate::encoder::cryptor::generate_x25519;
+    use std::fs::write;
+    use std::io::Read;
+
+    #[test]
+    fn sync_then_decrypt() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let out_dir = mktemp_dir("", "", None)?;
+        let syncer = CryptSyncer::new(Path::new("src/"), out_dir.path())?;
+        syncer.sync(&keys)?;
+
+        let src_root = source_root(&syncer.source);
+        let src_to_target = path_ciphertexts(&basename_ciphertexts(
+            src_root,
+            find(&syncer.source),
+            &keys,
+            NameCipher::AesSiv,
+        ));
+
+        // every file and dir in `src/` has exactly one counterpart in `out_dir`
+        let num_synced = find(out_dir.path())
+            .map(Result::unwrap)
+            .filter(|path| !path.starts_with(out_dir.path().join(METADATA_DIR)))
+            .count();
+        assert_eq!(find(&syncer.source).count() + 1, num_synced);
+        assert_eq!(find(&syncer.source).count(), src_to_target.len());
+
+        src_to_target
+            .par_iter()
+            .map(|(rel_path, target)| (src_root.join(rel_path), out_dir.path().join(target)))
+            .filter(|(source, _)| source.is_file())
+            .map(|(source, target)| -> Result<(), Error> {
+                let decrypted =
+                    open_decrypted(File::open(&target)?, &keys.contents, None)?.as_vec()?;
+
+                let mut expected = Vec::new();
+                File::open(&source)?.read_to_end(&mut expected)?;
+
+                assert_eq!(expected, decrypted);
+                Ok(())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn out_dir_inside_source_is_rejected() {
+        assert!(CryptSyncer::new(Path::new("src/"), Path::new("src/crypt/")).is_err());
+    }
+
+    #[test]
+    fn path_ciphertext_matches_path_ciphertexts() {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let source = canonicalize("src/").unwrap();
+        let src_root = source_root(&source);
+        let src_to_target = path_ciphertexts(&basename_ciphertexts(
+            src_root,
+            find(&source),
+            &keys,
+            NameCipher::AesSiv,
+        ));
+
+        src_to_target.par_iter().for_each(|(rel_path, target)| {
+            assert_eq!(
+                target,
+                &path_ciphertext(rel_path, &keys, NameCipher::AesSiv).unwrap()
+            );
+        });
+    }
+
+    #[test]
+    fn sync_paths_then_restore() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&[
+            ("modified", Some("before")),
+            ("removed", Some("removed")),
+            ("dir/subdir/file", Some("file")),
+            ("became_dir", Some("file")),
+        ])?;
+        let out_dir = mktemp_dir("", "", None)?;
+        let syncer = CryptSyncer::new(&source, out_dir.path())?;
+        syncer.sync(&keys)?;
+
+        write(source.join("modified"), "after")?;
+        remove_path(&source.join("removed"))?;
+        std::fs::rename(source.join("dir"), source.join("renamed"))?;
+        remove_path(&source.join("became_dir"))?;
+        create_dir_all(source.join("became_dir"))?;
+        write(source.join("became_dir/file"), "file")?;
+        write(source.join("added"), "added")?;
+
+        let changed: HashSet<_> = [
+            "modified",
+            "removed",
+            "dir",
+            "renamed",
+            "became_dir",
+            "added",
+        ]
+        .iter()
+        .map(|basename| source.join(basename))
+        .collect();
+        syncer.sync_paths(&changed, &keys)?;
+
+        // the integrity manifest vouches for exactly what is there now
+        let integrity = IntegrityManifest::load(out_dir.path(), &keys)?.unwrap();
+        assert!(integrity.check(out_dir.path())?.is_empty());
+
+        assert_restores(&source, out_dir.path(), &keys, Layout::Tree)
+    }
+
+    #[test]
+    fn padded_sync_then_restore() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&[("empty", Some(""))])?;
+        write(source.join("short"), drng(1000))?;
+        write(source.join("long"), drng(1010))?;
+
+        let out_dir = mktemp_dir("", "", None)?;
+        CryptSyncer::new(&source, out_dir.path())?
+            .with_padding(Padding::PowerOfTwo)
+            .sync(&keys)?;
+        let ciphertext_len = |rel_path: &str| -> Result<u64, Error> {
+            Ok(metadata(target(out_dir.path(), rel_path, &keys))?.len())
+        };
+        assert_eq!(ciphertext_len("short")?, ciphertext_len("long")?);
+
+        // the padding is dropped on restore, and vouched for like any other ciphertext
+        assert_restores(&source, out_dir.path(), &keys, Layout::Tree)?;
+        let integrity = IntegrityManifest::load(out_dir.path(), &keys)?.unwrap();
+        assert!(integrity.check(out_dir.path())?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn buckets_sync_then_restore() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&[
+            ("dir/sub/deep/file", Some("deep")),
+            ("dir/file", Some("file")),
+            ("became_dir", Some("file")),
+            ("empty", None),
+        ])?;
+        let out_dir = mktemp_dir("", "", None)?;
+        let syncer = CryptSyncer::new(&source, out_dir.path())?.with_layout(Layout::Buckets);
+        let check_restores = || -> Result<(), Error> {
+            // nothing but ciphertexts named after their ids, in buckets two levels deep
+            let metadata_dir = out_dir.path().join(METADATA_DIR);
+            let targets: Vec<PathBuf> = find(out_dir.path())
+                .map(Result::unwrap)
+                .filter(|path| !path.starts_with(&metadata_dir) && path.is_file())
+                .map(|path| path.strip_prefix(out_dir.path()).unwrap().to_path_buf())
+                .collect();
+            for target in &targets {
+                let id = target.file_name().unwrap().to_str().unwrap();
+                assert_eq!(&bucket_path(id), target);
+            }
+            let num_files = find(&source).filter(|path| path.as_ref().unwrap().is_file());
+            assert_eq!(num_files.count(), targets.len());
+
+            assert_restores(&source, out_dir.path(), &keys, Layout::Buckets)?;
+            let integrity = IntegrityManifest::load(out_dir.path(), &keys)?.unwrap();
+            assert!(integrity.check(out_dir.path())?.is_empty());
+            Ok(())
+        };
+
+        syncer.sync(&keys)?;
+        check_restores()?;
+
+        remove_path(&source.join("became_dir"))?;
+        create_dir_all(source.join("became_dir"))?;
+        write(source.join("became_dir/file"), "nested")?;
+        std::fs::rename(source.join("dir/sub"), source.join("renamed"))?;
+        remove_path(&source.join("empty"))?;
+        let changed: HashSet<_> = ["became_dir", "dir", "renamed", "empty"]
+            .iter()
+            .map(|basename| source.join(basename))
+            .collect();
+        syncer.sync_paths(&changed, &keys)?;
+        check_restores()?;
+
+        // whatever the index does not point to goes with the next sync of the whole tree
+        write(out_dir.path().join("stray"), "stray")?;
+        syncer.sync(&keys)?;
+        check_restores()
+    }
+
+    #[test]
+    fn long_names_sync_then_restore() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let long_name = "d".repeat(200);
+        let (_src_dir, source) = test_source(&[(&format!("{}/short", long_name), Some("short"))])?;
+        let long_dir = source.join(long_name);
+        write(long_dir.join("f".repeat(250)), "long")?;
+
+        let out_dir = mktemp_dir("", "", None)?;
+        let num_sidecars = || {
+            find(out_dir.path())
+                .filter_map(Result::ok)
+                .filter(|path| is_sidecar(path))
+                .count()
+        };
+        let check_restores = || -> Result<(), Error> {
+            assert_restores(&source, out_dir.path(), &keys, Layout::Tree)?;
+            let integrity = IntegrityManifest::load(out_dir.path(), &keys)?.unwrap();
+            assert!(integrity.check(out_dir.path())?.is_empty());
+            Ok(())
+        };
+
+        CryptSyncer::new(&source, out_dir.path())?.sync(&keys)?;
+        assert!(find(out_dir.path()).filter_map(Result::ok).all(|path| path
+            .file_name()
+            .unwrap()
+            .len()
+            <= LONG_NAME_MAX));
+        assert_eq!(2, num_sidecars());
+        check_restores()?;
+
+        // the sidecars of long names that are gone go along with them
+        std::fs::rename(
+            long_dir.join("f".repeat(250)),
+            long_dir.join("g".repeat(250)),
+        )?;
+        CryptSyncer::new(&source, out_dir.path())?.sync(&keys)?;
+        assert_eq!(2, num_sidecars());
+        check_restores()?;
+
+        remove_path(&long_dir)?;
+        CryptSyncer::new(&source, out_dir.path())?.sync(&keys)?;
+        assert_eq!(0, num_sidecars());
+        check_restores()
+    }
 
     #[test]
-    fn temp() {
-        let key_hash = hash("aoisjfk1".as_bytes());
-        let out_dir = mktemp_dir("", "", None).unwrap();
-        let syncer = CryptSyncer::new(Path::new("src/")).unwrap();
-        syncer.sync(&out_dir.path(), &key_hash[..]);
-        assert!(false);
+    fn resync_skips_unchanged_files() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&[
+            ("unchanged", Some("unchanged")),
+            ("touched", Some("touched")),
+            ("modified", Some("before")),
+        ])?;
+        let out_dir = synced(&source, &keys)?;
+
+        let target_mtimes = || -> Vec<SystemTime> {
+            ["unchanged", "touched", "modified"]
+                .iter()
+                .map(|rel_path| modified(&target(out_dir.path(), rel_path, &keys)).unwrap())
+                .collect()
+        };
+        let before = target_mtimes();
+
+        // make sure that mtimes differ even on filesystems with coarse timestamps
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        write(source.join("touched"), "touched")?;
+        write(source.join("modified"), "after")?;
+
+        // a new syncer, so that the manifest is loaded from `out_dir`
+        CryptSyncer::new(&source, out_dir.path())?.sync(&keys)?;
+        let after = target_mtimes();
+        assert_eq!(before[0], after[0]);
+        assert_eq!(before[1], after[1]);
+        assert_ne!(before[2], after[2]);
+
+        let manifest = Manifest::load(out_dir.path(), &keys)?;
+        assert_eq!(3, manifest.entries.len());
+        let touched = &manifest.entries[Path::new("source/touched")];
+        assert_eq!(modified(&source.join("touched"))?, touched.mtime);
+        Ok(())
+    }
+
+    #[test]
+    fn removed_integrity_manifest_is_tampering() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&[("file", Some("file"))])?;
+        let out_dir = synced(&source, &keys)?;
+        remove_path(&integrity_path(out_dir.path()))?;
+
+        // so whatever was put in place of the ciphertexts is never vouched for
+        let err = CryptSyncer::new(&source, out_dir.path())?
+            .sync(&keys)
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        assert!(IntegrityManifest::load_expected(out_dir.path(), &keys, true).is_err());
+        let report = CryptVerifier::new(&source, out_dir.path())?.verify(&keys)?;
+        assert_eq!(
+            vec![(
+                Path::new(METADATA_DIR).join(INTEGRITY_FILE),
+                Tampering::Missing
+            )],
+            report.tampered
+        );
+
+        // unless the config says that the tree predates integrity manifests
+        let syncer = CryptSyncer::new(&source, out_dir.path())?.with_integrity(false);
+        syncer.sync(&keys)?;
+        let integrity = IntegrityManifest::load_expected(out_dir.path(), &keys, true)?.unwrap();
+        assert!(integrity.check(out_dir.path())?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn resync_reencrypts_when_encryption_changes() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&[("a", Some("a")), ("b", Some("b"))])?;
+        let out_dir = mktemp_dir("", "", None)?;
+        let num_updated = |syncer: CryptSyncer| -> Result<usize, Error> {
+            let plan = syncer.plan(&keys)?;
+            syncer.apply(&plan, &keys)?;
+            Ok(plan.update.len())
+        };
+        let (_, alice) = generate_x25519()?;
+        let (_, bob) = generate_x25519()?;
+        let syncer = || CryptSyncer::new(&source, out_dir.path());
+        assert_eq!(0, num_updated(syncer()?)?);
+        assert_eq!(0, num_updated(syncer()?)?);
+        assert_eq!(2, num_updated(syncer()?.with_padding(Padding::Padme))?);
+        let to_both = || -> Result<CryptSyncer, Error> {
+            Ok(syncer()?
+                .with_padding(Padding::Padme)
+                .with_recipients(vec![alice, bob]))
+        };
+        assert_eq!(2, num_updated(to_both()?)?);
+        assert_eq!(0, num_updated(to_both()?)?);
+
+        // a recipient that was removed can no longer decrypt anything
+        let to_alice = syncer()?
+            .with_padding(Padding::Padme)
+            .with_recipients(vec![alice]);
+        assert_eq!(2, num_updated(to_alice)?);
+        Ok(())
+    }
+
+    #[test]
+    fn delete_policy_decides_what_happens_to_orphans() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&[
+            ("removed", Some("removed")),
+            ("dir/file", Some("file")),
+            ("kept", Some("kept")),
+        ])?;
+        let mirror_dir = synced(&source, &keys)?;
+        let archive_dir = synced(&source, &keys)?;
+
+        remove_path(&source.join("removed"))?;
+        std::fs::rename(source.join("dir"), source.join("renamed"))?;
+        let after = read_tree(&source);
+
+        // full syncs with new syncers, so nothing but `out_dir` tells what used to be there
+        CryptSyncer::new(&source, mirror_dir.path())?
+            .with_delete_policy(DeletePolicy::Mirror)
+            .sync(&keys)?;
+        CryptSyncer::new(&source, archive_dir.path())?
+            .with_delete_policy(DeletePolicy::Archive)
+            .sync(&keys)?;
+
+        for out_dir in [&mirror_dir, &archive_dir].iter() {
+            let integrity = IntegrityManifest::load(out_dir.path(), &keys)?.unwrap();
+            assert!(integrity.check(out_dir.path())?.is_empty());
+        }
+
+        assert_restores(&source, mirror_dir.path(), &keys, Layout::Tree)?;
+
+        let plain_dir = mktemp_dir("", "", None)?;
+        CryptRestorer::new(archive_dir.path(), plain_dir.path())?.restore(&keys)?;
+        let archived = read_tree(&plain_dir.path().join("source"));
+        assert_eq!(after.len() + 3, archived.len());
+        assert!(after
+            .iter()
+            .all(|(path, content)| archived[path] == *content));
+        assert_eq!(Some(b"removed".to_vec()), archived[Path::new("removed")]);
+        assert_eq!(Some(b"file".to_vec()), archived[Path::new("dir/file")]);
+        Ok(())
+    }
+
+    #[test]
+    fn plan_writes_nothing_until_applied() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&[
+            ("dir", None),
+            ("unchanged", Some("unchanged")),
+            ("modified", Some("before")),
+            ("removed", Some("removed")),
+        ])?;
+        let out_parent = mktemp_dir("", "", None)?;
+        let out_dir = out_parent.path().join("out");
+        let syncer = CryptSyncer::new(&source, &out_dir)?;
+        let plan = syncer.plan(&keys)?;
+        assert!(!out_dir.exists());
+
+        let paths = |planned: &[PlannedPath]| -> Vec<PathBuf> {
+            planned.iter().map(|planned| planned.path.clone()).collect()
+        };
+        let expected: Vec<_> = ["source", "source/dir"].iter().map(PathBuf::from).collect();
+        assert_eq!(expected, paths(&plan.create_dirs));
+        assert_eq!(3, plan.add.len());
+        assert_eq!(22, plan.bytes_to_encrypt());
+        assert!(plan.update.is_empty() && plan.delete.is_empty());
+
+        syncer.apply(&plan, &keys)?;
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        write(source.join("modified"), "after")?;
+        write(source.join("dir/added"), "added")?;
+        remove_path(&source.join("removed"))?;
+
+        let syncer = CryptSyncer::new(&source, &out_dir)?;
+        let plan = syncer.plan(&keys)?;
+        assert!(plan.create_dirs.is_empty());
+        assert_eq!(vec![PathBuf::from("source/dir/added")], paths(&plan.add));
+        assert_eq!(vec![PathBuf::from("source/modified")], paths(&plan.update));
+        let deleted: Vec<_> = plan.delete.iter().map(|deletion| &deletion.path).collect();
+        assert_eq!(vec![&Some(PathBuf::from("source/removed"))], deleted);
+
+        let json = serde_json::to_string(&plan).map_err(io_err)?;
+        let plan: SyncPlan = serde_json::from_str(&json).map_err(io_err)?;
+        syncer.apply(&plan, &keys)?;
+        assert!(syncer.plan(&keys)?.to_string().starts_with("0 dir(s)"));
+        assert_restores(&source, &out_dir, &keys, Layout::Tree)
     }
 }
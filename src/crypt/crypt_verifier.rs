@@ -0,0 +1,447 @@
+use rayon::prelude::*;
+use std::fmt;
+use std::fs::canonicalize;
+use std::fs::read;
+use std::fs::File;
+use std::io::Error;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::crypt::crypt_encoder::*;
+use crate::crypt::crypt_syncer::*;
+use crate::crypt::file_header::*;
+use crate::crypt::index::*;
+use crate::crypt::integrity::*;
+use crate::crypt::key_schedule::*;
+use crate::crypt::name_cipher::*;
+use crate::encoder::cryptor::X25519_KEY_LEN;
+use crate::util::*;
+
+/// Checks that an encrypted tree decrypts back to its source, byte for byte.
+#[derive(Debug)]
+pub struct CryptVerifier {
+    contents: ContentCipher, // how the content of files is encrypted
+    // whether the tree has an integrity manifest, as its config says, so that one that is missing
+    // was removed
+    has_integrity: bool,
+    layout: Layout,    // how the encrypted tree is laid out
+    names: NameCipher, // how basenames are encrypted
+    // the secret key to decrypt contents with, if they were encrypted to public keys
+    opt_identity: Option<[u8; X25519_KEY_LEN]>,
+    out_dir: PathBuf, // path to the dir in which the encrypted tree is stored
+    source: PathBuf,  // path to the source file/dir, canonicalized
+}
+
+/// Everything that is wrong with an encrypted tree, as found by `CryptVerifier::verify`; every
+/// path is a plaintext path, relative to the parent of `source`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub num_checked: usize,    // number of files that were decrypted and compared
+    pub missing: Vec<PathBuf>, // in `source`, but not in `out_dir`
+    // in `out_dir`, but not in `source`; the ciphertext path, relative to `out_dir`, comes along
+    // for those that cannot be decrypted, or are not in the index
+    pub extra: Vec<(Option<PathBuf>, PathBuf)>,
+    pub corrupt: Vec<(PathBuf, String)>, // cannot be decrypted, along with why
+    pub differing: Vec<PathBuf>,         // decrypted fine, but not to what is in `source`
+    // ciphertexts that are not as the integrity manifest of the last sync says, relative to
+    // `out_dir`, e.g. as they were deleted, swapped or rolled back since
+    pub tampered: Vec<(PathBuf, Tampering)>,
+    // the root of the integrity manifest, if the tree has one
+    pub opt_root: Option<String>,
+}
+
+impl VerifyReport {
+    /// # Returns
+    ///
+    /// The number of problems that were found.
+    pub fn num_problems(&self) -> usize {
+        self.missing.len()
+            + self.extra.len()
+            + self.corrupt.len()
+            + self.differing.len()
+            + self.tampered.len()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for path in &self.missing {
+            writeln!(f, "missing   {}", path.display())?;
+        }
+        for (opt_path, target) in &self.extra {
+            match opt_path {
+                Some(path) => writeln!(f, "extra     {}", path.display())?,
+                None => writeln!(f, "extra     {} (cannot be decrypted)", target.display())?,
+            }
+        }
+        for (path, reason) in &self.corrupt {
+            writeln!(f, "corrupt   {}: {}", path.display(), reason)?;
+        }
+        for path in &self.differing {
+            writeln!(f, "differs   {}", path.display())?;
+        }
+        for (target, tampering) in &self.tampered {
+            writeln!(f, "tampered  {} ({})", target.display(), tampering.name())?;
+        }
+        match (&self.opt_root, self.tampered.is_empty()) {
+            (Some(root), _) => writeln!(f, "root      {}", root)?,
+            (None, true) => writeln!(
+                f,
+                "root      none; synced before integrity manifests existed"
+            )?,
+            (None, false) => writeln!(f, "root      none; the integrity manifest was removed")?,
+        }
+
+        write!(
+            f,
+            "{} file(s) checked; {} missing, {} extra, {} corrupt, {} differing, {} tampered",
+            self.num_checked,
+            self.missing.len(),
+            self.extra.len(),
+            self.corrupt.len(),
+            self.differing.len(),
+            self.tampered.len()
+        )
+    }
+}
+
+// what became of every path in `source`, along with whether it is a file
+type Checks = Vec<(PathBuf, bool, Check)>;
+
+// whatever is in `out_dir`, but not in `source`; see `VerifyReport`
+type Extra = Vec<(Option<PathBuf>, PathBuf)>;
+
+// what became of a single path in `source`
+enum Check {
+    Missing,
+    Corrupt(String),
+    Differing,
+    Fine,
+}
+
+impl CryptVerifier {
+    /// Decrypt every file in the encrypted tree of `source` in memory, and compare it with its
+    /// counterpart in `source`, without writing anything. Every ciphertext is also checked against
+    /// the integrity manifest of the last sync, if there is one, which catches what decrypts fine
+    /// but is not what was synced, e.g. a ciphertext that was swapped with another; if there should
+    /// be one but is not, the integrity manifest itself is reported as missing.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `keys`: the keys of the tree
+    ///
+    /// # Returns
+    ///
+    /// What is missing from, extra in, corrupt in, or different in the encrypted tree; the tree is
+    /// intact if and only if there are no problems in the report.
+    pub fn verify(&self, keys: &Keys) -> Result<VerifyReport, Error> {
+        let (checks, extra) = match self.layout {
+            Layout::Tree => self.check_tree(keys)?,
+            Layout::Buckets => self.check_buckets(keys)?,
+        };
+
+        let mut report = VerifyReport {
+            extra,
+            ..VerifyReport::default()
+        };
+        for (rel_path, is_file, check) in checks {
+            report.num_checked += is_file as usize;
+            match check {
+                Check::Missing => report.missing.push(rel_path),
+                Check::Corrupt(reason) => report.corrupt.push((rel_path, reason)),
+                Check::Differing => report.differing.push(rel_path),
+                Check::Fine => (),
+            }
+        }
+
+        match IntegrityManifest::load(&self.out_dir, keys)? {
+            Some(integrity) => {
+                report.tampered = integrity.check(&self.out_dir)?;
+                report.opt_root = Some(integrity.root());
+            }
+            None if integrity_removed(&self.out_dir, self.has_integrity)? => {
+                let target = Path::new(METADATA_DIR).join(INTEGRITY_FILE);
+                report.tampered = vec![(target, Tampering::Missing)];
+            }
+            None => (),
+        }
+
+        report.missing.sort();
+        report.extra.sort();
+        report.corrupt.sort();
+        report.differing.sort();
+        Ok(report)
+    }
+
+    /// Check every path in `source` against its ciphertext, derived from its path, as in
+    /// `Layout::Tree`.
+    ///
+    /// # Returns
+    ///
+    /// What became of every path in `source`, along with whether it is a file, and whatever is in
+    /// `out_dir` that is not in `source`.
+    fn check_tree(&self, keys: &Keys) -> Result<(Checks, Extra), Error> {
+        let src_root = source_root(&self.source);
+        let src_to_target = path_ciphertexts(&basename_ciphertexts(
+            src_root,
+            find(&self.source),
+            keys,
+            self.names,
+        ));
+
+        let mut checks: Checks = src_to_target
+            .par_iter()
+            .map(|(rel_path, target)| {
+                let source = src_root.join(rel_path);
+                let target = self.out_dir.join(target);
+                let check = match (source.is_dir(), target.is_dir(), target.is_file()) {
+                    (true, true, _) => Check::Fine,
+                    (false, _, true) => self.check_file(&source, &target, keys),
+                    (_, false, false) => Check::Missing,
+                    _ => Check::Differing, // a file became a dir or vice versa
+                };
+                (rel_path.clone(), source.is_file(), check)
+            })
+            .collect();
+
+        // paths in `source` that cannot be encrypted, e.g. as they are not utf8, have nowhere to go
+        checks.extend(
+            find(&self.source)
+                .filter_map(Result::ok)
+                .filter_map(|path| path.strip_prefix(src_root).map(Path::to_path_buf).ok())
+                .filter(|rel_path| !src_to_target.contains_key(rel_path))
+                .map(|rel_path| (rel_path, false, Check::Missing)),
+        );
+
+        let rel_root = self.source.strip_prefix(src_root).map_err(io_err)?;
+        let extra = orphans(&self.out_dir, &[rel_root], &src_to_target, keys, self.names);
+        Ok((checks, extra))
+    }
+
+    /// Check every path in `source` against its entry in the index, and its ciphertext, as in
+    /// `Layout::Buckets`; returns the same as `check_tree`.
+    fn check_buckets(&self, keys: &Keys) -> Result<(Checks, Extra), Error> {
+        let index = Index::load(&self.out_dir, keys)?;
+        let src_root = source_root(&self.source);
+        let rel_paths: Vec<PathBuf> = find(&self.source)
+            .filter_map(Result::ok)
+            .filter_map(|path| path.strip_prefix(src_root).map(Path::to_path_buf).ok())
+            .collect();
+
+        let checks = rel_paths
+            .par_iter()
+            .map(|rel_path| {
+                let source = src_root.join(rel_path);
+                let check = match (source.is_dir(), index.entries.get(rel_path)) {
+                    (true, Some(IndexEntry::Dir)) => Check::Fine,
+                    (false, Some(IndexEntry::File { id })) => {
+                        let target = self.out_dir.join(bucket_path(id));
+                        match target.is_file() {
+                            true => self.check_file(&source, &target, keys),
+                            false => Check::Missing,
+                        }
+                    }
+                    (_, None) => Check::Missing,
+                    _ => Check::Differing, // a file became a dir or vice versa
+                };
+                (rel_path.clone(), source.is_file(), check)
+            })
+            .collect();
+
+        // every entry of the subtree that is gone from `source`, rather than only the outermost
+        let rel_root = self.source.strip_prefix(src_root).map_err(io_err)?;
+        let mut extra: Extra = index
+            .entries
+            .iter()
+            .filter(|(rel_path, _)| rel_path.starts_with(rel_root))
+            .filter(|(rel_path, _)| src_root.join(rel_path).symlink_metadata().is_err())
+            .map(|(rel_path, entry)| (Some(rel_path.clone()), entry.target().unwrap_or_default()))
+            .collect();
+        extra.extend(
+            index
+                .unindexed(&self.out_dir)
+                .into_iter()
+                .map(|target| (None, target)),
+        );
+        Ok((checks, extra))
+    }
+
+    /// Decrypt `target` in memory, and compare it with `source`.
+    fn check_file(&self, source: &Path, target: &Path, keys: &Keys) -> Check {
+        let decrypt = || -> Result<Vec<u8>, Error> {
+            open_content(
+                File::open(target)?,
+                &keys.contents,
+                self.opt_identity.as_ref(),
+                self.contents,
+            )?
+            .as_vec()
+        };
+
+        match (decrypt(), read(source)) {
+            (Err(err), _) => Check::Corrupt(err.to_string()),
+            (Ok(decrypted), Ok(expected)) if decrypted == expected => Check::Fine,
+            (Ok(_), _) => Check::Differing,
+        }
+    }
+
+    /// # Parameters
+    ///
+    /// 1. `source`: the file or directory that was synced
+    /// 1. `out_dir`: the directory in which the encrypted tree is stored
+    pub fn new(source: &Path, out_dir: &Path) -> Result<Self, Error> {
+        let source = canonicalize(source)?;
+        if source.file_name().is_none() {
+            return Err(err!("cannot verify `{:?}`, as it has no basename", source));
+        }
+
+        let out_dir = canonicalize(out_dir)?;
+        if !out_dir.is_dir() {
+            return Err(err!("`{:?}` is not a directory", out_dir));
+        }
+
+        Ok(Self {
+            contents: ContentCipher::default(),
+            has_integrity: true,
+            layout: Layout::Tree,
+            names: NameCipher::default(),
+            opt_identity: None,
+            out_dir,
+            source,
+        })
+    }
+
+    /// Report a missing integrity manifest as tampering if the config of the tree says that it
+    /// `has_integrity`, rather than take it for a tree that was last synced before integrity
+    /// manifests existed; `true` unless set otherwise.
+    pub fn with_integrity(mut self, has_integrity: bool) -> Self {
+        self.has_integrity = has_integrity;
+        self
+    }
+
+    /// Find what is where as `layout` says; `Layout::Tree` unless set otherwise.
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Decrypt basenames as `names` says; `NameCipher::AesSiv` unless set otherwise.
+    pub fn with_name_cipher(mut self, names: NameCipher) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Decrypt the content of files as `contents` says; `ContentCipher::Sealed` unless set
+    /// otherwise.
+    pub fn with_content_cipher(mut self, contents: ContentCipher) -> Self {
+        self.contents = contents;
+        self
+    }
+
+    /// Decrypt the content of files that were encrypted to public keys with `identity`, the
+    /// secret key of one of them.
+    pub fn with_identity(mut self, identity: [u8; X25519_KEY_LEN]) -> Self {
+        self.opt_identity = Some(identity);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::test_util::*;
+    use std::fs::write;
+
+    const FILES: [(&str, Option<&str>); 5] = [
+        ("missing", Some("missing")),
+        ("extra", Some("extra")),
+        ("corrupt", Some("corrupt")),
+        ("differing", Some("differing")),
+        ("dir/fine", Some("fine")),
+    ];
+
+    #[test]
+    fn verify_finds_every_kind_of_problem() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&FILES)?;
+        let out_dir = synced(&source, &keys)?;
+
+        let verifier = CryptVerifier::new(&source, out_dir.path())?;
+        let report = verifier.verify(&keys)?;
+        assert_eq!(0, report.num_problems());
+        assert_eq!(5, report.num_checked);
+        assert!(report.opt_root.is_some());
+
+        let target = |rel_path: &str| target(out_dir.path(), rel_path, &keys);
+        remove_path(&target("missing"))?;
+        remove_path(&source.join("extra"))?;
+        write(target("corrupt"), "not a ciphertext")?;
+        write(source.join("differing"), "changed")?;
+
+        let report = verifier.verify(&keys)?;
+        assert_eq!(vec![PathBuf::from("source/missing")], report.missing);
+        assert_eq!(
+            vec![(
+                Some(PathBuf::from("source/extra")),
+                target("extra")
+                    .strip_prefix(out_dir.path())
+                    .unwrap()
+                    .to_path_buf()
+            )],
+            report.extra
+        );
+        assert_eq!(1, report.corrupt.len());
+        assert_eq!(PathBuf::from("source/corrupt"), report.corrupt[0].0);
+        assert_eq!(vec![PathBuf::from("source/differing")], report.differing);
+        let rel_target = |basename: &str| -> PathBuf {
+            target(basename)
+                .strip_prefix(out_dir.path())
+                .unwrap()
+                .to_path_buf()
+        };
+        let mut tampered = vec![
+            (rel_target("missing"), Tampering::Missing),
+            (rel_target("corrupt"), Tampering::Modified),
+        ];
+        tampered.sort();
+        assert_eq!(tampered, report.tampered);
+        assert_eq!(6, report.num_problems());
+        Ok(())
+    }
+
+    #[test]
+    fn verify_buckets() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let (_src_dir, source) = test_source(&FILES)?;
+        let out_dir = mktemp_dir("", "", None)?;
+        CryptSyncer::new(&source, out_dir.path())?
+            .with_layout(Layout::Buckets)
+            .sync(&keys)?;
+
+        let verifier = CryptVerifier::new(&source, out_dir.path())?.with_layout(Layout::Buckets);
+        let report = verifier.verify(&keys)?;
+        assert_eq!(0, report.num_problems());
+        assert_eq!(5, report.num_checked);
+
+        let index = Index::load(out_dir.path(), &keys)?;
+        let target = |basename: &str| -> PathBuf {
+            let entry = &index.entries[&Path::new("source").join(basename)];
+            out_dir.path().join(entry.target().unwrap())
+        };
+        remove_path(&target("missing"))?;
+        remove_path(&source.join("extra"))?;
+        write(target("corrupt"), "not a ciphertext")?;
+        write(source.join("differing"), "changed")?;
+        write(out_dir.path().join("stray"), "stray")?;
+
+        let report = verifier.verify(&keys)?;
+        assert_eq!(vec![PathBuf::from("source/missing")], report.missing);
+        assert_eq!(2, report.extra.len());
+        assert!(report.extra.contains(&(None, PathBuf::from("stray"))));
+        assert_eq!(Some(PathBuf::from("source/extra")), report.extra[1].0);
+        assert_eq!(PathBuf::from("source/corrupt"), report.corrupt[0].0);
+        assert_eq!(vec![PathBuf::from("source/differing")], report.differing);
+        assert_eq!(3, report.tampered.len());
+        Ok(())
+    }
+}
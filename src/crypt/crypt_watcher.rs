@@ -0,0 +1,311 @@
+use inotify::EventMask;
+use inotify::Inotify;
+use inotify::WatchDescriptor;
+use inotify::WatchMask;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::canonicalize;
+use std::io::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::crypt::crypt_syncer::*;
+use crate::crypt::key_schedule::*;
+use crate::util::*;
+
+// a burst of events is considered over once no event arrives for this long
+const DEBOUNCE_QUIET_PERIOD: Duration = Duration::from_millis(500);
+
+// never hold off syncing for longer than this, even if events keep arriving
+const DEBOUNCE_MAX_DELAY: Duration = Duration::from_secs(10);
+
+// large enough for several events, each of which is at most `sizeof(inotify_event) + NAME_MAX + 1`
+const EVENT_BUFFER_SIZE: usize = 1 << 14;
+
+/// Watches `source` recursively with inotify, and resyncs whatever changed in it.
+pub struct CryptWatcher {
+    inotify: Inotify,
+    source: PathBuf, // canonicalized, just like in `CryptSyncer`
+    watches: HashMap<WatchDescriptor, PathBuf>, // the directory each watch is on
+}
+
+impl CryptWatcher {
+    /// Sync `source` once, then keep resyncing the paths that change in it, one burst of changes
+    /// at a time; only returns if watching itself fails, e.g. because `source` was removed.
+    ///
+    /// Failing to sync does not stop the watch, as the next change may well fix it; every failure
+    /// is reported to stderr instead.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `syncer`: syncer for the same `source`
+    /// 1. `keys`: the keys of the tree
+    pub fn watch(&mut self, syncer: &CryptSyncer, keys: &Keys) -> Result<(), Error> {
+        if let Err(err) = syncer.sync(keys) {
+            eprintln!("{}", err);
+        }
+
+        loop {
+            let changed = self.next_burst()?;
+            if let Err(err) = syncer.sync_paths(&changed, keys) {
+                eprintln!("{}", err);
+            }
+        }
+    }
+
+    /// Block until something in `source` changes, then keep collecting changes until they stop
+    /// coming in, so that e.g. an editor saving a file or a `git checkout` results in one resync
+    /// instead of hundreds.
+    ///
+    /// # Returns
+    ///
+    /// The paths that changed; `source` itself if the kernel dropped events, as then there is no
+    /// telling what changed.
+    pub fn next_burst(&mut self) -> Result<HashSet<PathBuf>, Error> {
+        let mut buffer = [0u8; EVENT_BUFFER_SIZE];
+        let mut changed = HashSet::new();
+
+        // nothing to debounce until the first relevant event arrives
+        let mut overflowed = false;
+        while changed.is_empty() && !overflowed {
+            overflowed = self.collect_events(&mut buffer, &mut changed, true)?;
+        }
+
+        let start = Instant::now();
+        let mut last_event = start;
+        while !overflowed
+            && last_event.elapsed() < DEBOUNCE_QUIET_PERIOD
+            && start.elapsed() < DEBOUNCE_MAX_DELAY
+        {
+            sleep(DEBOUNCE_QUIET_PERIOD / 5);
+            let num_changed = changed.len();
+            overflowed = self.collect_events(&mut buffer, &mut changed, false)?;
+            if changed.len() != num_changed {
+                last_event = Instant::now();
+            }
+        }
+
+        if overflowed {
+            // resync everything, and start watching from scratch as watches may be stale
+            self.watches
+                .drain()
+                .collect::<Vec<_>>()
+                .into_iter()
+                .for_each(|(wd, _)| drop(self.inotify.rm_watch(wd)));
+            self.watch_source()?;
+            changed = [self.source.clone()].iter().cloned().collect();
+        }
+
+        Ok(changed)
+    }
+
+    /// Read the pending events, and add the paths they are about to `changed`, watching new
+    /// directories along the way.
+    ///
+    /// # Returns
+    ///
+    /// Whether the event queue overflowed.
+    fn collect_events(
+        &mut self,
+        buffer: &mut [u8],
+        changed: &mut HashSet<PathBuf>,
+        blocking: bool,
+    ) -> Result<bool, Error> {
+        let events: Vec<_> = match blocking {
+            true => self.inotify.read_events_blocking(buffer)?,
+            false => self.inotify.read_events(buffer)?,
+        }
+        .map(|event| (event.wd, event.mask, event.name.map(PathBuf::from)))
+        .collect();
+
+        let mut overflowed = false;
+        for (wd, mask, opt_name) in events {
+            if mask.contains(EventMask::Q_OVERFLOW) {
+                overflowed = true;
+                continue;
+            }
+
+            let dir = match self.watches.get(&wd) {
+                Some(dir) => dir.clone(),
+                None => continue, // a stale event for a watch that is gone
+            };
+            if mask.contains(EventMask::IGNORED) {
+                self.watches.remove(&wd);
+                continue;
+            }
+            if dir == self.source && mask.intersects(EventMask::DELETE_SELF | EventMask::MOVE_SELF)
+            {
+                return Err(err!("`{:?}` was removed or moved away", self.source));
+            }
+
+            let path = match opt_name {
+                Some(name) => dir.join(name),
+                None => continue, // about the watched dir itself; its parent reports it too
+            };
+            if !path.starts_with(&self.source) {
+                continue; // a sibling of `source`, if `source` is a file
+            }
+
+            if mask.contains(EventMask::ISDIR) {
+                if mask.intersects(EventMask::MOVED_FROM | EventMask::DELETE) {
+                    self.remove_watches(&path);
+                }
+                if mask.intersects(EventMask::MOVED_TO | EventMask::CREATE) {
+                    // something may have been put in it before the watch was added, but that is
+                    // covered too, as the whole dir is resynced
+                    self.add_watches(&path)?;
+                }
+            }
+            changed.insert(path);
+        }
+
+        Ok(overflowed)
+    }
+
+    /// Watch `root` and every directory in it.
+    fn add_watches(&mut self, root: &Path) -> Result<(), Error> {
+        let mask = WatchMask::CLOSE_WRITE
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::DELETE_SELF
+            | WatchMask::MODIFY
+            | WatchMask::MOVE_SELF
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::ONLYDIR
+            | WatchMask::DONT_FOLLOW;
+
+        // a dir may be gone again by the time it is watched, which is fine
+        let dirs: Vec<PathBuf> = find(root)
+            .filter_map(Result::ok)
+            .filter(|path| path.is_dir())
+            .collect();
+        for dir in dirs {
+            match self.inotify.add_watch(&dir, mask) {
+                Ok(wd) => {
+                    self.watches.insert(wd, dir);
+                }
+                Err(err) if dir.exists() => return Err(err),
+                Err(_) => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stop watching `root` and every directory in it, e.g. because it was moved elsewhere, which
+    /// would make the paths of their watches wrong.
+    fn remove_watches(&mut self, root: &Path) {
+        let stale: Vec<WatchDescriptor> = self
+            .watches
+            .iter()
+            .filter(|(_, dir)| dir.starts_with(root))
+            .map(|(wd, _)| wd.clone())
+            .collect();
+
+        stale.into_iter().for_each(|wd| {
+            self.watches.remove(&wd);
+            // fails if the kernel already removed it, e.g. because the dir was deleted
+            drop(self.inotify.rm_watch(wd));
+        });
+    }
+
+    /// Watch `source` recursively if it is a directory, or its parent if it is a file, as editors
+    /// tend to replace files rather than write to them.
+    fn watch_source(&mut self) -> Result<(), Error> {
+        match self.source.is_dir() {
+            true => self.add_watches(&self.source.clone()),
+            false => {
+                let parent = self
+                    .source
+                    .parent()
+                    .ok_or(err!("`{:?}` has no parent", self.source))?
+                    .to_path_buf();
+                let wd = self.inotify.add_watch(
+                    &parent,
+                    WatchMask::CLOSE_WRITE
+                        | WatchMask::CREATE
+                        | WatchMask::DELETE
+                        | WatchMask::MODIFY
+                        | WatchMask::MOVED_FROM
+                        | WatchMask::MOVED_TO,
+                )?;
+                self.watches.insert(wd, parent);
+                Ok(())
+            }
+        }
+    }
+
+    /// # Parameters
+    ///
+    /// 1. `source`: the file or directory to watch
+    pub fn new(source: &Path) -> Result<Self, Error> {
+        let mut watcher = Self {
+            inotify: Inotify::init()?,
+            source: canonicalize(source)?,
+            watches: HashMap::new(),
+        };
+
+        watcher.watch_source()?;
+        Ok(watcher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::create_dir_all;
+    use std::fs::rename;
+    use std::fs::write;
+
+    #[test]
+    fn next_burst_collects_changes() -> Result<(), Error> {
+        let src_dir = mktemp_dir("", "", None)?;
+        let source = canonicalize(src_dir.path())?.join("source");
+        create_dir_all(source.join("dir"))?;
+        write(source.join("dir/file"), "file")?;
+        write(source.join("removed"), "removed")?;
+
+        let mut watcher = CryptWatcher::new(&source)?;
+        write(source.join("added"), "added")?;
+        remove_path(&source.join("removed"))?;
+        rename(source.join("dir"), source.join("renamed"))?;
+
+        let expected: HashSet<_> = ["added", "removed", "dir", "renamed"]
+            .iter()
+            .map(|basename| source.join(basename))
+            .collect();
+        assert_eq!(expected, watcher.next_burst()?);
+
+        // the renamed dir is watched under its new name
+        write(source.join("renamed/new_file"), "new")?;
+        create_dir_all(source.join("renamed/new_dir"))?;
+        write(source.join("renamed/new_dir/nested"), "nested")?;
+
+        let burst = watcher.next_burst()?;
+        assert!(burst.contains(&source.join("renamed/new_file")));
+        assert!(burst.contains(&source.join("renamed/new_dir")));
+        assert!(burst
+            .iter()
+            .all(|path| path.starts_with(source.join("renamed"))));
+        Ok(())
+    }
+
+    #[test]
+    fn file_source_ignores_siblings() -> Result<(), Error> {
+        let src_dir = mktemp_dir("", "", None)?;
+        let source = canonicalize(src_dir.path())?.join("source");
+        write(&source, "before")?;
+
+        let mut watcher = CryptWatcher::new(&source)?;
+        write(src_dir.path().join("sibling"), "sibling")?;
+        write(&source, "after")?;
+
+        let expected: HashSet<_> = [source.clone()].iter().cloned().collect();
+        assert_eq!(expected, watcher.next_burst()?);
+        Ok(())
+    }
+}
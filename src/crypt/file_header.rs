@@ -0,0 +1,648 @@
+use openssl::rand::rand_bytes;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::Cursor;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+
+use crate::crypt::crypt_encoder::*;
+use crate::encoder::cryptor::*;
+use crate::encoder::padder::*;
+use crate::encoder::zstd_decoder::*;
+use crate::encoder::zstd_encoder::*;
+use crate::util::*;
+
+/// The first bytes of every encrypted file.
+pub const MAGIC: [u8; 4] = *b"CSYN";
+
+/// The version of the format described by `FileHeader`, bumped on every incompatible change.
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Length of a serialized `FileHeader`.
+pub const HEADER_LEN: usize = MAGIC.len() + 4 + NONCE_LEN;
+
+/// The cipher the content of a file is encrypted with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CipherId {
+    Aes256Gcm = 1,             // under `key_hash`, see `Encryptor`
+    X25519Aes256Gcm = 2,       // to public keys, see `RecipientEncryptor`
+    Aes256GcmStream = 3,       // under `key_hash`, in segments, see `StreamEncryptor`
+    X25519Aes256GcmStream = 4, // to public keys, in segments
+}
+
+impl CipherId {
+    /// Whether the content is encrypted in segments, which can each be checked as soon as they
+    /// are read, rather than only once the whole file has been.
+    pub fn is_segmented(self) -> bool {
+        matches!(
+            self,
+            CipherId::Aes256GcmStream | CipherId::X25519Aes256GcmStream
+        )
+    }
+}
+
+/// The compression the content of a file went through before it was encrypted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompressionId {
+    None = 0,
+    Zstd = 1,
+}
+
+/// How the content of files is encrypted; every file in an encrypted tree must be encrypted the
+/// same way, as only `ContentCipher::Sealed` says how in the ciphertext itself, which is why this
+/// is kept in the config of the tree.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContentCipher {
+    // compressed with zstd, then encrypted with `CfbEncryptor` under a fixed IV, with no header,
+    // which is neither randomized nor authenticated; only for trees that predate configs
+    AesCfb,
+    // with `seal`, header first
+    #[default]
+    Sealed,
+}
+
+/// What is at the start of every encrypted file, so that decrypting it never depends on guessing
+/// how it was made, and the format can change without breaking older encrypted trees.
+///
+/// Laid out as `magic || version || cipher || compression || flags || nonce`, and authenticated
+/// along with the ciphertext that follows it, so it cannot be tampered with either.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileHeader {
+    pub version: u8,
+    pub cipher: CipherId,
+    pub compression: CompressionId,
+    pub flags: u8, // none are defined yet
+    pub nonce: [u8; NONCE_LEN],
+}
+
+impl FileHeader {
+    /// A header of the current version, with a fresh random nonce.
+    pub fn new(cipher: CipherId, compression: CompressionId) -> Result<Self, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce).map_err(io_err)?;
+        Ok(Self {
+            version: FORMAT_VERSION,
+            cipher,
+            compression,
+            flags: 0,
+            nonce,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&[
+            self.version,
+            self.cipher as u8,
+            self.compression as u8,
+            self.flags,
+        ]);
+        bytes.extend_from_slice(&self.nonce);
+        bytes
+    }
+
+    /// Read a header from the start of `source`, leaving `source` right after it.
+    ///
+    /// # Returns
+    ///
+    /// The header, or an `ErrorKind::InvalidData` error if `source` does not start with a header
+    /// that this version of csync understands.
+    pub fn read_from<R>(source: &mut R) -> Result<Self, Error>
+    where
+        R: Read,
+    {
+        let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+
+        let mut bytes = [0u8; HEADER_LEN];
+        source.read_exact(&mut bytes).map_err(|_| {
+            invalid(String::from(
+                "not encrypted by csync; the header is missing",
+            ))
+        })?;
+        if bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid(String::from(
+                "not encrypted by csync; bad magic bytes",
+            )));
+        }
+
+        let fields = &bytes[MAGIC.len()..MAGIC.len() + 4];
+        let (version, cipher, compression, flags) = (fields[0], fields[1], fields[2], fields[3]);
+        if version != FORMAT_VERSION {
+            return Err(invalid(format!("unsupported format version {}", version)));
+        }
+        let cipher = match cipher {
+            1 => CipherId::Aes256Gcm,
+            2 => CipherId::X25519Aes256Gcm,
+            3 => CipherId::Aes256GcmStream,
+            4 => CipherId::X25519Aes256GcmStream,
+            _ => return Err(invalid(format!("unsupported cipher {}", cipher))),
+        };
+        let compression = match compression {
+            0 => CompressionId::None,
+            1 => CompressionId::Zstd,
+            _ => return Err(invalid(format!("unsupported compression {}", compression))),
+        };
+        if flags != 0 {
+            return Err(invalid(format!("unsupported flags {:#04x}", flags)));
+        }
+
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&bytes[HEADER_LEN - NONCE_LEN..]);
+        Ok(Self {
+            version,
+            cipher,
+            compression,
+            flags,
+            nonce,
+        })
+    }
+}
+
+// so that what `seal` and `open_decrypted` return can be used like any other encoder
+impl<'a> CryptEncoder<Box<dyn Read + 'a>> for Box<dyn Read + 'a> {}
+
+/// Compress and encrypt `source`, with a header that says how.
+///
+/// # Parameters
+///
+/// 1. `source`: the plaintext
+/// 1. `key_hash`: hash of the key to use, for symmetric encryption
+/// 1. `recipients`: the public keys to encrypt to instead of `key_hash`, if any
+/// 1. `compression`: how to compress `source` before encrypting it
+/// 1. `padding`: how to pad the compressed `source` before encrypting it, which is done with zstd
+///    skippable frames, so anything but `Padding::None` needs `CompressionId::Zstd`
+///
+/// # Returns
+///
+/// The ciphertext, header first; the inverse of `open_decrypted`.
+pub fn seal<'a, R>(
+    source: R,
+    key_hash: &[u8],
+    recipients: &[[u8; X25519_KEY_LEN]],
+    compression: CompressionId,
+    padding: Padding,
+) -> Result<Box<dyn Read + 'a>, Error>
+where
+    R: Read + 'a,
+{
+    let cipher = match recipients.is_empty() {
+        true => CipherId::Aes256GcmStream,
+        false => CipherId::X25519Aes256GcmStream,
+    };
+    let header = FileHeader::new(cipher, compression)?;
+    let compressed: Box<dyn Read + 'a> = match (compression, padding) {
+        (CompressionId::None, Padding::None) => Box::new(source),
+        (CompressionId::None, _) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "only zstd compressed content can be padded",
+            ))
+        }
+        (CompressionId::Zstd, Padding::None) => Box::new(ZstdEncoder::new(source, None)?),
+        (CompressionId::Zstd, padding) => {
+            Box::new(Padder::new(ZstdEncoder::new(source, None)?, padding)?)
+        }
+    };
+    encrypt(&header, compressed, key_hash, recipients)
+}
+
+/// Read the header at the start of `source`, then decrypt and decompress the rest accordingly,
+/// which drops whatever padding there is along the way.
+///
+/// Just like with `Decryptor`, the plaintext must not be trusted until it has been read to the
+/// end without errors.
+///
+/// # Parameters
+///
+/// 1. `source`: a ciphertext made by `seal`
+/// 1. `key_hash`: hash of the key that was used to encrypt it
+/// 1. `opt_identity`: the secret key of one of the recipients, if it was encrypted to public keys
+pub fn open_decrypted<'a, R>(
+    mut source: R,
+    key_hash: &[u8],
+    opt_identity: Option<&[u8; X25519_KEY_LEN]>,
+) -> Result<Box<dyn Read + 'a>, Error>
+where
+    R: Read + 'a,
+{
+    let header = FileHeader::read_from(&mut source)?;
+    let decrypted = decrypt(&header, source, key_hash, opt_identity)?;
+
+    Ok(match header.compression {
+        CompressionId::None => Box::new(decrypted),
+        CompressionId::Zstd => Box::new(ZstdDecoder::new(decrypted, None)?),
+    })
+}
+
+/// Compress and encrypt the content of a file as `contents` says, which is `seal` with
+/// `CompressionId::Zstd` for anything but `ContentCipher::AesCfb`.
+///
+/// # Parameters
+///
+/// 1. `source`: the content of the file
+/// 1. `key_hash`: hash of the key to use, for symmetric encryption
+/// 1. `recipients`: the public keys to encrypt to instead of `key_hash`, if any
+/// 1. `padding`: how to pad the compressed `source` before encrypting it
+/// 1. `contents`: how the content of files in the tree is encrypted
+///
+/// # Returns
+///
+/// The ciphertext; the inverse of `open_content`. With `ContentCipher::AesCfb`, an
+/// `ErrorKind::InvalidInput` error if there are `recipients` or `padding`, which need a header.
+pub fn seal_content<'a, R>(
+    source: R,
+    key_hash: &[u8],
+    recipients: &[[u8; X25519_KEY_LEN]],
+    padding: Padding,
+    contents: ContentCipher,
+) -> Result<Box<dyn Read + 'a>, Error>
+where
+    R: Read + 'a,
+{
+    match contents {
+        ContentCipher::Sealed => seal(source, key_hash, recipients, CompressionId::Zstd, padding),
+        ContentCipher::AesCfb if recipients.is_empty() && padding == Padding::None => Ok(Box::new(
+            CfbEncryptor::new(ZstdEncoder::new(source, None)?, key_hash)?,
+        )),
+        ContentCipher::AesCfb => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "trees that predate configs can neither be padded nor encrypted to public keys; run \
+             `csync rekey` first",
+        )),
+    }
+}
+
+/// Decrypt and decompress the content of a file as `contents` says; the inverse of
+/// `seal_content`.
+///
+/// With `ContentCipher::AesCfb`, nothing is authenticated, so decrypting with the wrong key, or a
+/// tampered ciphertext, produces garbage, or fails to decompress, rather than fail to decrypt.
+///
+/// # Parameters
+///
+/// 1. `source`: a ciphertext made by `seal_content`
+/// 1. `key_hash`: hash of the key that was used to encrypt it
+/// 1. `opt_identity`: the secret key of one of the recipients, if it was encrypted to public keys
+/// 1. `contents`: how the content of files in the tree is encrypted
+pub fn open_content<'a, R>(
+    source: R,
+    key_hash: &[u8],
+    opt_identity: Option<&[u8; X25519_KEY_LEN]>,
+    contents: ContentCipher,
+) -> Result<Box<dyn Read + 'a>, Error>
+where
+    R: Read + 'a,
+{
+    match contents {
+        ContentCipher::Sealed => open_decrypted(source, key_hash, opt_identity),
+        ContentCipher::AesCfb => Ok(Box::new(ZstdDecoder::new(
+            CfbDecryptor::new(source, key_hash)?,
+            None,
+        )?)),
+    }
+}
+
+/// Decrypt a ciphertext made by `seal_content` and encrypt it again under `new_key_hash`, with
+/// `seal`, without decompressing it, e.g. to rotate the key of an encrypted tree; the plaintext
+/// only ever exists in memory, one buffer at a time.
+///
+/// Ciphertexts that were encrypted to public keys do not depend on `key_hash`, so they are passed
+/// through as they are.
+///
+/// # Parameters
+///
+/// 1. `source`: a ciphertext made by `seal_content`
+/// 1. `key_hash`: hash of the key that was used to encrypt it
+/// 1. `new_key_hash`: hash of the key to encrypt it under instead
+/// 1. `contents`: how `source` was encrypted
+pub fn reseal<'a, R>(
+    mut source: R,
+    key_hash: &[u8],
+    new_key_hash: &[u8],
+    contents: ContentCipher,
+) -> Result<Box<dyn Read + 'a>, Error>
+where
+    R: Read + 'a,
+{
+    if contents == ContentCipher::AesCfb {
+        let new_header = FileHeader::new(CipherId::Aes256GcmStream, CompressionId::Zstd)?;
+        let decrypted = Box::new(CfbDecryptor::new(source, key_hash)?);
+        return encrypt(&new_header, decrypted, new_key_hash, &[]);
+    }
+
+    let header = FileHeader::read_from(&mut source)?;
+    match header.cipher {
+        CipherId::Aes256Gcm | CipherId::Aes256GcmStream => {
+            let new_header = FileHeader::new(CipherId::Aes256GcmStream, header.compression)?;
+            let decrypted = decrypt(&header, source, key_hash, None)?;
+            encrypt(&new_header, decrypted, new_key_hash, &[])
+        }
+        CipherId::X25519Aes256Gcm | CipherId::X25519Aes256GcmStream => {
+            Ok(Box::new(Cursor::new(header.to_bytes()).chain(source)))
+        }
+    }
+}
+
+/// Encrypt `source` as `header` says, with `header` in front of it.
+fn encrypt<'a>(
+    header: &FileHeader,
+    source: Box<dyn Read + 'a>,
+    key_hash: &[u8],
+    recipients: &[[u8; X25519_KEY_LEN]],
+) -> Result<Box<dyn Read + 'a>, Error> {
+    let (prefix, nonce) = (header.to_bytes(), &header.nonce);
+    Ok(match header.cipher {
+        CipherId::Aes256Gcm => Box::new(Encryptor::with_prefix(source, key_hash, nonce, &prefix)?),
+        CipherId::Aes256GcmStream => Box::new(StreamEncryptor::with_prefix(
+            source, key_hash, nonce, &prefix,
+        )?),
+        cipher => Box::new(RecipientEncryptor::with_prefix(
+            source,
+            recipients,
+            nonce,
+            &prefix,
+            cipher.is_segmented(),
+        )?),
+    })
+}
+
+/// Decrypt what follows `header` in `source`, as `header` says.
+fn decrypt<'a, R>(
+    header: &FileHeader,
+    source: R,
+    key_hash: &[u8],
+    opt_identity: Option<&[u8; X25519_KEY_LEN]>,
+) -> Result<Box<dyn Read + 'a>, Error>
+where
+    R: Read + 'a,
+{
+    let (prefix, nonce) = (header.to_bytes(), &header.nonce);
+    Ok(match (header.cipher, opt_identity) {
+        (CipherId::Aes256Gcm, _) => {
+            Box::new(Decryptor::with_prefix(source, key_hash, nonce, &prefix)?)
+        }
+        (CipherId::Aes256GcmStream, _) => Box::new(StreamDecryptor::with_prefix(
+            source, key_hash, nonce, &prefix,
+        )?),
+        (cipher, Some(identity)) => Box::new(RecipientDecryptor::with_prefix(
+            source,
+            identity,
+            nonce,
+            &prefix,
+            cipher.is_segmented(),
+        )?),
+        (_, None) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "encrypted to public keys, so an identity is needed to decrypt it",
+            ))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::test_util::*;
+    use crate::hasher::*;
+
+    fn read_all(mut source: Box<dyn Read + '_>) -> Result<Vec<u8>, Error> {
+        source.as_vec()
+    }
+
+    #[test]
+    fn seal_then_open() -> Result<(), Error> {
+        let key_hash = test_key_hash();
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 7) as u8).collect();
+
+        for &compression in [CompressionId::None, CompressionId::Zstd].iter() {
+            let ciphertext =
+                read_all(seal(&data[..], &key_hash, &[], compression, Padding::None)?)?;
+            let header = FileHeader::read_from(&mut &ciphertext[..])?;
+            assert_eq!(compression, header.compression);
+            assert_eq!(FORMAT_VERSION, header.version);
+
+            assert_eq!(
+                data,
+                read_all(open_decrypted(&ciphertext[..], &key_hash, None)?)?
+            );
+        }
+
+        // files sealed before segments existed still open
+        let header = FileHeader::new(CipherId::Aes256Gcm, CompressionId::None)?;
+        let ciphertext =
+            Encryptor::with_prefix(&data[..], &key_hash, &header.nonce, &header.to_bytes())?
+                .as_vec()?;
+        assert_eq!(
+            data,
+            read_all(open_decrypted(&ciphertext[..], &key_hash, None)?)?
+        );
+
+        // the identity, rather than the key, decrypts what was encrypted to its public key
+        let (identity, recipient) = generate_x25519()?;
+        let ciphertext = read_all(seal(
+            &data[..],
+            &key_hash,
+            &[recipient],
+            CompressionId::Zstd,
+            Padding::None,
+        )?)?;
+        let header = FileHeader::read_from(&mut &ciphertext[..])?;
+        assert_eq!(CipherId::X25519Aes256GcmStream, header.cipher);
+        assert_eq!(
+            data,
+            read_all(open_decrypted(
+                &ciphertext[..],
+                &[0u8; 64],
+                Some(&identity)
+            )?)?
+        );
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            open_decrypted(&ciphertext[..], &key_hash, None)
+                .err()
+                .unwrap()
+                .kind()
+        );
+
+        // only the new key opens what was resealed, and recipient ciphertexts stay as they are
+        let new_key_hash = hash_custom("aoisjfk2".as_bytes(), None, Some(1 << 8));
+        let resealed = read_all(reseal(
+            &ciphertext[..],
+            &key_hash,
+            &new_key_hash,
+            ContentCipher::Sealed,
+        )?)?;
+        assert_eq!(ciphertext, resealed);
+        for &compression in [CompressionId::None, CompressionId::Zstd].iter() {
+            let ciphertext =
+                read_all(seal(&data[..], &key_hash, &[], compression, Padding::None)?)?;
+            let resealed = read_all(reseal(
+                &ciphertext[..],
+                &key_hash,
+                &new_key_hash,
+                ContentCipher::Sealed,
+            )?)?;
+            assert_eq!(
+                compression,
+                FileHeader::read_from(&mut &resealed[..])?.compression
+            );
+            assert_eq!(
+                data,
+                read_all(open_decrypted(&resealed[..], &new_key_hash, None)?)?
+            );
+            assert!(read_all(open_decrypted(&resealed[..], &key_hash, None)?).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn padding_hides_exact_sizes() -> Result<(), Error> {
+        let key_hash = test_key_hash();
+        let seal_padded = |data: &[u8], padding: Padding| -> Result<Vec<u8>, Error> {
+            read_all(seal(data, &key_hash, &[], CompressionId::Zstd, padding)?)
+        };
+
+        // random bytes hardly compress, so both end up in the same bucket
+        let (short, long) = (drng(1000), drng(1010));
+        for &padding in [Padding::Padme, Padding::PowerOfTwo].iter() {
+            let ciphertexts = [seal_padded(&short, padding)?, seal_padded(&long, padding)?];
+            assert_eq!(ciphertexts[0].len(), ciphertexts[1].len());
+            for (data, ciphertext) in [&short, &long].iter().zip(ciphertexts.iter()) {
+                assert_eq!(
+                    **data,
+                    read_all(open_decrypted(&ciphertext[..], &key_hash, None)?)?
+                );
+            }
+        }
+        assert_ne!(
+            seal_padded(&short, Padding::None)?.len(),
+            seal_padded(&long, Padding::None)?.len()
+        );
+
+        // the padding lives in zstd frames
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            seal(
+                &short[..],
+                &key_hash,
+                &[],
+                CompressionId::None,
+                Padding::Padme
+            )
+            .err()
+            .unwrap()
+            .kind()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_contents_open() -> Result<(), Error> {
+        let key_hash = test_key_hash();
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 7) as u8).collect();
+
+        // just like trees that predate configs were encrypted
+        let ciphertext = read_all(seal_content(
+            &data[..],
+            &key_hash,
+            &[],
+            Padding::None,
+            ContentCipher::AesCfb,
+        )?)?;
+        assert_eq!(
+            CfbEncryptor::new(ZstdEncoder::new(&data[..], None)?, &key_hash)?.as_vec()?,
+            ciphertext
+        );
+        assert_eq!(
+            data,
+            read_all(open_content(
+                &ciphertext[..],
+                &key_hash,
+                None,
+                ContentCipher::AesCfb
+            )?)?
+        );
+
+        // which is how they stay until they are resealed
+        let new_key_hash = hash_custom("aoisjfk2".as_bytes(), None, Some(1 << 8));
+        let resealed = read_all(reseal(
+            &ciphertext[..],
+            &key_hash,
+            &new_key_hash,
+            ContentCipher::AesCfb,
+        )?)?;
+        assert_eq!(
+            data,
+            read_all(open_decrypted(&resealed[..], &new_key_hash, None)?)?
+        );
+
+        let (_, recipient) = generate_x25519()?;
+        for (recipients, padding) in
+            [(&[recipient][..], Padding::None), (&[], Padding::Padme)].iter()
+        {
+            assert_eq!(
+                ErrorKind::InvalidInput,
+                seal_content(
+                    &data[..],
+                    &key_hash,
+                    recipients,
+                    *padding,
+                    ContentCipher::AesCfb
+                )
+                .err()
+                .unwrap()
+                .kind()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bad_headers_fail() -> Result<(), Error> {
+        let key_hash = test_key_hash();
+        let ciphertext = read_all(seal(
+            &b"data"[..],
+            &key_hash,
+            &[],
+            CompressionId::Zstd,
+            Padding::None,
+        )?)?;
+
+        let open = |ciphertext: &[u8]| -> Result<Vec<u8>, Error> {
+            read_all(open_decrypted(ciphertext, &key_hash, None)?)
+        };
+        let with = |i: usize, byte: u8| -> Vec<u8> {
+            let mut modified = ciphertext.clone();
+            modified[i] = byte;
+            modified
+        };
+
+        // bad magic, a future version, unknown ids and flags, and a short file
+        let bad = vec![
+            with(0, b'X'),
+            with(MAGIC.len(), FORMAT_VERSION + 1),
+            with(MAGIC.len() + 1, 0xff),
+            with(MAGIC.len() + 2, 0xff),
+            with(MAGIC.len() + 3, 0x01),
+            ciphertext[..HEADER_LEN - 1].to_vec(),
+        ];
+        for ciphertext in bad {
+            assert_eq!(
+                ErrorKind::InvalidData,
+                open(&ciphertext).unwrap_err().kind()
+            );
+        }
+
+        // the header is authenticated, so a valid but different header fails too
+        let mut header = FileHeader::read_from(&mut &ciphertext[..])?;
+        header.compression = CompressionId::None;
+        let mut modified = header.to_bytes();
+        modified.extend_from_slice(&ciphertext[HEADER_LEN..]);
+        assert!(open(&modified).is_err());
+        Ok(())
+    }
+}
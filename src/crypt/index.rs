@@ -0,0 +1,247 @@
+use data_encoding::HEXLOWER;
+use openssl::rand::rand_bytes;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::crypt::crypt_syncer::*;
+use crate::crypt::key_schedule::*;
+use crate::crypt::manifest::*;
+use crate::util::*;
+
+/// Name of the index file in `METADATA_DIR`, which only trees in `Layout::Buckets` have.
+pub const INDEX_FILE: &str = "index";
+
+/// Length of the random id of every file in `Layout::Buckets`.
+pub const FILE_ID_LEN: usize = 16;
+
+/// How an encrypted tree is laid out in its `out_dir`; every sync to a tree must lay it out the
+/// same way, which is why this is kept in the config of the tree.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    // like the source, with every basename encrypted, so that the ciphertext of a path can be
+    // derived from the path alone; the depth and fan-out of the source show through
+    #[default]
+    Tree,
+    // every file under a random id, in one of 65536 buckets two levels deep, with the real tree
+    // only in the encrypted index, so that nothing but the number and sizes of files show through
+    Buckets,
+}
+
+impl Layout {
+    pub const NAMES: [&'static str; 2] = ["tree", "buckets"];
+}
+
+impl FromStr for Layout {
+    type Err = Error;
+
+    fn from_str(layout: &str) -> Result<Self, Error> {
+        match layout {
+            "tree" => Ok(Layout::Tree),
+            "buckets" => Ok(Layout::Buckets),
+            _ => Err(err!(
+                "`{}` is not one of `{}`",
+                layout,
+                Layout::NAMES.join("`, `")
+            )),
+        }
+    }
+}
+
+/// What a single path in the source is, as far as the index knows.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IndexEntry {
+    Dir,
+    File { id: String }, // hex; see `bucket_path`
+}
+
+impl IndexEntry {
+    /// # Returns
+    ///
+    /// The ciphertext of the entry, relative to `out_dir`, or `None` for directories, which only
+    /// exist in the index.
+    pub fn target(&self) -> Option<PathBuf> {
+        match self {
+            IndexEntry::Dir => None,
+            IndexEntry::File { id } => Some(bucket_path(id)),
+        }
+    }
+}
+
+/// The real tree of an encrypted tree in `Layout::Buckets`, stored encrypted in its
+/// `METADATA_DIR`, as nothing in `out_dir` says which ciphertext is which file, or where it is.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Index {
+    // keyed by the plaintext path of every directory and file, relative to the parent of `source`
+    pub entries: BTreeMap<PathBuf, IndexEntry>,
+}
+
+impl Index {
+    /// Load the index of the encrypted tree in `out_dir`, or an empty one if there is none yet.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `out_dir`: the directory in which the encrypted tree is stored
+    /// 1. `keys`: the keys of the tree, of which the index key is used
+    pub fn load(out_dir: &Path, keys: &Keys) -> Result<Self, Error> {
+        let path = index_path(out_dir);
+        match path.exists() {
+            true => load_sealed_json(&path, &keys.index),
+            false => Ok(Self::default()),
+        }
+    }
+
+    /// Store the index encrypted in `out_dir`, replacing the old one atomically, as without it,
+    /// nothing in `out_dir` can be found.
+    pub fn store(&self, out_dir: &Path, keys: &Keys) -> Result<(), Error> {
+        store_sealed_json(self, &index_path(out_dir), &keys.index)
+    }
+
+    /// # Parameters
+    ///
+    /// 1. `opt_plain_root`: plaintext path of the root of a subtree, relative to the root of the
+    ///    tree; `None` for the whole tree
+    ///
+    /// # Returns
+    ///
+    /// The plaintext paths of the directories at or below `opt_plain_root`, and a mapping from the
+    /// ciphertext of each file there, relative to `out_dir`, to its plaintext path; the same as
+    /// `subtree_plaintexts` finds by walking a tree in `Layout::Tree`. An `ErrorKind::NotFound`
+    /// error if there is nothing at `opt_plain_root`.
+    pub fn subtree(
+        &self,
+        opt_plain_root: Option<&Path>,
+    ) -> Result<(Vec<PathBuf>, HashMap<PathBuf, PathBuf>), Error> {
+        if let Some(plain_root) = opt_plain_root {
+            if !self.entries.contains_key(plain_root) {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("`{:?}` is not in the index", plain_root),
+                ));
+            }
+        }
+
+        let mut dirs = Vec::new();
+        let mut enc_to_plain = HashMap::new();
+        self.entries
+            .iter()
+            .filter(|(path, _)| opt_plain_root.is_none_or(|root| path.starts_with(root)))
+            .for_each(|(path, entry)| match entry.target() {
+                Some(target) => {
+                    enc_to_plain.insert(target, path.clone());
+                }
+                None => dirs.push(path.clone()),
+            });
+        Ok((dirs, enc_to_plain))
+    }
+
+    /// # Returns
+    ///
+    /// Every file in `out_dir` outside of `METADATA_DIR` that no entry points to, relative to
+    /// `out_dir`, e.g. what is left of a sync that was interrupted.
+    pub fn unindexed(&self, out_dir: &Path) -> Vec<PathBuf> {
+        if !out_dir.exists() {
+            return Vec::new();
+        }
+
+        let targets: HashSet<PathBuf> = self
+            .entries
+            .values()
+            .filter_map(IndexEntry::target)
+            .collect();
+        let metadata_dir = out_dir.join(METADATA_DIR);
+        find(out_dir)
+            .filter_map(Result::ok)
+            .filter(|path| !path.starts_with(&metadata_dir) && path.is_file())
+            .filter_map(|path| path.strip_prefix(out_dir).map(Path::to_path_buf).ok())
+            .filter(|target| !targets.contains(target))
+            .collect()
+    }
+}
+
+/// # Returns
+///
+/// A fresh random id for a file, i.e. a fresh place for its ciphertext, which says nothing about
+/// the file.
+pub fn new_file_id() -> Result<String, Error> {
+    let mut id = [0u8; FILE_ID_LEN];
+    rand_bytes(&mut id).map_err(io_err)?;
+    Ok(HEXLOWER.encode(&id))
+}
+
+/// # Returns
+///
+/// Where the ciphertext of the file with `id` is, relative to `out_dir`, in the bucket named
+/// after the first two bytes of `id`, e.g. `3f/a0/3fa0...`.
+pub fn bucket_path(id: &str) -> PathBuf {
+    let (first, second) = (id.get(..2).unwrap_or(""), id.get(2..4).unwrap_or(""));
+    Path::new(first).join(second).join(id)
+}
+
+#[inline]
+pub fn index_path(out_dir: &Path) -> PathBuf {
+    out_dir.join(METADATA_DIR).join(INDEX_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::test_util::*;
+    use std::fs::create_dir_all;
+    use std::fs::write;
+
+    #[test]
+    fn store_then_load() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let out_dir = mktemp_dir("", "", None)?;
+
+        let mut index = Index::load(out_dir.path(), &keys)?;
+        assert!(index.entries.is_empty());
+
+        let id = new_file_id()?;
+        assert_eq!(2 * FILE_ID_LEN, id.len());
+        assert_ne!(id, new_file_id()?);
+        index.entries.insert(PathBuf::from("a"), IndexEntry::Dir);
+        index.entries.insert(PathBuf::from("a/b"), IndexEntry::Dir);
+        let file = IndexEntry::File { id: id.clone() };
+        index.entries.insert(PathBuf::from("a/b/c"), file);
+        index.store(out_dir.path(), &keys)?;
+
+        let loaded = Index::load(out_dir.path(), &keys)?;
+        assert_eq!(index.entries, loaded.entries);
+        let (dirs, enc_to_plain) = loaded.subtree(Some(Path::new("a/b")))?;
+        assert_eq!(vec![PathBuf::from("a/b")], dirs);
+        let target = Path::new(&id[..2]).join(&id[2..4]).join(&id);
+        assert_eq!(Some(&PathBuf::from("a/b/c")), enc_to_plain.get(&target));
+        assert_eq!(
+            ErrorKind::NotFound,
+            loaded.subtree(Some(Path::new("a/c"))).unwrap_err().kind()
+        );
+
+        // whatever no entry points to is left over
+        create_dir_all(out_dir.path().join(target.parent().unwrap()))?;
+        write(out_dir.path().join(&target), "c")?;
+        write(out_dir.path().join("stray"), "stray")?;
+        assert_eq!(
+            vec![PathBuf::from("stray")],
+            loaded.unindexed(out_dir.path())
+        );
+
+        // only the index key opens it
+        let wrong_keys = Keys {
+            index: keys.manifest.clone(),
+            ..keys
+        };
+        assert!(Index::load(out_dir.path(), &wrong_keys).is_err());
+        Ok(())
+    }
+}
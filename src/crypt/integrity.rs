@@ -0,0 +1,421 @@
+use data_encoding::HEXLOWER;
+use rayon::prelude::*;
+use ring::digest;
+use ring::hmac;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs::create_dir_all;
+use std::fs::read;
+use std::io::Error;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::crypt::crypt_syncer::*;
+use crate::crypt::index::*;
+use crate::crypt::key_schedule::*;
+use crate::crypt::manifest::*;
+use crate::hasher::*;
+use crate::util::*;
+
+/// Name of the integrity manifest file in `METADATA_DIR`.
+pub const INTEGRITY_FILE: &str = "integrity";
+
+// what the MAC of the root is computed over, along with the root
+const ROOT_MAC_INFO: &[u8] = b"csync integrity root";
+
+// what the MAC of a single leaf is computed over, along with the leaf
+const LEAF_MAC_INFO: &[u8] = b"csync integrity leaf";
+
+// prefixes of what is hashed into leaves and inner nodes, so that neither can pass for the other
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// The content hash of every ciphertext in an encrypted tree, as of the last sync, along with the
+/// root of a Merkle tree over them and a MAC of the root, so that files that were deleted, swapped
+/// or rolled back since can be told apart from what the sync wrote.
+///
+/// It is stored in plaintext in `METADATA_DIR`, as it holds nothing but ciphertext paths and
+/// hashes, so two copies of an encrypted tree can be compared by their roots alone.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct IntegrityManifest {
+    // keyed by the path of each ciphertext, relative to `out_dir`, with the SHA256 of its content,
+    // see `hasher::hash_file`; includes the manifest and the index
+    pub leaves: BTreeMap<PathBuf, String>,
+    root: String, // of the Merkle tree over `leaves`, as of when it was stored
+    mac: String,  // of `root`, under the MAC key
+}
+
+/// How a ciphertext differs from what the integrity manifest says it should be.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Tampering {
+    Missing,  // in the integrity manifest, but not in `out_dir`
+    Modified, // in both, but with another content, e.g. swapped with another or rolled back
+    Unknown,  // in `out_dir`, but not in the integrity manifest
+}
+
+impl Tampering {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tampering::Missing => "missing",
+            Tampering::Modified => "modified",
+            Tampering::Unknown => "unknown",
+        }
+    }
+}
+
+impl IntegrityManifest {
+    /// Load the integrity manifest of the encrypted tree in `out_dir`, and check that its root is
+    /// the root of its leaves, and that its MAC is that of its root.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `out_dir`: the directory in which the encrypted tree is stored
+    /// 1. `keys`: the keys of the tree, of which the MAC key is used
+    ///
+    /// # Returns
+    ///
+    /// `None` if there is no integrity manifest yet, e.g. as the tree was last synced before they
+    /// existed.
+    pub fn load(out_dir: &Path, keys: &Keys) -> Result<Option<Self>, Error> {
+        Self::load_file(&integrity_path(out_dir), keys)
+    }
+
+    /// Load an integrity manifest from `path`, which need not be where that of a tree is kept,
+    /// e.g. that of a rekey in progress; see `load`.
+    pub fn load_file(path: &Path, keys: &Keys) -> Result<Option<Self>, Error> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let tampered = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "`{:?}` does not match its MAC; either the key is wrong, or it was tampered \
+                     with, in which case restore from the tree what `verify` finds intact, and \
+                     sync that to a new directory",
+                    path
+                ),
+            )
+        };
+        let integrity: Self = serde_json::from_slice(&read(path)?).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("failed to parse `{:?}`: {}", path, err),
+            )
+        })?;
+        let mac = HEXLOWER
+            .decode(integrity.mac.as_bytes())
+            .map_err(|_| tampered())?;
+        match integrity.root() == integrity.root {
+            true => hmac::verify(&mac_key(keys), &root_mac_input(&integrity.root), &mac)
+                .map(|_| Some(integrity))
+                .map_err(|_| tampered()),
+            false => Err(tampered()),
+        }
+    }
+
+    /// Like `load`, but for a tree whose config says whether it `has_integrity`, i.e. whether it
+    /// was synced since integrity manifests existed, in which case there has to be one.
+    ///
+    /// # Returns
+    ///
+    /// `None` if there is no integrity manifest, and the tree has none yet; an
+    /// `ErrorKind::InvalidData` error if it was removed, see `integrity_removed`.
+    pub fn load_expected(
+        out_dir: &Path,
+        keys: &Keys,
+        has_integrity: bool,
+    ) -> Result<Option<Self>, Error> {
+        match Self::load(out_dir, keys)? {
+            None if integrity_removed(out_dir, has_integrity)? => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "`{:?}` was removed, though the config says it should be there, so `{:?}` \
+                     was tampered with; see `verify`",
+                    integrity_path(out_dir),
+                    out_dir
+                ),
+            )),
+            opt_integrity => Ok(opt_integrity),
+        }
+    }
+
+    /// Hash every ciphertext that is in `out_dir` now, along with its manifest and index.
+    pub fn scan(out_dir: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            leaves: ciphertext_hashes(out_dir)?,
+            ..Self::default()
+        })
+    }
+
+    /// An integrity manifest of ciphertexts whose hashes are known already, e.g. as they were
+    /// hashed as they were written, rather than of whatever is in some `out_dir` by now.
+    pub fn with_leaves(leaves: BTreeMap<PathBuf, String>) -> Self {
+        Self {
+            leaves,
+            ..Self::default()
+        }
+    }
+
+    /// Store the integrity manifest in `out_dir` along with its root and the MAC of its root,
+    /// replacing the old one atomically.
+    pub fn store(&mut self, out_dir: &Path, keys: &Keys) -> Result<(), Error> {
+        self.store_file(&integrity_path(out_dir), keys)
+    }
+
+    /// Store the integrity manifest at `path`, atomically replacing whatever is there; the
+    /// counterpart of `load_file`.
+    pub fn store_file(&mut self, path: &Path, keys: &Keys) -> Result<(), Error> {
+        self.root = self.root();
+        let tag = hmac::sign(&mac_key(keys), &root_mac_input(&self.root));
+        self.mac = HEXLOWER.encode(tag.as_ref());
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        create_dir_all(dir)?;
+        let json = serde_json::to_vec_pretty(self).map_err(io_err)?;
+        let mut temp_file = mktemp_file(INTEGRITY_FILE, "", Some(dir))?;
+        temp_file.write_all(&json)?;
+        temp_file.persist(path).map(|_| ()).map_err(|err| err.error)
+    }
+
+    /// Forget every ciphertext at or below `target`, relative to `out_dir`.
+    pub fn remove_subtree(&mut self, target: &Path) {
+        self.leaves.retain(|path, _| !path.starts_with(target));
+    }
+
+    /// # Returns
+    ///
+    /// The root of the Merkle tree over the leaves, encoded as lowercase hex; equal for two trees
+    /// if and only if they hold the same ciphertexts at the same paths.
+    pub fn root(&self) -> String {
+        let mut level: Vec<digest::Digest> = self
+            .leaves
+            .iter()
+            .map(|(path, hash)| {
+                let mut context = digest::Context::new(&digest::SHA256);
+                context.update(&[LEAF_PREFIX]);
+                context.update(path.to_string_lossy().as_bytes());
+                context.update(&[0]);
+                context.update(hash.as_bytes());
+                context.finish()
+            })
+            .collect();
+
+        if level.is_empty() {
+            return HEXLOWER.encode(digest::digest(&digest::SHA256, &[]).as_ref());
+        }
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => {
+                        let mut context = digest::Context::new(&digest::SHA256);
+                        context.update(&[NODE_PREFIX]);
+                        context.update(left.as_ref());
+                        context.update(right.as_ref());
+                        context.finish()
+                    }
+                    // the last node of a level with an odd number of them is carried up as it is
+                    _ => pair[0],
+                })
+                .collect();
+        }
+        HEXLOWER.encode(level[0].as_ref())
+    }
+
+    /// Hash every ciphertext in `out_dir`, and compare it with the leaves, without decrypting
+    /// anything.
+    ///
+    /// # Returns
+    ///
+    /// Every ciphertext that is not as the leaves say, relative to `out_dir`, sorted, along with
+    /// how it differs; `out_dir` is as it was when the integrity manifest was stored if and only if
+    /// there are none.
+    pub fn check(&self, out_dir: &Path) -> Result<Vec<(PathBuf, Tampering)>, Error> {
+        let actual = ciphertext_hashes(out_dir)?;
+        let paths: BTreeSet<&PathBuf> = self.leaves.keys().chain(actual.keys()).collect();
+        Ok(paths
+            .into_iter()
+            .filter_map(|path| match (self.leaves.get(path), actual.get(path)) {
+                (Some(_), None) => Some((path.clone(), Tampering::Missing)),
+                (None, Some(_)) => Some((path.clone(), Tampering::Unknown)),
+                (Some(expected), Some(hash)) if expected != hash => {
+                    Some((path.clone(), Tampering::Modified))
+                }
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+#[inline]
+pub fn integrity_path(out_dir: &Path) -> PathBuf {
+    out_dir.join(METADATA_DIR).join(INTEGRITY_FILE)
+}
+
+/// # Returns
+///
+/// Whether the integrity manifest of the tree in `out_dir` was removed, i.e. whether there is
+/// none, though its config says that it `has_integrity`, while there are ciphertexts for one to
+/// vouch for; removing it would otherwise have whatever is there trusted again.
+pub fn integrity_removed(out_dir: &Path, has_integrity: bool) -> Result<bool, Error> {
+    if !has_integrity || integrity_path(out_dir).exists() || !out_dir.exists() {
+        return Ok(false);
+    }
+
+    let metadata_dir = out_dir.join(METADATA_DIR);
+    let has_ciphertexts = manifest_path(out_dir).is_file()
+        || index_path(out_dir).is_file()
+        || find(out_dir)
+            .filter_map(Result::ok)
+            .any(|path| !path.starts_with(&metadata_dir) && path.is_file());
+    Ok(has_ciphertexts)
+}
+
+/// # Returns
+///
+/// The MAC of a single leaf, i.e. of the ciphertext at `path` having `hash`, so that leaves can be
+/// vouched for one at a time, e.g. as a rekey stages them, before there is a root.
+pub fn leaf_mac(keys: &Keys, path: &Path, hash: &str) -> String {
+    let tag = hmac::sign(&mac_key(keys), &leaf_mac_input(path, hash));
+    HEXLOWER.encode(tag.as_ref())
+}
+
+/// # Returns
+///
+/// Whether `mac` is the MAC of the leaf, as `leaf_mac` computes it.
+pub fn verify_leaf_mac(keys: &Keys, path: &Path, hash: &str, mac: &str) -> bool {
+    HEXLOWER
+        .decode(mac.as_bytes())
+        .is_ok_and(|mac| hmac::verify(&mac_key(keys), &leaf_mac_input(path, hash), &mac).is_ok())
+}
+
+#[inline]
+fn mac_key(keys: &Keys) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA512, &keys.mac)
+}
+
+#[inline]
+fn root_mac_input(root: &str) -> Vec<u8> {
+    [ROOT_MAC_INFO, root.as_bytes()].concat()
+}
+
+#[inline]
+fn leaf_mac_input(path: &Path, hash: &str) -> Vec<u8> {
+    let path = path.to_string_lossy();
+    [LEAF_MAC_INFO, &[0], path.as_bytes(), &[0], hash.as_bytes()].concat()
+}
+
+/// Hash every file in `out_dir` outside of `METADATA_DIR`, and the manifest and the index, if
+/// there are any.
+///
+/// # Returns
+///
+/// A mapping from the path of each, relative to `out_dir`, to its hash.
+fn ciphertext_hashes(out_dir: &Path) -> Result<BTreeMap<PathBuf, String>, Error> {
+    if !out_dir.exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    let metadata_dir = out_dir.join(METADATA_DIR);
+    let mut paths: Vec<PathBuf> = find(out_dir)
+        .filter_map(Result::ok)
+        .filter(|path| !path.starts_with(&metadata_dir))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.extend(
+        [manifest_path(out_dir), index_path(out_dir)]
+            .iter()
+            .filter(|path| path.is_file())
+            .cloned(),
+    );
+
+    paths
+        .par_iter()
+        .map(|path| {
+            let rel_path = path.strip_prefix(out_dir).map_err(io_err)?;
+            Ok((rel_path.to_path_buf(), hash_file(path)?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::test_util::*;
+    use std::fs::rename;
+    use std::fs::write;
+
+    #[test]
+    fn check_finds_every_kind_of_tampering() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let out_dir = mktemp_dir("", "", None)?;
+        create_dir_all(out_dir.path().join("d"))?;
+        ["a", "b", "c", "d/e"]
+            .iter()
+            .try_for_each(|name| write(out_dir.path().join(name), name))?;
+
+        let mut integrity = IntegrityManifest::scan(out_dir.path())?;
+        assert_eq!(4, integrity.leaves.len());
+        integrity.store(out_dir.path(), &keys)?;
+        let loaded = IntegrityManifest::load(out_dir.path(), &keys)?.unwrap();
+        assert_eq!(integrity, loaded);
+        assert!(loaded.check(out_dir.path())?.is_empty());
+
+        // swapping two files changes neither the set of paths nor of contents, but the root
+        rename(out_dir.path().join("a"), out_dir.path().join("x"))?;
+        rename(out_dir.path().join("b"), out_dir.path().join("a"))?;
+        rename(out_dir.path().join("x"), out_dir.path().join("b"))?;
+        remove_path(&out_dir.path().join("c"))?;
+        write(out_dir.path().join("f"), "f")?;
+        assert_eq!(
+            vec![
+                (PathBuf::from("a"), Tampering::Modified),
+                (PathBuf::from("b"), Tampering::Modified),
+                (PathBuf::from("c"), Tampering::Missing),
+                (PathBuf::from("f"), Tampering::Unknown),
+            ],
+            loaded.check(out_dir.path())?
+        );
+        assert_ne!(
+            loaded.root(),
+            IntegrityManifest::scan(out_dir.path())?.root()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_are_authenticated() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let out_dir = mktemp_dir("", "", None)?;
+        write(out_dir.path().join("a"), "a")?;
+
+        let mut integrity = IntegrityManifest::scan(out_dir.path())?;
+        integrity.store(out_dir.path(), &keys)?;
+
+        // only the MAC key of the tree can vouch for it
+        let wrong_keys = Keys {
+            mac: keys.contents.clone(),
+            ..keys.clone()
+        };
+        assert!(IntegrityManifest::load(out_dir.path(), &wrong_keys).is_err());
+
+        // nor can the leaves be changed to match what is there without it
+        write(out_dir.path().join("a"), "b")?;
+        let json = String::from_utf8(read(integrity_path(out_dir.path()))?).unwrap();
+        let forged = json.replace(
+            &integrity.leaves[Path::new("a")],
+            &hash_file(&out_dir.path().join("a"))?,
+        );
+        write(integrity_path(out_dir.path()), forged)?;
+        assert!(IntegrityManifest::load(out_dir.path(), &keys).is_err());
+
+        remove_path(&integrity_path(out_dir.path()))?;
+        assert_eq!(None, IntegrityManifest::load(out_dir.path(), &keys)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use ring::hkdf;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::Error;
+
+use crate::util::*;
+
+/// Length of every key in `Keys`, enough for `SivEncryptor`, which needs the most.
+pub const SUBKEY_LEN: usize = 64;
+
+// what each key in `Keys` is derived for, so that no key is ever used for two purposes
+const NAMES_KDF_INFO: &[u8] = b"csync names";
+const CONTENTS_KDF_INFO: &[u8] = b"csync contents";
+const MANIFEST_KDF_INFO: &[u8] = b"csync manifest";
+const INDEX_KDF_INFO: &[u8] = b"csync index";
+const CONFIG_KDF_INFO: &[u8] = b"csync config";
+const MAC_KDF_INFO: &[u8] = b"csync mac";
+
+/// What the key of the basenames in a single directory is derived for, along with its path.
+pub const DIR_NAMES_KDF_INFO: &[u8] = b"csync names of";
+
+/// How the keys in `Keys` are derived from the master key; every key in an encrypted tree must be
+/// derived the same way, which is why this is kept in the config of the tree.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeySchedule {
+    // the master key itself is used for names, contents and the manifest alike, and the key of the
+    // basenames in each directory is derived with a single iteration of PBKDF2; only for trees that
+    // predate configs
+    Direct,
+    // each key is derived with HKDF-SHA512 under a label of its own, as is the key of the basenames
+    // in each directory
+    #[default]
+    Hkdf,
+}
+
+/// The keys that everything in an encrypted tree is encrypted or authenticated with, each derived
+/// from the master key for a single purpose.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keys {
+    pub schedule: KeySchedule, // how they were derived
+    pub names: Vec<u8>,        // from which the key of the basenames in each directory is derived
+    pub contents: Vec<u8>,     // of the content of every file
+    pub manifest: Vec<u8>,     // of the manifest
+    pub index: Vec<u8>,        // of the index of trees in `Layout::Buckets`
+    pub config: Vec<u8>,       // of the MAC of the config
+    pub mac: Vec<u8>,          // of whatever is authenticated rather than encrypted
+}
+
+impl Keys {
+    /// # Parameters
+    ///
+    /// 1. `master_key`: the master key, i.e. the `key_hash` of the tree, at least `SUBKEY_LEN`
+    ///    bytes long
+    /// 1. `schedule`: how the tree derives its keys, as its config says
+    pub fn derive(master_key: &[u8], schedule: KeySchedule) -> Result<Self, Error> {
+        // there never were keys for these before
+        let index = hkdf_sha512(master_key, &[INDEX_KDF_INFO])?;
+        let config = hkdf_sha512(master_key, &[CONFIG_KDF_INFO])?;
+        let mac = hkdf_sha512(master_key, &[MAC_KDF_INFO])?;
+        match schedule {
+            KeySchedule::Direct => Ok(Self {
+                schedule,
+                names: master_key.to_vec(),
+                contents: master_key.to_vec(),
+                manifest: master_key.to_vec(),
+                index,
+                config,
+                mac,
+            }),
+            KeySchedule::Hkdf => Ok(Self {
+                schedule,
+                names: hkdf_sha512(master_key, &[NAMES_KDF_INFO])?,
+                contents: hkdf_sha512(master_key, &[CONTENTS_KDF_INFO])?,
+                manifest: hkdf_sha512(master_key, &[MANIFEST_KDF_INFO])?,
+                index,
+                config,
+                mac,
+            }),
+        }
+    }
+}
+
+/// Derive a `SUBKEY_LEN`-byte key from `key` with HKDF-SHA512, without a salt, as `key` is
+/// uniformly random already.
+///
+/// # Parameters
+///
+/// 1. `key`: the key to derive from
+/// 1. `info`: what the derived key is for, concatenated; different for every purpose
+pub fn hkdf_sha512(key: &[u8], info: &[&[u8]]) -> Result<Vec<u8>, Error> {
+    let mut derived = vec![0u8; SUBKEY_LEN];
+    hkdf::Salt::new(hkdf::HKDF_SHA512, &[])
+        .extract(key)
+        .expand(info, hkdf::HKDF_SHA512)
+        .and_then(|okm| okm.fill(&mut derived))
+        .map_err(|_| err!("failed to derive a key"))?;
+    Ok(derived)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::test_util::*;
+
+    #[test]
+    fn every_purpose_gets_its_own_key() -> Result<(), Error> {
+        let key_hash = test_key_hash();
+        let keys = Keys::derive(&key_hash, KeySchedule::Hkdf)?;
+        let all = [
+            &keys.names,
+            &keys.contents,
+            &keys.manifest,
+            &keys.index,
+            &keys.config,
+            &keys.mac,
+            &key_hash,
+        ];
+        for (i, a) in all.iter().enumerate() {
+            assert_eq!(SUBKEY_LEN, a.len());
+            assert!(all[i + 1..].iter().all(|b| a != b));
+        }
+        assert_eq!(keys, Keys::derive(&key_hash, KeySchedule::Hkdf)?);
+
+        // older trees use the master key for everything but what did not exist back then
+        let direct = Keys::derive(&key_hash, KeySchedule::Direct)?;
+        assert_eq!(key_hash, direct.names);
+        assert_eq!(key_hash, direct.contents);
+        assert_eq!(key_hash, direct.manifest);
+        assert_eq!(keys.index, direct.index);
+        assert_eq!(keys.config, direct.config);
+        assert_eq!(keys.mac, direct.mac);
+        Ok(())
+    }
+}
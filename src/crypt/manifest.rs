@@ -0,0 +1,201 @@
+use data_encoding::HEXLOWER;
+use ring::digest;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::create_dir_all;
+use std::fs::File;
+use std::io::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::crypt::crypt_encoder::*;
+use crate::crypt::crypt_syncer::*;
+use crate::crypt::file_header::*;
+use crate::crypt::key_schedule::*;
+use crate::encoder::cryptor::X25519_KEY_LEN;
+use crate::encoder::padder::Padding;
+use crate::util::*;
+
+/// Name of the manifest file in `METADATA_DIR`.
+pub const MANIFEST_FILE: &str = "manifest";
+
+/// What is known about a source file as of when it was last synced, and how its ciphertext was
+/// encrypted, so that it is encrypted again once either changes.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    pub size: u64,
+    pub mtime: SystemTime,
+    pub content_hash: String, // see `hasher::hash_file`
+    // entries from before this was recorded were never padded
+    #[serde(default)]
+    pub padding: Padding,
+    // see `recipients_fingerprint`; empty if it was encrypted under the key
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub recipients: String,
+}
+
+/// The sync state of an encrypted tree, stored encrypted in its `METADATA_DIR`, so that later
+/// syncs can tell which files changed since.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Manifest {
+    // keyed by the plaintext path of each file, relative to the root of the tree
+    pub entries: HashMap<PathBuf, ManifestEntry>,
+}
+
+impl Manifest {
+    /// Load the manifest of the encrypted tree in `out_dir`, or an empty one if there is none yet.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `out_dir`: the directory in which the encrypted tree is stored
+    /// 1. `keys`: the keys of the tree, of which the manifest key is used
+    pub fn load(out_dir: &Path, keys: &Keys) -> Result<Self, Error> {
+        let path = manifest_path(out_dir);
+        match path.exists() {
+            true => load_sealed_json(&path, &keys.manifest),
+            false => Ok(Self::default()),
+        }
+    }
+
+    /// Store the manifest encrypted in `out_dir`, replacing the old one atomically, so that an
+    /// interrupted sync never leaves a corrupt manifest behind.
+    pub fn store(&self, out_dir: &Path, keys: &Keys) -> Result<(), Error> {
+        store_sealed_json(self, &manifest_path(out_dir), &keys.manifest)
+    }
+
+    /// Forget every entry at or below `rel_path`.
+    pub fn remove_subtree(&mut self, rel_path: &Path) {
+        self.entries.retain(|path, _| !path.starts_with(rel_path));
+    }
+}
+
+/// # Returns
+///
+/// The SHA256 of the sorted public keys in `recipients`, encoded as lowercase hex, which is the
+/// same for the same set of recipients in any order, or an empty string if there are none.
+pub fn recipients_fingerprint(recipients: &[[u8; X25519_KEY_LEN]]) -> String {
+    if recipients.is_empty() {
+        return String::new();
+    }
+
+    let mut sorted = recipients.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+    HEXLOWER.encode(digest::digest(&digest::SHA256, &sorted.concat()).as_ref())
+}
+
+/// Decrypt the file at `path`, which `store_sealed_json` stored, and parse what is in it.
+///
+/// # Returns
+///
+/// What was stored, or an `ErrorKind::InvalidData` error if it cannot be decrypted or parsed,
+/// which almost certainly means the key is wrong.
+pub fn load_sealed_json<T>(path: &Path, key: &[u8]) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    let json = open_decrypted(File::open(path)?, key, None)
+        .and_then(|mut decrypted| decrypted.as_vec())
+        .map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "failed to decrypt `{:?}`; is the password correct? {}",
+                    path, err
+                ),
+            )
+        })?;
+
+    serde_json::from_slice(&json).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "failed to parse `{:?}`; is the password correct? {}",
+                path, err
+            ),
+        )
+    })
+}
+
+/// Store `value` as JSON, compressed and encrypted under `key`, at `path`, atomically replacing
+/// whatever is there; the counterpart of `load_sealed_json`.
+pub fn store_sealed_json<T>(value: &T, path: &Path, key: &[u8]) -> Result<(), Error>
+where
+    T: Serialize,
+{
+    let dir = path.parent().unwrap_or(Path::new(""));
+    create_dir_all(dir)?;
+
+    let json = serde_json::to_vec(value).map_err(io_err)?;
+    let prefix = path.file_name().and_then(OsStr::to_str).unwrap_or("");
+    let mut temp_file = mktemp_file(prefix, "", Some(dir))?;
+    seal(&json[..], key, &[], CompressionId::Zstd, Padding::None)?
+        .write_all_to(temp_file.as_file_mut())?;
+
+    temp_file.persist(path).map(|_| ()).map_err(|err| err.error)
+}
+
+#[inline]
+pub fn manifest_path(out_dir: &Path) -> PathBuf {
+    out_dir.join(METADATA_DIR).join(MANIFEST_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::test_util::*;
+
+    #[test]
+    fn store_then_load() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let out_dir = mktemp_dir("", "", None)?;
+
+        let mut manifest = Manifest::load(out_dir.path(), &keys)?;
+        assert!(manifest.entries.is_empty());
+
+        let entry = ManifestEntry {
+            size: 3,
+            mtime: SystemTime::now(),
+            content_hash: String::from("abc"),
+            padding: Padding::Padme,
+            recipients: recipients_fingerprint(&[[1u8; X25519_KEY_LEN], [2u8; X25519_KEY_LEN]]),
+        };
+        manifest.entries.insert(PathBuf::from("a/b"), entry.clone());
+        manifest
+            .entries
+            .insert(PathBuf::from("a/bc"), entry.clone());
+        manifest.store(out_dir.path(), &keys)?;
+
+        let mut loaded = Manifest::load(out_dir.path(), &keys)?;
+        assert_eq!(manifest.entries, loaded.entries);
+
+        loaded.remove_subtree(Path::new("a/b"));
+        assert_eq!(
+            vec![&PathBuf::from("a/bc")],
+            loaded.entries.keys().collect::<Vec<_>>()
+        );
+
+        // only the manifest key opens it
+        let wrong_keys = Keys {
+            manifest: keys.contents.clone(),
+            ..keys
+        };
+        assert!(Manifest::load(out_dir.path(), &wrong_keys).is_err());
+
+        // the order of recipients does not matter, but which they are does
+        assert_eq!(
+            entry.recipients,
+            recipients_fingerprint(&[[2u8; X25519_KEY_LEN], [1u8; X25519_KEY_LEN]])
+        );
+        assert_ne!(
+            entry.recipients,
+            recipients_fingerprint(&[[1u8; X25519_KEY_LEN]])
+        );
+        assert_eq!("", recipients_fingerprint(&[]));
+        Ok(())
+    }
+}
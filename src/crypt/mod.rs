@@ -1,7 +1,22 @@
 #[macro_use]
 pub mod crypt_encoder;
 
+pub mod crypt_lister;
+pub mod crypt_rekeyer;
+pub mod crypt_restorer;
 pub mod crypt_syncer;
+pub mod crypt_verifier;
+pub mod crypt_watcher;
+pub mod file_header;
+pub mod index;
+pub mod integrity;
+pub mod key_schedule;
+pub mod manifest;
+pub mod name_cipher;
+pub mod repo_config;
+pub mod sync_plan;
+#[cfg(test)]
+pub mod test_util;
 
 // pub use crypt_encoder;
 // pub use crypt_syncer;
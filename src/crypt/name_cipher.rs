@@ -0,0 +1,368 @@
+use data_encoding::HEXLOWER;
+use ring::digest;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs::read_to_string;
+use std::fs::write;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::from_utf8;
+
+use crate::crypt::key_schedule::*;
+use crate::encoder::cryptor::*;
+use crate::encoder::text_decoder::*;
+use crate::hasher::*;
+use crate::util::*;
+
+/// Longest basename that is stored as it is, i.e. `NAME_MAX` on most filesystems; longer ones are
+/// stored as long names instead, see `stored_basename`.
+pub const LONG_NAME_MAX: usize = 255;
+
+// long names are lowercase, unlike text-encoded ciphertexts, so the two can never collide
+const LONG_NAME_PREFIX: &str = "ln-";
+const SIDECAR_SUFFIX: &str = ".name";
+
+/// How basenames are encrypted; every basename in an encrypted tree must be encrypted the same
+/// way, so that the ciphertext of a path can be derived from its plaintext, which is why this is
+/// kept in the config of the tree.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NameCipher {
+    // with `CfbEncryptor` under a fixed IV, which is malleable, and lets basenames that share a
+    // prefix share a ciphertext prefix too; only for trees that predate configs
+    AesCfb,
+    // with `SivEncryptor`, which is authenticated, and leaks nothing but whether two basenames in
+    // the same directory are equal
+    #[default]
+    AesSiv,
+}
+
+/// Derive the key with which the basename of some path is encrypted.
+///
+/// # Parameters
+///
+/// 1. `opt_parent`: plaintext path of the parent, relative to the root of the tree; `None` or an
+///    empty path for the root itself
+/// 1. `keys`: the keys of the tree
+///
+/// # Returns
+///
+/// The key of every basename in `opt_parent`: with `KeySchedule::Hkdf`, the names key expanded
+/// with the parent path as info, or with `KeySchedule::Direct`, the names key itself for the root,
+/// and the names key hashed with the parent path as salt otherwise.
+pub fn parent_derived_hash(opt_parent: Option<&Path>, keys: &Keys) -> Result<Vec<u8>, Error> {
+    let parent = opt_parent.unwrap_or(Path::new(""));
+    let parent_str = parent
+        .to_str()
+        .ok_or(err!("`{:?}` contains non utf8 chars", parent))?;
+    match (keys.schedule, parent_str) {
+        (KeySchedule::Direct, "") => Ok(keys.names.clone()),
+        (KeySchedule::Direct, _) => Ok(hash_custom(
+            &keys.names,
+            Some(parent_str.as_bytes()),
+            Some(1),
+        )),
+        (KeySchedule::Hkdf, _) => {
+            hkdf_sha512(&keys.names, &[DIR_NAMES_KDF_INFO, parent_str.as_bytes()])
+        }
+    }
+}
+
+/// # Returns
+///
+/// `basename` encrypted with `key` as `names` says, then text-encoded so that it can be used as a
+/// basename. Unlike file contents, basenames are encrypted deterministically, as finding the
+/// ciphertext of a path without decrypting the whole tree, e.g. in `path_ciphertext`, depends on
+/// it.
+pub fn encrypt_basename(basename: &str, key: &[u8], names: NameCipher) -> Result<String, Error> {
+    match names {
+        NameCipher::AesCfb => compose_encoders!(
+            basename.as_bytes(),
+            CfbEncryptor => key,
+            TextEncoder => None
+        )?
+        .as_string(),
+        NameCipher::AesSiv => compose_encoders!(
+            basename.as_bytes(),
+            SivEncryptor => key,
+            TextEncoder => None
+        )?
+        .as_string(),
+    }
+}
+
+/// Inverse of `encrypt_basename`.
+///
+/// With `NameCipher::AesCfb`, decrypting with the wrong key produces garbage rather than an error,
+/// so the result is checked to be something that can actually be a basename; this also makes sure
+/// that a tampered ciphertext cannot escape the directory it is restored into.
+pub fn decrypt_basename(ciphertext: &str, key: &[u8], names: NameCipher) -> Result<String, Error> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "failed to decrypt `{}`; is the password correct?",
+                ciphertext
+            ),
+        )
+    };
+    let plaintext = match names {
+        NameCipher::AesCfb => compose_encoders!(
+            ciphertext.as_bytes(),
+            TextDecoder => None,
+            CfbDecryptor => key
+        )?
+        .as_vec()?,
+        NameCipher::AesSiv => compose_encoders!(
+            ciphertext.as_bytes(),
+            TextDecoder => None,
+            SivDecryptor => key
+        )?
+        .as_vec()
+        .map_err(|_| invalid())?,
+    };
+
+    let basename = from_utf8(&plaintext).map_err(|_| invalid())?;
+    match basename {
+        "" | "." | ".." => Err(invalid()),
+        _ if basename.contains(['/', '\0']) => Err(invalid()),
+        _ => Ok(String::from(basename)),
+    }
+}
+
+/// # Returns
+///
+/// The basename under which the basename `ciphertext` is stored: `ciphertext` itself, unless it
+/// is longer than `LONG_NAME_MAX`, in which case it is a long name, made of the hash of
+/// `ciphertext`, and `ciphertext` itself goes into a sidecar file next to it, see `sidecar_path`.
+pub fn stored_basename(ciphertext: String) -> String {
+    match ciphertext.len() > LONG_NAME_MAX {
+        true => {
+            let hash = digest::digest(&digest::SHA256, ciphertext.as_bytes());
+            format!("{}{}", LONG_NAME_PREFIX, HEXLOWER.encode(hash.as_ref()))
+        }
+        false => ciphertext,
+    }
+}
+
+/// # Returns
+///
+/// The path of the sidecar file that holds the ciphertext of the basename of `stored`, if it is a
+/// long name.
+pub fn sidecar_path(stored: &Path) -> Option<PathBuf> {
+    let basename = stored.file_name()?.to_str()?;
+    match basename.starts_with(LONG_NAME_PREFIX) && !basename.ends_with(SIDECAR_SUFFIX) {
+        true => Some(stored.with_file_name(format!("{}{}", basename, SIDECAR_SUFFIX))),
+        false => None,
+    }
+}
+
+/// # Returns
+///
+/// Whether `path` is the sidecar file of some long name, rather than a ciphertext of its own.
+pub fn is_sidecar(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|basename| basename.to_str())
+        .map(|basename| {
+            basename.starts_with(LONG_NAME_PREFIX) && basename.ends_with(SIDECAR_SUFFIX)
+        })
+        .unwrap_or(false)
+}
+
+/// Find the ciphertext of the basename of `stored`, a path in some encrypted tree, reading it
+/// from its sidecar file if it is a long name; the inverse of `stored_basename`.
+pub fn stored_ciphertext(stored: &Path) -> Result<String, Error> {
+    let basename = basename_bytes(stored)?;
+    let basename = from_utf8(basename).map_err(io_err)?;
+    let sidecar = match sidecar_path(stored) {
+        Some(sidecar) => sidecar,
+        None => return Ok(String::from(basename)),
+    };
+
+    let ciphertext = read_to_string(&sidecar)
+        .map_err(|err| err!("failed to read the sidecar `{:?}`: {}", sidecar, err))?;
+    match stored_basename(ciphertext.clone()) == basename {
+        true => Ok(ciphertext),
+        false => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("`{:?}` does not match its sidecar", stored),
+        )),
+    }
+}
+
+/// Write the sidecar file of `stored`, if it is a long name, replacing whatever was there.
+///
+/// # Parameters
+///
+/// 1. `stored`: the path in some encrypted tree that `rel_path` is stored at
+/// 1. `rel_path`: plaintext path relative to the root of the tree
+/// 1. `keys`: the keys of the tree
+/// 1. `names`: how the basenames of the tree are encrypted
+pub fn write_sidecar(
+    stored: &Path,
+    rel_path: &Path,
+    keys: &Keys,
+    names: NameCipher,
+) -> Result<(), Error> {
+    let sidecar = match sidecar_path(stored) {
+        Some(sidecar) => sidecar,
+        None => return Ok(()),
+    };
+
+    let basename = rel_path
+        .file_name()
+        .and_then(|basename| basename.to_str())
+        .ok_or(err!("`{:?}` has no utf8 basename", rel_path))?;
+    let key = parent_derived_hash(rel_path.parent(), keys)?;
+    write(sidecar, encrypt_basename(basename, &key, names)?)
+}
+
+/// Encrypt a single path, one basename at a time; the single-path counterpart of what
+/// `CryptSyncer` derives for a whole tree.
+///
+/// Only the plaintext path is needed, so this also works for paths that no longer exist. Long
+/// names are hashed, just like they are stored.
+///
+/// # Parameters
+///
+/// 1. `rel_path`: plaintext path relative to the root of the tree, i.e. starting with the basename
+///    of the synced source
+/// 1. `keys`: the keys of the tree
+/// 1. `names`: how the basenames of the tree are encrypted
+pub fn path_ciphertext(rel_path: &Path, keys: &Keys, names: NameCipher) -> Result<PathBuf, Error> {
+    rel_path
+        .components()
+        .try_fold(
+            // initial value: tuple (acc_src, acc_enc)
+            (PathBuf::new(), PathBuf::new()),
+            |(mut acc_src, mut acc_enc), comp| match comp {
+                Component::Normal(component) => {
+                    let basename = component
+                        .to_str()
+                        .ok_or(err!("`{:?}` contains non utf8 chars", rel_path))?;
+                    let key = parent_derived_hash(Some(&acc_src), keys)?;
+                    acc_enc.push(stored_basename(encrypt_basename(basename, &key, names)?));
+                    acc_src.push(component);
+                    Ok((acc_src, acc_enc))
+                }
+                _ => Err(err!("`{:?}` is not a normal relative path", rel_path)),
+            },
+        )
+        .map(|(_, acc_enc)| acc_enc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::test_util::*;
+    use rayon::prelude::*;
+
+    fn test_data() -> Vec<&'static str> {
+        vec![
+            "a",
+            "main.rs",
+            ".gitignore",
+            "some dir with spaces",
+            "ünïcödé",
+        ]
+    }
+
+    #[test]
+    fn parametrized_identity() {
+        let keys = test_keys(KeySchedule::Hkdf);
+
+        test_data().into_par_iter().for_each(|basename| {
+            let key = parent_derived_hash(Some(Path::new("p1/p2")), &keys).unwrap();
+            for &names in [NameCipher::AesCfb, NameCipher::AesSiv].iter() {
+                let ciphertext = encrypt_basename(basename, &key, names).unwrap();
+                assert_ne!(basename, ciphertext);
+                assert_eq!(
+                    basename,
+                    decrypt_basename(&ciphertext, &key, names).unwrap()
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn siv_names_are_authenticated() {
+        let key_hash = test_key_hash();
+        let other_key_hash = hash_custom("sdf98!@4".as_bytes(), None, Some(1 << 8));
+        let names = NameCipher::AesSiv;
+
+        let ciphertext = encrypt_basename("main.rs", &key_hash, names).unwrap();
+        let other = encrypt_basename("main.rt", &key_hash, names).unwrap();
+        assert_ne!(ciphertext[..8], other[..8]);
+
+        assert!(decrypt_basename(&ciphertext, &other_key_hash, names).is_err());
+        let mut modified = ciphertext.into_bytes();
+        modified[4] = if modified[4] == b'A' { b'B' } else { b'A' };
+        let modified = String::from_utf8(modified).unwrap();
+        assert!(decrypt_basename(&modified, &key_hash, names).is_err());
+    }
+
+    #[test]
+    fn long_names_are_resolved_through_sidecars() -> Result<(), Error> {
+        let keys = test_keys(KeySchedule::Hkdf);
+        let out_dir = tempfile::tempdir()?;
+        let short = Path::new("dir/short");
+        let long = PathBuf::from("dir").join("x".repeat(200));
+
+        let stored = out_dir
+            .path()
+            .join(path_ciphertext(short, &keys, NameCipher::AesSiv)?);
+        assert_eq!(None, sidecar_path(&stored));
+        write_sidecar(&stored, short, &keys, NameCipher::AesSiv)?;
+        assert_eq!(0, std::fs::read_dir(out_dir.path())?.count());
+
+        let stored = out_dir
+            .path()
+            .join(path_ciphertext(&long, &keys, NameCipher::AesSiv)?);
+        let sidecar = sidecar_path(&stored).unwrap();
+        assert!(stored.file_name().unwrap().len() <= LONG_NAME_MAX);
+        assert!(sidecar.file_name().unwrap().len() <= LONG_NAME_MAX);
+        assert!(is_sidecar(&sidecar) && !is_sidecar(&stored));
+        assert_eq!(None, sidecar_path(&sidecar));
+
+        std::fs::create_dir_all(stored.parent().unwrap())?;
+        assert!(stored_ciphertext(&stored).is_err());
+        write_sidecar(&stored, &long, &keys, NameCipher::AesSiv)?;
+        let key = parent_derived_hash(long.parent(), &keys)?;
+        let basename = decrypt_basename(&stored_ciphertext(&stored)?, &key, NameCipher::AesSiv)?;
+        assert_eq!(long.file_name().unwrap().to_str().unwrap(), basename);
+
+        // a sidecar that belongs to another long name is rejected
+        std::fs::rename(
+            &sidecar,
+            sidecar.with_file_name(format!("ln-{}.name", "0".repeat(64))),
+        )?;
+        let other = stored.with_file_name(format!("ln-{}", "0".repeat(64)));
+        assert!(stored_ciphertext(&other).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn every_dir_gets_its_own_key() {
+        for &schedule in [KeySchedule::Direct, KeySchedule::Hkdf].iter() {
+            let keys = test_keys(schedule);
+            let root_key = parent_derived_hash(Some(Path::new("")), &keys).unwrap();
+            assert_eq!(root_key, parent_derived_hash(None, &keys).unwrap());
+            assert_ne!(
+                root_key,
+                parent_derived_hash(Some(Path::new("p1")), &keys).unwrap()
+            );
+            assert_eq!(SIV_KEY_LEN, root_key.len());
+        }
+
+        // trees that predate `KeySchedule::Hkdf` use the key itself for the root
+        let keys = test_keys(KeySchedule::Direct);
+        assert_eq!(keys.names, parent_derived_hash(None, &keys).unwrap());
+        assert_ne!(
+            keys.names,
+            parent_derived_hash(None, &test_keys(KeySchedule::Hkdf)).unwrap()
+        );
+    }
+}
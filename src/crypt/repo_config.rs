@@ -0,0 +1,797 @@
+use data_encoding::HEXLOWER;
+use openssl::rand::rand_bytes;
+use ring::hmac;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs::create_dir_all;
+use std::fs::read;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::crypt::crypt_encoder::*;
+use crate::crypt::crypt_syncer::*;
+use crate::crypt::file_header::*;
+use crate::crypt::index::*;
+use crate::crypt::key_schedule::*;
+use crate::crypt::manifest::*;
+use crate::crypt::name_cipher::*;
+use crate::encoder::cryptor::*;
+use crate::encoder::padder::*;
+use crate::hasher::*;
+use crate::util::*;
+
+/// Name of the repository config file in `METADATA_DIR`.
+pub const CONFIG_FILE: &str = "config";
+
+/// Version of the config format, bumped on every incompatible change.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Length of the random salt of a new repository.
+pub const SALT_LEN: usize = 16;
+
+/// Length of the random master key of a new repository, i.e. of its `key_hash`.
+pub const MASTER_KEY_LEN: usize = 64;
+
+// what the MAC of the config is computed over, along with the config
+const CONFIG_MAC_INFO: &[u8] = b"csync config";
+
+/// One of the keys that unlock an encrypted tree, e.g. the passphrase of one team member.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct KeySlot {
+    pub id: u32,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub label: String, // e.g. whose key it is
+    pub salt: String, // hex
+    pub kdf: Kdf,     // how the key is stretched
+    // hex; the master key, encrypted with `Encryptor` under the stretched key; `None` only in
+    // `RepoConfig::legacy`, whose stretched key is `key_hash` itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrapped_key: Option<String>,
+}
+
+impl KeySlot {
+    /// A slot with a fresh random salt, in which `master_key` is wrapped under `key`.
+    fn wrapping(
+        id: u32,
+        label: &str,
+        master_key: &[u8],
+        kdf: Kdf,
+        key: &[u8],
+    ) -> Result<Self, Error> {
+        let mut salt = [0u8; SALT_LEN];
+        rand_bytes(&mut salt).map_err(io_err)?;
+
+        let wrapped_key = Encryptor::new(master_key, &kdf.hash(key, &salt)?)?.as_vec()?;
+        Ok(Self {
+            id,
+            label: String::from(label),
+            salt: HEXLOWER.encode(&salt),
+            kdf,
+            wrapped_key: Some(HEXLOWER.encode(&wrapped_key)),
+        })
+    }
+
+    /// # Returns
+    ///
+    /// The master key, or an `ErrorKind::InvalidData` error if `key` does not unlock this slot, or
+    /// if it has no wrapped key.
+    fn unlock(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        let wrapped_key = match &self.wrapped_key {
+            Some(wrapped_key) => decode_hex(wrapped_key, "wrapped key")?,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("key slot {} has no wrapped key", self.id),
+                ))
+            }
+        };
+        let wrapping_key = self.kdf.hash(key, &self.salt_bytes()?)?;
+        Decryptor::new(&wrapped_key[..], &wrapping_key)?
+            .as_vec()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "the password is wrong"))
+    }
+
+    fn salt_bytes(&self) -> Result<Vec<u8>, Error> {
+        match decode_hex(&self.salt, "salt")? {
+            salt if salt.len() >= SALT_LEN => Ok(salt),
+            _ => Err(Error::new(ErrorKind::InvalidData, "the salt is too short")),
+        }
+    }
+}
+
+/// What is needed to derive `key_hash` for an encrypted tree, stored in plaintext in its
+/// `METADATA_DIR`, as it has to be read before there is a key to decrypt anything with.
+///
+/// `key_hash` is a random master key, which is stored once per key slot, encrypted under the key of
+/// that slot, stretched under a salt that is random for every slot, so that guesses of a key cannot
+/// be precomputed once and then tried against every encrypted tree there is. A key can then be
+/// changed, added or revoked by re-encrypting only the master key, rather than the whole tree.
+///
+/// Whoever can write to `out_dir` can change the config, e.g. to have every later sync encrypt
+/// basenames the weaker way, so the config has a MAC under a key derived from the master key, which
+/// is checked before the master key is ever used. Only `legacy` has none, and is accepted only as
+/// it is, byte for byte.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RepoConfig {
+    pub version: u32,
+    pub slots: Vec<KeySlot>,     // never empty
+    pub names: NameCipher,       // how basenames are encrypted
+    pub contents: ContentCipher, // how the content of files is encrypted
+    pub keys: KeySchedule,       // how the keys of the tree are derived from the master key
+    // whether the tree has an integrity manifest, so that one that is missing was removed; `false`
+    // only in `legacy`
+    pub integrity: bool,
+    pub padding: Padding, // how the content of files is padded before it is encrypted
+    pub layout: Layout,   // how the tree is laid out in its `out_dir`
+    // hex; of everything else, under the config key; empty only in `legacy`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub mac: String,
+}
+
+impl RepoConfig {
+    /// A config with a fresh random master key, in a single slot.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `kdf`: how to stretch the key
+    /// 1. `key`: the key that unlocks the master key
+    /// 1. `padding`: how to pad the content of files
+    /// 1. `layout`: how to lay out the tree
+    pub fn new(kdf: Kdf, key: &[u8], padding: Padding, layout: Layout) -> Result<Self, Error> {
+        let mut master_key = [0u8; MASTER_KEY_LEN];
+        rand_bytes(&mut master_key).map_err(io_err)?;
+        let mut config = Self {
+            version: CONFIG_VERSION,
+            slots: vec![KeySlot::wrapping(0, "", &master_key, kdf, key)?],
+            names: NameCipher::default(),
+            contents: ContentCipher::default(),
+            keys: KeySchedule::default(),
+            integrity: true,
+            padding,
+            layout,
+            mac: String::new(),
+        };
+        config.authenticate(&master_key)?;
+        Ok(config)
+    }
+
+    /// The config that every encrypted tree implicitly had before configs existed: the key hashed
+    /// under `DEFAULT_SALT`, which contents and basenames alike are encrypted with AES-CFB under.
+    ///
+    /// It has neither a master key nor a MAC, so it is the only config that is accepted without a
+    /// MAC, and only as it is; `rekey` migrates a tree to a config that has both.
+    pub fn legacy() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            slots: vec![KeySlot {
+                id: 0,
+                label: String::new(),
+                salt: HEXLOWER.encode(&DEFAULT_SALT),
+                kdf: Kdf::Pbkdf2Sha512 {
+                    num_iter: PBKDF2_NUM_ITER,
+                },
+                wrapped_key: None,
+            }],
+            names: NameCipher::AesCfb,
+            contents: ContentCipher::AesCfb,
+            keys: KeySchedule::Direct,
+            integrity: false,
+            padding: Padding::None,
+            layout: Layout::Tree,
+            mac: String::new(),
+        }
+    }
+
+    /// Wrap the same master key under `new_key` instead, in the slot that `key` unlocks, so that
+    /// nothing else in the encrypted tree has to change along with the key.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `key`: the key that currently unlocks the master key
+    /// 1. `new_key`: the key that unlocks it from now on
+    ///
+    /// # Returns
+    ///
+    /// An `ErrorKind::InvalidData` error if `key` is wrong, or if the config has no master key, in
+    /// which case the whole tree would have to be re-encrypted.
+    pub fn change_key(&mut self, key: &[u8], new_key: &[u8]) -> Result<(), Error> {
+        let (i, master_key) = self.unlock_master_key(key)?;
+        let slot = &self.slots[i];
+        self.slots[i] =
+            KeySlot::wrapping(slot.id, &slot.label, &master_key, slot.kdf.clone(), new_key)?;
+        self.authenticate(&master_key)
+    }
+
+    /// Add a slot in which the master key is wrapped under `new_key`, with the same KDF as the slot
+    /// that `key` unlocks.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `key`: a key that already unlocks the master key
+    /// 1. `new_key`: the key of the new slot
+    /// 1. `label`: e.g. whose key it is
+    ///
+    /// # Returns
+    ///
+    /// The id of the new slot.
+    pub fn add_slot(&mut self, key: &[u8], new_key: &[u8], label: &str) -> Result<u32, Error> {
+        let (i, master_key) = self.unlock_master_key(key)?;
+        let id = self.slots.iter().map(|slot| slot.id + 1).max().unwrap_or(0);
+        let slot = KeySlot::wrapping(id, label, &master_key, self.slots[i].kdf.clone(), new_key)?;
+        self.slots.push(slot);
+        self.authenticate(&master_key)?;
+        Ok(id)
+    }
+
+    /// Remove the slot with `id`, so that its key no longer unlocks the master key.
+    ///
+    /// The master key itself stays the same, so whoever held the key of the slot, and kept a copy
+    /// of the master key or of the config, can still decrypt the tree.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `key`: a key that unlocks the master key, possibly that of the removed slot
+    /// 1. `id`: the slot to remove
+    ///
+    /// # Returns
+    ///
+    /// An `ErrorKind::NotFound` error if there is no such slot, or an `ErrorKind::InvalidInput`
+    /// error if it is the last one, as nothing could be decrypted without it.
+    pub fn remove_slot(&mut self, key: &[u8], id: u32) -> Result<(), Error> {
+        let (_, master_key) = self.unlock_master_key(key)?;
+        match self.slots.iter().position(|slot| slot.id == id) {
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("there is no key slot {}", id),
+            )),
+            Some(_) if self.slots.len() == 1 => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("key slot {} is the last one", id),
+            )),
+            Some(i) => {
+                self.slots.remove(i);
+                self.authenticate(&master_key)
+            }
+        }
+    }
+
+    /// A config for the same tree once every file and basename in it has been encrypted again under
+    /// a fresh random master key, e.g. because the old one was compromised; see `CryptRekeyer`.
+    ///
+    /// The new master key is wrapped under `new_key` in a single slot, which keeps the id, label
+    /// and KDF of the slot that `key` unlocks; every other slot is dropped, as their keys are not
+    /// known. Basenames and contents are encrypted, and keys derived, the default way from then on,
+    /// and `legacy` trees get a master key, with the default KDF; files stay padded, and the tree
+    /// laid out, as they were.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `key`: the key that currently unlocks the master key
+    /// 1. `new_key`: the key that unlocks the new master key
+    pub fn rekey(&self, key: &[u8], new_key: &[u8]) -> Result<Self, Error> {
+        let (id, label, kdf) = match self.is_legacy() {
+            true => (0, String::new(), Kdf::default()),
+            false => {
+                let slot = &self.slots[self.unlock_master_key(key)?.0];
+                (slot.id, slot.label.clone(), slot.kdf.clone())
+            }
+        };
+
+        let mut master_key = [0u8; MASTER_KEY_LEN];
+        rand_bytes(&mut master_key).map_err(io_err)?;
+        let mut config = Self {
+            version: CONFIG_VERSION,
+            slots: vec![KeySlot::wrapping(id, &label, &master_key, kdf, new_key)?],
+            names: NameCipher::default(),
+            contents: ContentCipher::default(),
+            keys: KeySchedule::default(),
+            integrity: true,
+            padding: self.padding,
+            layout: self.layout,
+            mac: String::new(),
+        };
+        config.authenticate(&master_key)?;
+        Ok(config)
+    }
+
+    /// # Returns
+    ///
+    /// The index of the first slot that `key` unlocks, along with the master key, once the MAC of
+    /// the config has been checked; an `ErrorKind::InvalidData` error if the config is `legacy`,
+    /// or if it does not match its MAC.
+    fn unlock_master_key(&self, key: &[u8]) -> Result<(usize, Vec<u8>), Error> {
+        if self.is_legacy() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the encrypted tree predates master keys, so its keys cannot be changed; run \
+                 `csync rekey` to give it one",
+            ));
+        }
+
+        for (i, slot) in self.slots.iter().enumerate() {
+            match slot.unlock(key) {
+                Ok(master_key) => {
+                    self.check_mac(&master_key)?;
+                    return Ok((i, master_key));
+                }
+                Err(err) if err.kind() == ErrorKind::InvalidData => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(Error::new(ErrorKind::InvalidData, "the password is wrong"))
+    }
+
+    /// Compute the MAC of the config under `master_key`, once anything in it changed.
+    fn authenticate(&mut self, master_key: &[u8]) -> Result<(), Error> {
+        self.mac =
+            HEXLOWER.encode(hmac::sign(&config_mac_key(master_key)?, &self.mac_input()?).as_ref());
+        Ok(())
+    }
+
+    /// Check the MAC of the config under `master_key`, the master key as unlocked by some slot.
+    fn check_mac(&self, master_key: &[u8]) -> Result<(), Error> {
+        let tampered = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                "the config does not match its MAC, so it was tampered with",
+            )
+        };
+        let mac = HEXLOWER
+            .decode(self.mac.as_bytes())
+            .map_err(|_| tampered())?;
+        hmac::verify(&config_mac_key(master_key)?, &self.mac_input()?, &mac).map_err(|_| tampered())
+    }
+
+    /// # Returns
+    ///
+    /// What the MAC of the config is computed over: everything in it but the MAC itself.
+    fn mac_input(&self) -> Result<Vec<u8>, Error> {
+        let unauthenticated = Self {
+            mac: String::new(),
+            ..self.clone()
+        };
+        let json = serde_json::to_vec(&unauthenticated).map_err(io_err)?;
+        Ok([CONFIG_MAC_INFO, &[0], &json].concat())
+    }
+
+    /// Create a new config for the encrypted tree in `out_dir`, creating `out_dir` if needed.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `out_dir`: the directory in which the encrypted tree will be stored
+    /// 1. `kdf`: how to stretch the key
+    /// 1. `key`: the key that unlocks the master key
+    /// 1. `padding`: how to pad the content of files
+    /// 1. `layout`: how to lay out the tree
+    ///
+    /// # Returns
+    ///
+    /// The new config, or an `ErrorKind::AlreadyExists` error if `out_dir` already has one, since
+    /// replacing it would make everything in `out_dir` undecryptable.
+    pub fn init(
+        out_dir: &Path,
+        kdf: Kdf,
+        key: &[u8],
+        padding: Padding,
+        layout: Layout,
+    ) -> Result<Self, Error> {
+        let config = Self::new(kdf, key, padding, layout)?;
+        config.write(&config_path(out_dir), false)?;
+        Ok(config)
+    }
+
+    /// Load the config of the encrypted tree in `out_dir`.
+    ///
+    /// # Returns
+    ///
+    /// The config, or `None` if there is none.
+    pub fn load(out_dir: &Path) -> Result<Option<Self>, Error> {
+        Self::load_file(&config_path(out_dir))
+    }
+
+    /// Load a config from `path`, which need not be where the config of a tree is kept, e.g. that of
+    /// a rekey in progress.
+    ///
+    /// # Returns
+    ///
+    /// The config, or `None` if there is none.
+    pub fn load_file(path: &Path) -> Result<Option<Self>, Error> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+        let json = read(path)?;
+        let config: Self = serde_json::from_slice(&json)
+            .map_err(|err| invalid(format!("failed to parse `{:?}`: {}", path, err)))?;
+        if config.version != CONFIG_VERSION {
+            return Err(invalid(format!(
+                "`{:?}` is of unsupported version {}",
+                path, config.version
+            )));
+        }
+        if config.slots.is_empty() {
+            return Err(invalid(format!("`{:?}` has no key slots", path)));
+        }
+        // the MAC is only checked once the master key is unlocked, which a config that was stripped
+        // of it must never get as far as
+        if config.mac.is_empty()
+            && json != serde_json::to_vec_pretty(&Self::legacy()).map_err(io_err)?
+        {
+            return Err(invalid(format!(
+                "`{:?}` has no MAC, so it was tampered with; only the config of trees synced \
+                 before configs existed has none, and `csync rekey` gives them one",
+                path
+            )));
+        }
+        for slot in &config.slots {
+            slot.salt_bytes()?;
+            slot.kdf
+                .check()
+                .map_err(|err| invalid(format!("`{:?}`: {}", path, err)))?;
+        }
+        Ok(Some(config))
+    }
+
+    /// Load the config of the encrypted tree in `out_dir`, falling back to `legacy` for trees that
+    /// were synced before configs existed.
+    pub fn load_or_legacy(out_dir: &Path) -> Result<Self, Error> {
+        Ok(Self::load(out_dir)?.unwrap_or_else(Self::legacy))
+    }
+
+    /// Load the config of the encrypted tree that a sync to `out_dir` updates, creating one with
+    /// the default KDF if this is the first sync to `out_dir`.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `out_dir`: the directory in which the encrypted tree is stored
+    /// 1. `key`: the key that unlocks the master key, if a config is created
+    /// 1. `store`: whether to store the new config, if one is created; `false` for dry runs
+    pub fn load_or_init(out_dir: &Path, key: &[u8], store: bool) -> Result<Self, Error> {
+        match Self::load(out_dir)? {
+            Some(config) => Ok(config),
+            // synced before configs existed
+            None if out_dir.join(METADATA_DIR).join(MANIFEST_FILE).exists() => Ok(Self::legacy()),
+            None if store => Self::init(out_dir, Kdf::default(), key, Padding::None, Layout::Tree),
+            None => Self::new(Kdf::default(), key, Padding::None, Layout::Tree),
+        }
+    }
+
+    /// Store the config in `out_dir`, atomically replacing the one that is there, if any.
+    pub fn store(&self, out_dir: &Path) -> Result<(), Error> {
+        self.store_file(&config_path(out_dir))
+    }
+
+    /// Store the config at `path`, atomically replacing whatever is there; the counterpart of
+    /// `load_file`.
+    pub fn store_file(&self, path: &Path) -> Result<(), Error> {
+        self.write(path, true)
+    }
+
+    /// Unlock the master key with `key`, trying every slot in turn.
+    ///
+    /// # Returns
+    ///
+    /// The hash that everything in the encrypted tree is encrypted with, or an
+    /// `ErrorKind::InvalidData` error if `key` unlocks no slot.
+    pub fn derive_key_hash(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.is_legacy() {
+            // there is nothing to unlock in trees that predate master keys
+            true => self.slots[0].kdf.hash(key, &DEFAULT_SALT),
+            false => self
+                .unlock_master_key(key)
+                .map(|(_, master_key)| master_key),
+        }
+    }
+
+    /// Unlock the master key with `key`, then derive the keys of the tree from it, as the config
+    /// says.
+    pub fn derive_keys(&self, key: &[u8]) -> Result<Keys, Error> {
+        Keys::derive(&self.derive_key_hash(key)?, self.keys)
+    }
+
+    /// Whether this is `legacy`, the only config that has no MAC, and so can never be changed.
+    fn is_legacy(&self) -> bool {
+        *self == Self::legacy()
+    }
+
+    fn write(&self, path: &Path, replace: bool) -> Result<(), Error> {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        create_dir_all(dir)?;
+
+        let json = serde_json::to_vec_pretty(self).map_err(io_err)?;
+        let mut temp_file = mktemp_file(CONFIG_FILE, "", Some(dir))?;
+        temp_file.write_all(&json)?;
+
+        match replace {
+            true => temp_file.persist(path).map(|_| ()).map_err(|err| err.error),
+            false => temp_file
+                .persist_noclobber(path)
+                .map(|_| ())
+                .map_err(|err| {
+                    Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("`{:?}` already exists: {}", path, err.error),
+                    )
+                }),
+        }
+    }
+}
+
+/// The key of the MAC of a config, derived from its master key.
+fn config_mac_key(master_key: &[u8]) -> Result<hmac::Key, Error> {
+    let keys = Keys::derive(master_key, KeySchedule::Hkdf)?;
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, &keys.config))
+}
+
+fn decode_hex(value: &str, what: &str) -> Result<Vec<u8>, Error> {
+    HEXLOWER.decode(value.as_bytes()).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("`{}` is not a valid {}", value, what),
+        )
+    })
+}
+
+#[inline]
+pub fn config_path(out_dir: &Path) -> PathBuf {
+    out_dir.join(METADATA_DIR).join(CONFIG_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // as cheap as the floors allow, for tests
+    const KDF: Kdf = Kdf::Pbkdf2Sha512 {
+        num_iter: PBKDF2_MIN_NUM_ITER,
+    };
+
+    #[test]
+    fn init_then_load() -> Result<(), Error> {
+        let out_dir = mktemp_dir("", "", None)?;
+        assert_eq!(None, RepoConfig::load(out_dir.path())?);
+
+        let config = RepoConfig::init(
+            out_dir.path(),
+            KDF,
+            b"password",
+            Padding::None,
+            Layout::Tree,
+        )?;
+        assert_eq!(Some(config.clone()), RepoConfig::load(out_dir.path())?);
+        assert_eq!(
+            config,
+            RepoConfig::load_or_init(out_dir.path(), b"password", true)?
+        );
+        assert_eq!(
+            ErrorKind::AlreadyExists,
+            RepoConfig::init(
+                out_dir.path(),
+                KDF,
+                b"password",
+                Padding::None,
+                Layout::Tree
+            )
+            .unwrap_err()
+            .kind()
+        );
+
+        // every repository gets its own salt and master key, and so its own key hash for the same
+        // key
+        let other = RepoConfig::new(KDF, b"password", Padding::None, Layout::Tree)?;
+        assert_ne!(config.slots[0].salt, other.slots[0].salt);
+        assert_ne!(
+            config.derive_key_hash(b"password")?,
+            other.derive_key_hash(b"password")?
+        );
+        assert_eq!(
+            config.derive_key_hash(b"password")?,
+            RepoConfig::load_or_legacy(out_dir.path())?.derive_key_hash(b"password")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn change_key_keeps_the_key_hash() -> Result<(), Error> {
+        let config = RepoConfig::new(KDF, b"old", Padding::None, Layout::Tree)?;
+        let key_hash = config.derive_key_hash(b"old")?;
+        assert_eq!(MASTER_KEY_LEN, key_hash.len());
+        assert_eq!(
+            ErrorKind::InvalidData,
+            config.derive_key_hash(b"new").unwrap_err().kind()
+        );
+
+        let mut changed = config.clone();
+        changed.change_key(b"old", b"new")?;
+        assert_ne!(config.slots[0].wrapped_key, changed.slots[0].wrapped_key);
+        assert_eq!(key_hash, changed.derive_key_hash(b"new")?);
+        assert!(changed.derive_key_hash(b"old").is_err());
+        assert!(changed.change_key(b"wrong", b"new").is_err());
+        assert!(RepoConfig::legacy().change_key(b"old", b"new").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn every_slot_unlocks_the_same_master_key() -> Result<(), Error> {
+        let mut config = RepoConfig::new(KDF, b"alice", Padding::None, Layout::Tree)?;
+        let key_hash = config.derive_key_hash(b"alice")?;
+        assert_eq!(1, config.add_slot(b"alice", b"bob", "bob")?);
+        assert_eq!(2, config.add_slot(b"bob", b"carol", "")?);
+        assert!(config.add_slot(b"mallory", b"mallory", "").is_err());
+
+        for key in [&b"alice"[..], b"bob", b"carol"].iter() {
+            assert_eq!(key_hash, config.derive_key_hash(key)?);
+        }
+
+        config.remove_slot(b"alice", 1)?;
+        assert!(config.derive_key_hash(b"bob").is_err());
+        assert_eq!(
+            ErrorKind::NotFound,
+            config.remove_slot(b"alice", 1).unwrap_err().kind()
+        );
+        config.remove_slot(b"carol", 0)?;
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            config.remove_slot(b"carol", 2).unwrap_err().kind()
+        );
+        assert_eq!(key_hash, config.derive_key_hash(b"carol")?);
+        Ok(())
+    }
+
+    #[test]
+    fn rekey_gets_a_new_master_key() -> Result<(), Error> {
+        let mut config = RepoConfig::new(KDF, b"alice", Padding::Padme, Layout::Buckets)?;
+        config.add_slot(b"alice", b"bob", "bob")?;
+        let key_hash = config.derive_key_hash(b"alice")?;
+
+        let rekeyed = config.rekey(b"bob", b"new")?;
+        assert_eq!(1, rekeyed.slots.len());
+        assert_eq!(
+            (1, "bob"),
+            (rekeyed.slots[0].id, &rekeyed.slots[0].label[..])
+        );
+        assert_ne!(key_hash, rekeyed.derive_key_hash(b"new")?);
+        assert!(rekeyed.derive_key_hash(b"alice").is_err());
+        assert_eq!(Padding::Padme, rekeyed.padding);
+        assert_eq!(Layout::Buckets, rekeyed.layout);
+        assert!(config.rekey(b"mallory", b"new").is_err());
+
+        // trees that predate master keys get one
+        let rekeyed = RepoConfig::legacy().rekey(b"old", b"new")?;
+        assert_eq!(Kdf::default(), rekeyed.slots[0].kdf);
+        assert!(rekeyed.slots[0].wrapped_key.is_some());
+        assert_eq!(NameCipher::AesSiv, rekeyed.names);
+        assert_eq!(ContentCipher::Sealed, rekeyed.contents);
+        assert_eq!(KeySchedule::Hkdf, rekeyed.keys);
+        Ok(())
+    }
+
+    #[test]
+    fn cheap_kdfs_are_rejected() -> Result<(), Error> {
+        let out_dir = mktemp_dir("", "", None)?;
+        let mut config = RepoConfig::init(
+            out_dir.path(),
+            KDF,
+            b"password",
+            Padding::None,
+            Layout::Tree,
+        )?;
+        config.slots[0].kdf = Kdf::Pbkdf2Sha512 { num_iter: 1 };
+        config.store(out_dir.path())?;
+        assert_eq!(
+            ErrorKind::InvalidData,
+            RepoConfig::load(out_dir.path()).unwrap_err().kind()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn configs_without_a_mac_are_rejected() -> Result<(), Error> {
+        let out_dir = mktemp_dir("", "", None)?;
+        let config = RepoConfig::init(
+            out_dir.path(),
+            KDF,
+            b"password",
+            Padding::None,
+            Layout::Tree,
+        )?;
+        let stripped = RepoConfig {
+            mac: String::new(),
+            ..config
+        };
+        stripped.store(out_dir.path())?;
+        assert_eq!(
+            ErrorKind::InvalidData,
+            RepoConfig::load(out_dir.path()).unwrap_err().kind()
+        );
+        assert_eq!(
+            ErrorKind::InvalidData,
+            RepoConfig::load_or_init(out_dir.path(), b"password", true)
+                .unwrap_err()
+                .kind()
+        );
+
+        // not even if it is `legacy` in all but its layout
+        let reformatted = serde_json::to_vec(&RepoConfig::legacy()).map_err(io_err)?;
+        std::fs::write(config_path(out_dir.path()), reformatted)?;
+        assert!(RepoConfig::load(out_dir.path()).is_err());
+
+        RepoConfig::legacy().store(out_dir.path())?;
+        assert_eq!(
+            Some(RepoConfig::legacy()),
+            RepoConfig::load(out_dir.path())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn tampered_configs_are_rejected() -> Result<(), Error> {
+        let config = RepoConfig::new(KDF, b"password", Padding::Padme, Layout::Buckets)?;
+        config.derive_keys(b"password")?;
+
+        let tampered = [
+            RepoConfig {
+                names: NameCipher::AesCfb,
+                ..config.clone()
+            },
+            RepoConfig {
+                keys: KeySchedule::Direct,
+                ..config.clone()
+            },
+            RepoConfig {
+                padding: Padding::None,
+                ..config.clone()
+            },
+            RepoConfig {
+                layout: Layout::Tree,
+                ..config.clone()
+            },
+            RepoConfig {
+                mac: String::new(),
+                ..config.clone()
+            },
+            // posing as `legacy`, but for the master key
+            RepoConfig {
+                slots: config.slots.clone(),
+                ..RepoConfig::legacy()
+            },
+        ];
+        for config in tampered.iter() {
+            assert_eq!(
+                ErrorKind::InvalidData,
+                config.derive_keys(b"password").unwrap_err().kind()
+            );
+        }
+
+        // every change made with the key keeps the config authentic
+        let mut config = config;
+        config.add_slot(b"password", b"other", "")?;
+        config.change_key(b"other", b"another")?;
+        config.remove_slot(b"password", 0)?;
+        config.derive_keys(b"another")?;
+        config.rekey(b"another", b"new")?.derive_keys(b"new")?;
+        Ok(())
+    }
+
+    #[test]
+    fn trees_without_a_config_are_legacy() -> Result<(), Error> {
+        assert_eq!(
+            hash_custom(b"password", None, None),
+            RepoConfig::legacy().derive_key_hash(b"password")?
+        );
+
+        let out_dir = mktemp_dir("", "", None)?;
+        create_dir_all(out_dir.path().join(METADATA_DIR))?;
+        std::fs::write(out_dir.path().join(METADATA_DIR).join(MANIFEST_FILE), "")?;
+        assert_eq!(
+            RepoConfig::legacy(),
+            RepoConfig::load_or_init(out_dir.path(), b"password", true)?
+        );
+        assert_eq!(None, RepoConfig::load(out_dir.path())?);
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::crypt::manifest::*;
+
+/// A path in `source` that a sync touches.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlannedPath {
+    pub path: PathBuf, // plaintext, relative to the parent of `source`
+    // ciphertext, relative to `out_dir`; empty for directories in `Layout::Buckets`, which only
+    // exist in the index
+    pub target: PathBuf,
+    pub size: u64, // bytes to encrypt, for files
+}
+
+/// A path in `out_dir` that a sync removes.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PlannedDeletion {
+    // plaintext, relative to the parent of `source`; `None` if the ciphertext cannot be decrypted,
+    // e.g. because it is not a ciphertext at all
+    pub path: Option<PathBuf>,
+    pub target: PathBuf, // ciphertext, relative to `out_dir`; empty, as above, for directories
+    pub size: u64,       // bytes freed, counting everything below `target`
+}
+
+/// Everything a sync would do, worked out without writing anything, so that it can be reviewed
+/// before it is applied with `CryptSyncer::apply`.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct SyncPlan {
+    pub create_dirs: Vec<PlannedPath>,
+    pub add: Vec<PlannedPath>,    // files with no ciphertext yet
+    pub update: Vec<PlannedPath>, // files whose ciphertext is out of date
+    pub delete: Vec<PlannedDeletion>,
+
+    // the plaintext roots of the subtrees that were planned, relative to the parent of `source`;
+    // their manifest entries are replaced by `entries` once the plan is applied
+    pub(crate) scope: Vec<PathBuf>,
+    pub(crate) entries: HashMap<PathBuf, ManifestEntry>,
+}
+
+impl SyncPlan {
+    /// Sort everything by plaintext path, so that plans are the same no matter in which order the
+    /// paths were found.
+    pub fn sort(&mut self) {
+        self.create_dirs.sort_by(|a, b| a.path.cmp(&b.path));
+        self.add.sort_by(|a, b| a.path.cmp(&b.path));
+        self.update.sort_by(|a, b| a.path.cmp(&b.path));
+        self.delete
+            .sort_by(|a, b| (&a.path, &a.target).cmp(&(&b.path, &b.target)));
+    }
+
+    /// # Returns
+    ///
+    /// The number of plaintext bytes that would be encrypted.
+    pub fn bytes_to_encrypt(&self) -> u64 {
+        self.add
+            .iter()
+            .chain(&self.update)
+            .map(|file| file.size)
+            .sum()
+    }
+
+    /// # Returns
+    ///
+    /// The number of ciphertext bytes that would be removed.
+    pub fn bytes_to_delete(&self) -> u64 {
+        self.delete.iter().map(|deletion| deletion.size).sum()
+    }
+}
+
+impl fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for dir in &self.create_dirs {
+            writeln!(f, "mkdir   {}", dir.path.display())?;
+        }
+        for file in &self.add {
+            writeln!(f, "add     {} ({} bytes)", file.path.display(), file.size)?;
+        }
+        for file in &self.update {
+            writeln!(f, "update  {} ({} bytes)", file.path.display(), file.size)?;
+        }
+        for deletion in &self.delete {
+            match &deletion.path {
+                Some(path) => writeln!(f, "delete  {} ({} bytes)", path.display(), deletion.size)?,
+                None => writeln!(
+                    f,
+                    "delete  {} ({} bytes, cannot be decrypted)",
+                    deletion.target.display(),
+                    deletion.size
+                )?,
+            }
+        }
+
+        write!(
+            f,
+            "{} dir(s) to create, {} file(s) to add, {} file(s) to update, {} path(s) to delete; \
+             {} bytes to encrypt, {} bytes to delete",
+            self.create_dirs.len(),
+            self.add.len(),
+            self.update.len(),
+            self.delete.len(),
+            self.bytes_to_encrypt(),
+            self.bytes_to_delete()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_lists_every_change() {
+        let planned = |path: &str, size: u64| PlannedPath {
+            path: PathBuf::from(path),
+            target: PathBuf::from("TARGET"),
+            size,
+        };
+        let plan = SyncPlan {
+            create_dirs: vec![planned("source/dir", 0)],
+            add: vec![planned("source/dir/added", 3)],
+            update: vec![planned("source/updated", 4)],
+            delete: vec![
+                PlannedDeletion {
+                    path: Some(PathBuf::from("source/deleted")),
+                    target: PathBuf::from("TARGET"),
+                    size: 5,
+                },
+                PlannedDeletion {
+                    path: None,
+                    target: PathBuf::from("TARGET/JUNK"),
+                    size: 6,
+                },
+            ],
+            ..SyncPlan::default()
+        };
+
+        let expected = "mkdir   source/dir\n\
+                        add     source/dir/added (3 bytes)\n\
+                        update  source/updated (4 bytes)\n\
+                        delete  source/deleted (5 bytes)\n\
+                        delete  TARGET/JUNK (6 bytes, cannot be decrypted)\n\
+                        1 dir(s) to create, 1 file(s) to add, 1 file(s) to update, \
+                        2 path(s) to delete; 7 bytes to encrypt, 11 bytes to delete";
+        assert_eq!(expected, plan.to_string());
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::fs::write;
+use std::io::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+use crate::crypt::crypt_restorer::*;
+use crate::crypt::crypt_syncer::*;
+use crate::crypt::index::*;
+use crate::crypt::key_schedule::*;
+use crate::crypt::name_cipher::*;
+use crate::hasher::*;
+use crate::util::*;
+
+/// # Returns
+///
+/// The master key that tests encrypt under, hashed from a fixed password, cheaply.
+pub fn test_key_hash() -> Vec<u8> {
+    hash_custom("aoisjfk1".as_bytes(), None, Some(1 << 8))
+}
+
+/// # Returns
+///
+/// The keys that `schedule` derives from `test_key_hash`.
+pub fn test_keys(schedule: KeySchedule) -> Keys {
+    Keys::derive(&test_key_hash(), schedule).unwrap()
+}
+
+/// Create a tree named `source` in a new temp dir.
+///
+/// # Parameters
+///
+/// 1. `entries`: every path in the tree, relative to it, along with its content, or `None` if it
+///    is a directory; parents are created as needed
+///
+/// # Returns
+///
+/// The temp dir, which the tree goes along with, and the path of the tree.
+pub fn test_source(entries: &[(&str, Option<&str>)]) -> Result<(TempDir, PathBuf), Error> {
+    let src_dir = mktemp_dir("", "", None)?;
+    let source = src_dir.path().join("source");
+    create_dir_all(&source)?;
+    for (rel_path, opt_content) in entries {
+        let path = source.join(rel_path);
+        match opt_content {
+            Some(content) => {
+                create_dir_all(path.parent().unwrap())?;
+                write(&path, content)?;
+            }
+            None => create_dir_all(&path)?,
+        }
+    }
+    Ok((src_dir, source))
+}
+
+/// Sync `source` into a new temp dir, laid out like a tree.
+pub fn synced(source: &Path, keys: &Keys) -> Result<TempDir, Error> {
+    let out_dir = mktemp_dir("", "", None)?;
+    CryptSyncer::new(source, out_dir.path())?.sync(keys)?;
+    Ok(out_dir)
+}
+
+/// # Returns
+///
+/// The ciphertext of `rel_path`, a path in the tree created by `test_source`, relative to that
+/// tree, in the tree layout.
+pub fn target(out_dir: &Path, rel_path: &str, keys: &Keys) -> PathBuf {
+    let rel_path = Path::new("source").join(rel_path);
+    out_dir.join(path_ciphertext(&rel_path, keys, NameCipher::AesSiv).unwrap())
+}
+
+/// # Returns
+///
+/// A mapping from each path in `root`, relative to `root`, to its content, or `None` if it is
+/// a directory.
+pub fn read_tree(root: &Path) -> HashMap<PathBuf, Option<Vec<u8>>> {
+    find(root)
+        .map(Result::unwrap)
+        .map(|path| {
+            let content = match path.is_file() {
+                true => Some(std::fs::read(&path).unwrap()),
+                false => None,
+            };
+            (path.strip_prefix(root).unwrap().to_path_buf(), content)
+        })
+        .collect()
+}
+
+/// Restore the encrypted tree in `out_dir` into a new temp dir, and check that it is the same as
+/// `source`, the tree created by `test_source`.
+pub fn assert_restores(
+    source: &Path,
+    out_dir: &Path,
+    keys: &Keys,
+    layout: Layout,
+) -> Result<(), Error> {
+    let plain_dir = mktemp_dir("", "", None)?;
+    CryptRestorer::new(out_dir, plain_dir.path())?
+        .with_layout(layout)
+        .restore(keys)?;
+    assert_eq!(
+        read_tree(source),
+        read_tree(&plain_dir.path().join("source"))
+    );
+    Ok(())
+}
@@ -1,24 +1,64 @@
+use openssl::derive::Deriver;
+use openssl::memcmp;
+use openssl::pkey::Id;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::symm::decrypt_aead;
+use openssl::symm::encrypt_aead;
 use openssl::symm::Cipher;
 use openssl::symm::Crypter;
 use openssl::symm::Mode;
-use rayon::iter::ParallelBridge;
-use rayon::prelude::*;
+use ring::hkdf;
+use std::cmp::min;
 use std::io::Bytes;
 use std::io::Error;
 use std::io::ErrorKind;
 use std::io::Read;
 
 use crate::crypt::crypt_encoder::*;
-use crate::encoder::text_encoder::*;
-use crate::hasher::*;
 use crate::util::*;
 
 const INITIALIZATION_VECTOR: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
 
-/// create Encryptor and Decryptor, because they differ only by the
+/// Length of the random nonce that starts every ciphertext of `Encryptor`.
+pub const NONCE_LEN: usize = 12;
+
+/// Length of the authentication tag that ends every ciphertext of `Encryptor`.
+pub const TAG_LEN: usize = 16;
+
+/// Length of the plaintext of every segment of `StreamEncryptor` but the last.
+pub const SEGMENT_LEN: usize = 1 << 16;
+
+/// Length of the synthetic IV that starts every ciphertext of `SivEncryptor`.
+pub const SIV_LEN: usize = 16;
+
+/// Length of the key of `SivEncryptor`, i.e. two AES-256 keys, one for S2V and one for CTR.
+pub const SIV_KEY_LEN: usize = 64;
+
+/// Length of X25519 public and secret keys.
+pub const X25519_KEY_LEN: usize = 32;
+
+// how much to read from the source at a time
+const BUFFER_SIZE: usize = 4096;
+
+// length of the random key that the content of a file is encrypted with by `RecipientEncryptor`
+const FILE_KEY_LEN: usize = 32;
+
+// label of the key that wraps the file key for a single recipient
+const RECIPIENT_KDF_INFO: &[u8] = b"csync x25519 file key";
+
+// label of the key that every `StreamEncryptor` ciphertext is encrypted under
+const SEGMENT_KDF_INFO: &[u8] = b"csync stream segment key";
+
+/// create CfbEncryptor and CfbDecryptor, because they differ only by the
 /// struct name and the openssl::symm::Mode that is used
+///
+/// these are deterministic, i.e. the same plaintext always results in the same
+/// ciphertext, which is what encrypted basenames need but file contents must
+/// not have; see `Encryptor` for those
 macro_rules! cryptor {
-    // `$struct_name` => Encryptor | Decryptor | ..
+    // `$struct_name` => CfbEncryptor | CfbDecryptor | ..
     // `$crypter_mode` => MODE::Encrypt | MODE::Decrypt
     ( $struct_name:ident, $crypter_mode:expr ) => {
         pub struct $struct_name<R>
@@ -95,9 +135,941 @@ macro_rules! cryptor {
     };
 }
 
-cryptor!(Encryptor, Mode::Encrypt);
+cryptor!(CfbEncryptor, Mode::Encrypt);
+
+cryptor!(CfbDecryptor, Mode::Decrypt);
+
+/// Encrypts with AES-256-GCM under a random nonce, so that encrypting the same plaintext twice
+/// never results in the same ciphertext, and any modification of the ciphertext is detected by
+/// `Decryptor`.
+///
+/// The ciphertext is laid out as `nonce || encrypted source || tag`.
+pub struct Encryptor<R>
+where
+    R: Read,
+{
+    done: bool,       // whether the tag has been produced
+    encoder: Crypter, // what does the actual work
+    pending: Vec<u8>, // output that has yet to be read
+    source: R,
+}
+
+impl<R> Encryptor<R>
+where
+    R: Read,
+{
+    /// # Parameters
+    ///
+    /// - `source`: some struct that impls `std::io::Read` that this struct wraps around
+    /// - `key_hash`: hash whose first 32 bytes are used as the key
+    pub fn new(source: R, key_hash: &[u8]) -> Result<Self, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce).map_err(io_err)?;
+        Self::with_prefix(source, key_hash, &nonce, &nonce)
+    }
+
+    /// Like `new`, but with the given nonce, and with `prefix` in its place at the start of the
+    /// ciphertext; `prefix` is authenticated along with the rest, e.g. a header that holds the
+    /// nonce among other things.
+    ///
+    /// # Parameters
+    ///
+    /// - `source`: some struct that impls `std::io::Read` that this struct wraps around
+    /// - `key_hash`: hash whose first 32 bytes are used as the key
+    /// - `nonce`: `NONCE_LEN` bytes that must never be used twice with the same key
+    /// - `prefix`: bytes to put before the ciphertext
+    pub fn with_prefix(
+        source: R,
+        key_hash: &[u8],
+        nonce: &[u8],
+        prefix: &[u8],
+    ) -> Result<Self, Error> {
+        assert!(key_hash.len() >= 32);
+        assert_eq!(NONCE_LEN, nonce.len());
+
+        let mut encoder = Crypter::new(
+            Cipher::aes_256_gcm(),
+            Mode::Encrypt,
+            &key_hash[..32],
+            Some(nonce),
+        )
+        .map_err(io_err)?;
+        encoder.aad_update(prefix).map_err(io_err)?;
+        Ok(Self {
+            done: false,
+            encoder,
+            pending: prefix.to_vec(),
+            source,
+        })
+    }
+}
+
+impl<R> Read for Encryptor<R>
+where
+    R: Read,
+{
+    fn read(&mut self, target: &mut [u8]) -> Result<usize, Error> {
+        let mut buffer = [0u8; BUFFER_SIZE];
+        while self.pending.is_empty() && !self.done {
+            let mut output = vec![0u8; BUFFER_SIZE + Cipher::aes_256_gcm().block_size()];
+            let num_output = match self.source.read(&mut buffer)? {
+                0 => {
+                    let num_output = self.encoder.finalize(&mut output).map_err(io_err)?;
+                    let mut tag = [0u8; TAG_LEN];
+                    self.encoder.get_tag(&mut tag).map_err(io_err)?;
+                    output.truncate(num_output);
+                    output.extend_from_slice(&tag);
+                    self.done = true;
+                    output.len()
+                }
+                num_read => self
+                    .encoder
+                    .update(&buffer[..num_read], &mut output)
+                    .map_err(io_err)?,
+            };
+            self.pending.extend_from_slice(&output[..num_output]);
+        }
+
+        Ok(drain_into(&mut self.pending, target))
+    }
+}
+
+impl<R> CryptEncoder<R> for Encryptor<R> where R: Read {}
+
+/// Inverse of `Encryptor`, which fails with `ErrorKind::InvalidData` once it reaches the end of
+/// its source if the ciphertext was modified in any way, or the key is wrong.
+///
+/// The plaintext is produced before the tag at the very end can be checked, so it must not be
+/// trusted until the source has been read to the end without errors.
+pub struct Decryptor<R>
+where
+    R: Read,
+{
+    decoder: Crypter, // what does the actual work
+    done: bool,       // whether the tag has been checked
+    pending: Vec<u8>, // output that has yet to be read
+    source: R,
+    tail: Vec<u8>, // the last bytes read, which may turn out to be the tag
+}
 
-cryptor!(Decryptor, Mode::Decrypt);
+impl<R> Decryptor<R>
+where
+    R: Read,
+{
+    /// # Parameters
+    ///
+    /// - `source`: some struct that impls `std::io::Read` that this struct wraps around
+    /// - `key_hash`: hash whose first 32 bytes are used as the key
+    pub fn new(mut source: R, key_hash: &[u8]) -> Result<Self, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        source
+            .read_exact(&mut nonce)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "the ciphertext is truncated"))?;
+        Self::with_prefix(source, key_hash, &nonce, &nonce)
+    }
+
+    /// Like `new`, but for a ciphertext made by `Encryptor::with_prefix`, whose prefix has
+    /// already been read from `source`.
+    ///
+    /// # Parameters
+    ///
+    /// - `source`: what is left of the ciphertext after the prefix
+    /// - `key_hash`: hash whose first 32 bytes are used as the key
+    /// - `nonce`: the nonce it was encrypted with
+    /// - `prefix`: the bytes that were put before the ciphertext
+    pub fn with_prefix(
+        source: R,
+        key_hash: &[u8],
+        nonce: &[u8],
+        prefix: &[u8],
+    ) -> Result<Self, Error> {
+        assert!(key_hash.len() >= 32);
+        assert_eq!(NONCE_LEN, nonce.len());
+
+        let mut decoder = Crypter::new(
+            Cipher::aes_256_gcm(),
+            Mode::Decrypt,
+            &key_hash[..32],
+            Some(nonce),
+        )
+        .map_err(io_err)?;
+        decoder.aad_update(prefix).map_err(io_err)?;
+        Ok(Self {
+            decoder,
+            done: false,
+            pending: Vec::new(),
+            source,
+            tail: Vec::with_capacity(BUFFER_SIZE + TAG_LEN),
+        })
+    }
+}
+
+impl<R> Read for Decryptor<R>
+where
+    R: Read,
+{
+    fn read(&mut self, target: &mut [u8]) -> Result<usize, Error> {
+        let mut buffer = [0u8; BUFFER_SIZE];
+        while self.pending.is_empty() && !self.done {
+            let mut output = vec![0u8; BUFFER_SIZE + TAG_LEN + Cipher::aes_256_gcm().block_size()];
+            let num_output = match self.source.read(&mut buffer)? {
+                0 => {
+                    self.done = true;
+                    let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message);
+                    if self.tail.len() < TAG_LEN {
+                        return Err(invalid("the ciphertext is truncated"));
+                    }
+                    self.decoder.set_tag(&self.tail).map_err(io_err)?;
+                    self.decoder.finalize(&mut output).map_err(|_| {
+                        invalid("the ciphertext was modified, or the password is wrong")
+                    })?
+                }
+                num_read => {
+                    // never decrypt the last `TAG_LEN` bytes seen so far, as they may be the tag
+                    self.tail.extend_from_slice(&buffer[..num_read]);
+                    let num_ready = self.tail.len().saturating_sub(TAG_LEN);
+                    let ready: Vec<u8> = self.tail.drain(..num_ready).collect();
+                    self.decoder.update(&ready, &mut output).map_err(io_err)?
+                }
+            };
+            self.pending.extend_from_slice(&output[..num_output]);
+        }
+
+        Ok(drain_into(&mut self.pending, target))
+    }
+}
+
+impl<R> CryptEncoder<R> for Decryptor<R> where R: Read {}
+
+/// Encrypts with AES-256-GCM in segments of `SEGMENT_LEN` bytes, following the STREAM
+/// construction, so that `StreamDecryptor` can check every segment as soon as it has read it, with
+/// constant memory, rather than only at the very end like `Decryptor`.
+///
+/// Every ciphertext is encrypted under its own key, derived from the key and the nonce, and every
+/// segment under a nonce made of its index and whether it is the last one, so truncating,
+/// reordering, or splicing segments, within a ciphertext or across ciphertexts, is detected.
+///
+/// The ciphertext is laid out as `nonce || (encrypted segment || tag)...`, where only the last
+/// segment is shorter than `SEGMENT_LEN`, or as long but marked last; an empty source is a single
+/// empty last segment.
+pub struct StreamEncryptor<R>
+where
+    R: Read,
+{
+    done: bool,       // whether the last segment has been produced
+    index: u64,       // of the next segment
+    key: Vec<u8>,     // of this ciphertext alone
+    pending: Vec<u8>, // output that has yet to be read
+    prefix: Vec<u8>,  // authenticated along with every segment
+    source: R,
+    unread: Vec<u8>, // plaintext of the next segments, read ahead to find the last one
+}
+
+impl<R> StreamEncryptor<R>
+where
+    R: Read,
+{
+    /// # Parameters
+    ///
+    /// - `source`: some struct that impls `std::io::Read` that this struct wraps around
+    /// - `key_hash`: hash whose first 32 bytes are used as the key
+    #[allow(dead_code)]
+    pub fn new(source: R, key_hash: &[u8]) -> Result<Self, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce).map_err(io_err)?;
+        Self::with_prefix(source, key_hash, &nonce, &nonce)
+    }
+
+    /// Like `new`, but with the given nonce, and with `prefix` in its place at the start of the
+    /// ciphertext, just like `Encryptor::with_prefix`.
+    pub fn with_prefix(
+        source: R,
+        key_hash: &[u8],
+        nonce: &[u8],
+        prefix: &[u8],
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            done: false,
+            index: 0,
+            key: segment_key(key_hash, nonce)?,
+            pending: prefix.to_vec(),
+            prefix: prefix.to_vec(),
+            source,
+            unread: Vec::with_capacity(SEGMENT_LEN + BUFFER_SIZE),
+        })
+    }
+}
+
+impl<R> Read for StreamEncryptor<R>
+where
+    R: Read,
+{
+    fn read(&mut self, target: &mut [u8]) -> Result<usize, Error> {
+        while self.pending.is_empty() && !self.done {
+            // a segment is the last one if nothing follows it
+            let is_last = !fill_past(&mut self.source, &mut self.unread, SEGMENT_LEN)?;
+            let segment: Vec<u8> = match is_last {
+                true => self.unread.drain(..).collect(),
+                false => self.unread.drain(..SEGMENT_LEN).collect(),
+            };
+
+            let mut tag = [0u8; TAG_LEN];
+            let ciphertext = encrypt_aead(
+                Cipher::aes_256_gcm(),
+                &self.key,
+                Some(&segment_nonce(self.index, is_last)),
+                &self.prefix,
+                &segment,
+                &mut tag,
+            )
+            .map_err(io_err)?;
+            self.pending.extend_from_slice(&ciphertext);
+            self.pending.extend_from_slice(&tag);
+            self.index += 1;
+            self.done = is_last;
+        }
+
+        Ok(drain_into(&mut self.pending, target))
+    }
+}
+
+impl<R> CryptEncoder<R> for StreamEncryptor<R> where R: Read {}
+
+/// Inverse of `StreamEncryptor`, which fails with `ErrorKind::InvalidData` as soon as it reaches a
+/// segment that was modified, moved or cut short, or if the key is wrong.
+///
+/// Every segment is checked before any of its plaintext is produced, so what has been read without
+/// errors can be trusted, but only reading to the end without errors shows that nothing is missing.
+pub struct StreamDecryptor<R>
+where
+    R: Read,
+{
+    done: bool,       // whether the last segment has been checked
+    index: u64,       // of the next segment
+    key: Vec<u8>,     // of this ciphertext alone
+    pending: Vec<u8>, // output that has yet to be read
+    prefix: Vec<u8>,  // authenticated along with every segment
+    source: R,
+    unread: Vec<u8>, // ciphertext of the next segments, read ahead to find the last one
+}
+
+impl<R> StreamDecryptor<R>
+where
+    R: Read,
+{
+    /// # Parameters
+    ///
+    /// - `source`: some struct that impls `std::io::Read` that this struct wraps around
+    /// - `key_hash`: hash whose first 32 bytes are used as the key
+    #[allow(dead_code)]
+    pub fn new(mut source: R, key_hash: &[u8]) -> Result<Self, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        read_exact_or_truncated(&mut source, &mut nonce)?;
+        Self::with_prefix(source, key_hash, &nonce, &nonce)
+    }
+
+    /// Like `new`, but for a ciphertext made by `StreamEncryptor::with_prefix`, whose prefix has
+    /// already been read from `source`.
+    pub fn with_prefix(
+        source: R,
+        key_hash: &[u8],
+        nonce: &[u8],
+        prefix: &[u8],
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            done: false,
+            index: 0,
+            key: segment_key(key_hash, nonce)?,
+            pending: Vec::new(),
+            prefix: prefix.to_vec(),
+            source,
+            unread: Vec::with_capacity(SEGMENT_LEN + TAG_LEN + BUFFER_SIZE),
+        })
+    }
+}
+
+impl<R> Read for StreamDecryptor<R>
+where
+    R: Read,
+{
+    fn read(&mut self, target: &mut [u8]) -> Result<usize, Error> {
+        const ENCRYPTED_SEGMENT_LEN: usize = SEGMENT_LEN + TAG_LEN;
+        while self.pending.is_empty() && !self.done {
+            let is_last = !fill_past(&mut self.source, &mut self.unread, ENCRYPTED_SEGMENT_LEN)?;
+            let segment: Vec<u8> = match is_last {
+                true => self.unread.drain(..).collect(),
+                false => self.unread.drain(..ENCRYPTED_SEGMENT_LEN).collect(),
+            };
+            if segment.len() < TAG_LEN {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "the ciphertext is truncated",
+                ));
+            }
+
+            let (ciphertext, tag) = segment.split_at(segment.len() - TAG_LEN);
+            let plaintext = decrypt_aead(
+                Cipher::aes_256_gcm(),
+                &self.key,
+                Some(&segment_nonce(self.index, is_last)),
+                &self.prefix,
+                ciphertext,
+                tag,
+            )
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "segment {} of the ciphertext was modified, moved or cut short, or the \
+                         password is wrong",
+                        self.index
+                    ),
+                )
+            })?;
+            self.pending = plaintext;
+            self.index += 1;
+            self.done = is_last;
+        }
+
+        Ok(drain_into(&mut self.pending, target))
+    }
+}
+
+impl<R> CryptEncoder<R> for StreamDecryptor<R> where R: Read {}
+
+/// Derive the key of a single `StreamEncryptor` ciphertext, so that no two ciphertexts share one,
+/// however many segments they have.
+fn segment_key(key_hash: &[u8], nonce: &[u8]) -> Result<Vec<u8>, Error> {
+    assert!(key_hash.len() >= 32);
+    assert_eq!(NONCE_LEN, nonce.len());
+
+    let mut key = vec![0u8; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, nonce)
+        .extract(&key_hash[..32])
+        .expand(&[SEGMENT_KDF_INFO], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut key))
+        .map_err(|_| err!("failed to derive the key of a ciphertext"))?;
+    Ok(key)
+}
+
+/// # Returns
+///
+/// The nonce of segment `index`, laid out as `0 || index || is_last`.
+fn segment_nonce(index: u64, is_last: bool) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[NONCE_LEN - 9..NONCE_LEN - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = is_last as u8;
+    nonce
+}
+
+/// Read from `source` into `buffer` until it holds more than `len` bytes, or `source` ends.
+///
+/// # Returns
+///
+/// Whether `buffer` holds more than `len` bytes, i.e. whether more follows its first `len` bytes.
+fn fill_past<R>(source: &mut R, buffer: &mut Vec<u8>, len: usize) -> Result<bool, Error>
+where
+    R: Read,
+{
+    let mut chunk = [0u8; BUFFER_SIZE];
+    while buffer.len() <= len {
+        match source.read(&mut chunk)? {
+            0 => return Ok(false),
+            num_read => buffer.extend_from_slice(&chunk[..num_read]),
+        }
+    }
+    Ok(true)
+}
+
+/// Encrypts deterministically with AES-SIV, as in RFC 5297, so that the same plaintext always
+/// results in the same ciphertext, like `CfbEncryptor`, but any modification of the ciphertext is
+/// detected by `SivDecryptor`, and plaintexts that share a prefix have unrelated ciphertexts.
+///
+/// The whole source is read before anything is produced, so this is meant for short plaintexts,
+/// e.g. basenames. The ciphertext is laid out as `synthetic IV || encrypted source`.
+pub struct SivEncryptor<R>
+where
+    R: Read,
+{
+    key: Vec<u8>,
+    pending: Option<Vec<u8>>, // output that has yet to be read, once the source has been
+    source: R,
+}
+
+impl<R> SivEncryptor<R>
+where
+    R: Read,
+{
+    /// # Parameters
+    ///
+    /// - `source`: some struct that impls `std::io::Read` that this struct wraps around
+    /// - `key_hash`: hash whose first `SIV_KEY_LEN` bytes are used as the key
+    pub fn new(source: R, key_hash: &[u8]) -> Result<Self, Error> {
+        assert!(key_hash.len() >= SIV_KEY_LEN);
+        Ok(Self {
+            key: key_hash[..SIV_KEY_LEN].to_vec(),
+            pending: None,
+            source,
+        })
+    }
+}
+
+impl<R> Read for SivEncryptor<R>
+where
+    R: Read,
+{
+    fn read(&mut self, target: &mut [u8]) -> Result<usize, Error> {
+        if self.pending.is_none() {
+            let mut plaintext = Vec::new();
+            self.source.read_to_end(&mut plaintext)?;
+            self.pending = Some(siv_encrypt(&self.key, &[], &plaintext)?);
+        }
+        Ok(drain_into(self.pending.as_mut().unwrap(), target))
+    }
+}
+
+impl<R> CryptEncoder<R> for SivEncryptor<R> where R: Read {}
+
+/// Inverse of `SivEncryptor`, which fails with `ErrorKind::InvalidData` before producing anything
+/// if the ciphertext was modified in any way, or the key is wrong.
+pub struct SivDecryptor<R>
+where
+    R: Read,
+{
+    key: Vec<u8>,
+    pending: Option<Vec<u8>>, // output that has yet to be read, once the source has been
+    source: R,
+}
+
+impl<R> SivDecryptor<R>
+where
+    R: Read,
+{
+    /// # Parameters
+    ///
+    /// - `source`: some struct that impls `std::io::Read` that this struct wraps around
+    /// - `key_hash`: hash whose first `SIV_KEY_LEN` bytes are used as the key
+    pub fn new(source: R, key_hash: &[u8]) -> Result<Self, Error> {
+        assert!(key_hash.len() >= SIV_KEY_LEN);
+        Ok(Self {
+            key: key_hash[..SIV_KEY_LEN].to_vec(),
+            pending: None,
+            source,
+        })
+    }
+}
+
+impl<R> Read for SivDecryptor<R>
+where
+    R: Read,
+{
+    fn read(&mut self, target: &mut [u8]) -> Result<usize, Error> {
+        if self.pending.is_none() {
+            let mut ciphertext = Vec::new();
+            self.source.read_to_end(&mut ciphertext)?;
+            self.pending = Some(siv_decrypt(&self.key, &[], &ciphertext)?);
+        }
+        Ok(drain_into(self.pending.as_mut().unwrap(), target))
+    }
+}
+
+impl<R> CryptEncoder<R> for SivDecryptor<R> where R: Read {}
+
+/// AES-SIV encryption, as in RFC 5297; the first half of `key` is used for S2V, and the second for
+/// CTR, with AES-128 for 32-byte keys and AES-256 for 64-byte keys.
+///
+/// # Returns
+///
+/// `synthetic IV || ciphertext`
+fn siv_encrypt(key: &[u8], associated_data: &[&[u8]], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let (mac_key, ctr_key) = key.split_at(key.len() / 2);
+    let siv = s2v(mac_key, associated_data, plaintext)?;
+
+    let mut ciphertext = siv.to_vec();
+    ciphertext.extend_from_slice(&siv_ctr(ctr_key, &siv, plaintext)?);
+    Ok(ciphertext)
+}
+
+/// Inverse of `siv_encrypt`, which fails with `ErrorKind::InvalidData` if the synthetic IV does not
+/// match the plaintext.
+fn siv_decrypt(key: &[u8], associated_data: &[&[u8]], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "the ciphertext was modified");
+    if ciphertext.len() < SIV_LEN {
+        return Err(invalid());
+    }
+
+    let (mac_key, ctr_key) = key.split_at(key.len() / 2);
+    let (siv, ciphertext) = ciphertext.split_at(SIV_LEN);
+    let plaintext = siv_ctr(ctr_key, siv, ciphertext)?;
+    match memcmp::eq(&s2v(mac_key, associated_data, &plaintext)?, siv) {
+        true => Ok(plaintext),
+        false => Err(invalid()),
+    }
+}
+
+/// The S2V of RFC 5297, i.e. a MAC over a vector of strings, the last of which is `plaintext`.
+fn s2v(key: &[u8], associated_data: &[&[u8]], plaintext: &[u8]) -> Result<[u8; SIV_LEN], Error> {
+    let mut d = cmac(key, &[0u8; SIV_LEN])?;
+    for data in associated_data {
+        d = xor(&dbl(&d), &cmac(key, data)?);
+    }
+
+    let last = match plaintext.len() >= SIV_LEN {
+        true => {
+            // xor `d` into the last block
+            let mut last = plaintext.to_vec();
+            let start = last.len() - SIV_LEN;
+            last[start..]
+                .iter_mut()
+                .zip(d.iter())
+                .for_each(|(byte, d)| *byte ^= d);
+            last
+        }
+        false => {
+            let mut padded = [0u8; SIV_LEN];
+            padded[..plaintext.len()].copy_from_slice(plaintext);
+            padded[plaintext.len()] = 0x80;
+            xor(&dbl(&d), &padded).to_vec()
+        }
+    };
+    cmac(key, &last)
+}
+
+/// AES-CTR under `key`, starting at `siv` with the two bits that RFC 5297 clears.
+fn siv_ctr(key: &[u8], siv: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut counter = [0u8; SIV_LEN];
+    counter.copy_from_slice(siv);
+    counter[8] &= 0x7f;
+    counter[12] &= 0x7f;
+
+    let cipher = match key.len() {
+        16 => Cipher::aes_128_ctr(),
+        _ => Cipher::aes_256_ctr(),
+    };
+    let mut crypter = Crypter::new(cipher, Mode::Encrypt, key, Some(&counter)).map_err(io_err)?;
+    let mut output = vec![0u8; data.len() + cipher.block_size()];
+    let num_output = crypter.update(data, &mut output).map_err(io_err)?;
+    let num_final = crypter
+        .finalize(&mut output[num_output..])
+        .map_err(io_err)?;
+    output.truncate(num_output + num_final);
+    Ok(output)
+}
+
+fn cmac(key: &[u8], data: &[u8]) -> Result<[u8; SIV_LEN], Error> {
+    let cipher = match key.len() {
+        16 => Cipher::aes_128_cbc(),
+        _ => Cipher::aes_256_cbc(),
+    };
+    let pkey = PKey::cmac(&cipher, key).map_err(io_err)?;
+    let mut signer = Signer::new_without_digest(&pkey).map_err(io_err)?;
+    signer.update(data).map_err(io_err)?;
+
+    let mut mac = [0u8; SIV_LEN];
+    mac.copy_from_slice(&signer.sign_to_vec().map_err(io_err)?);
+    Ok(mac)
+}
+
+/// Doubling in GF(2^128), as defined in RFC 5297.
+fn dbl(block: &[u8; SIV_LEN]) -> [u8; SIV_LEN] {
+    let value = u128::from_be_bytes(*block);
+    let doubled = (value << 1) ^ if value >> 127 == 1 { 0x87 } else { 0 };
+    doubled.to_be_bytes()
+}
+
+fn xor(a: &[u8; SIV_LEN], b: &[u8; SIV_LEN]) -> [u8; SIV_LEN] {
+    let mut result = [0u8; SIV_LEN];
+    result
+        .iter_mut()
+        .zip(a.iter().zip(b.iter()))
+        .for_each(|(result, (a, b))| *result = a ^ b);
+    result
+}
+
+/// Encrypts to one or more X25519 public keys, so that encrypting needs no secret at all, and only
+/// the holders of the matching secret keys can decrypt.
+///
+/// The source is encrypted with `StreamEncryptor` under a random file key, which is wrapped for
+/// every recipient under a key agreed between a single ephemeral key pair and the recipient. The
+/// ciphertext is laid out as `nonce || ephemeral public key || number of recipients || wrapped file
+/// keys || (encrypted segment || tag)...`, where everything before the first segment is
+/// authenticated along with every segment, so each one is checked as soon as it has been read.
+/// Ciphertexts that predate segments have a single `encrypted source || tag` in their place.
+///
+/// Since anyone can encrypt to a public key, a ciphertext that decrypts fine says nothing about who
+/// made it.
+pub struct RecipientEncryptor<R>
+where
+    R: Read,
+{
+    encryptor: Content<Encryptor<R>, StreamEncryptor<R>>,
+}
+
+impl<R> RecipientEncryptor<R>
+where
+    R: Read,
+{
+    /// # Parameters
+    ///
+    /// - `source`: some struct that impls `std::io::Read` that this struct wraps around
+    /// - `recipients`: the public keys to encrypt to; at least 1 and at most 255
+    #[allow(dead_code)]
+    pub fn new(source: R, recipients: &[[u8; X25519_KEY_LEN]]) -> Result<Self, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce).map_err(io_err)?;
+        Self::with_prefix(source, recipients, &nonce, &nonce, true)
+    }
+
+    /// Like `new`, but with the given nonce, and with `prefix` in its place at the start of the
+    /// ciphertext, just like `Encryptor::with_prefix`.
+    ///
+    /// # Parameters
+    ///
+    /// - `segmented`: whether to encrypt the source with `StreamEncryptor`, rather than `Encryptor`
+    pub fn with_prefix(
+        source: R,
+        recipients: &[[u8; X25519_KEY_LEN]],
+        nonce: &[u8],
+        prefix: &[u8],
+        segmented: bool,
+    ) -> Result<Self, Error> {
+        if recipients.is_empty() || recipients.len() > u8::MAX as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot encrypt to {} recipients", recipients.len()),
+            ));
+        }
+
+        let mut file_key = [0u8; FILE_KEY_LEN];
+        rand_bytes(&mut file_key).map_err(io_err)?;
+        let (ephemeral_secret, ephemeral_public) = generate_x25519()?;
+
+        let mut stanzas = ephemeral_public.to_vec();
+        stanzas.push(recipients.len() as u8);
+        for recipient in recipients {
+            let wrapping_key =
+                recipient_key(&ephemeral_secret, recipient, &ephemeral_public, recipient)?;
+            let mut tag = [0u8; TAG_LEN];
+            let wrapped = encrypt_aead(
+                Cipher::aes_256_gcm(),
+                &wrapping_key,
+                Some(&[0u8; NONCE_LEN]), // every wrapping key is used only once
+                &[],
+                &file_key,
+                &mut tag,
+            )
+            .map_err(io_err)?;
+            stanzas.extend_from_slice(&wrapped);
+            stanzas.extend_from_slice(&tag);
+        }
+
+        let prefix = [prefix, &stanzas].concat();
+        Ok(Self {
+            encryptor: match segmented {
+                true => Content::Segmented(StreamEncryptor::with_prefix(
+                    source, &file_key, nonce, &prefix,
+                )?),
+                false => Content::Whole(Encryptor::with_prefix(source, &file_key, nonce, &prefix)?),
+            },
+        })
+    }
+}
+
+impl<R> Read for RecipientEncryptor<R>
+where
+    R: Read,
+{
+    fn read(&mut self, target: &mut [u8]) -> Result<usize, Error> {
+        self.encryptor.read(target)
+    }
+}
+
+impl<R> CryptEncoder<R> for RecipientEncryptor<R> where R: Read {}
+
+/// Inverse of `RecipientEncryptor`, for the holder of the secret key of one of the recipients.
+///
+/// Just like with `Decryptor`, the plaintext must not be trusted until the source has been read to
+/// the end without errors.
+pub struct RecipientDecryptor<R>
+where
+    R: Read,
+{
+    decryptor: Content<Decryptor<R>, StreamDecryptor<R>>,
+}
+
+impl<R> RecipientDecryptor<R>
+where
+    R: Read,
+{
+    /// # Parameters
+    ///
+    /// - `source`: some struct that impls `std::io::Read` that this struct wraps around
+    /// - `identity`: the secret key of one of the recipients
+    #[allow(dead_code)]
+    pub fn new(mut source: R, identity: &[u8; X25519_KEY_LEN]) -> Result<Self, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        read_exact_or_truncated(&mut source, &mut nonce)?;
+        Self::with_prefix(source, identity, &nonce, &nonce, true)
+    }
+
+    /// Like `new`, but for a ciphertext made by `RecipientEncryptor::with_prefix`, whose prefix
+    /// has already been read from `source`.
+    pub fn with_prefix(
+        mut source: R,
+        identity: &[u8; X25519_KEY_LEN],
+        nonce: &[u8],
+        prefix: &[u8],
+        segmented: bool,
+    ) -> Result<Self, Error> {
+        let mut ephemeral_public = [0u8; X25519_KEY_LEN];
+        let mut num_recipients = [0u8; 1];
+        read_exact_or_truncated(&mut source, &mut ephemeral_public)?;
+        read_exact_or_truncated(&mut source, &mut num_recipients)?;
+        let mut wrapped = vec![0u8; num_recipients[0] as usize * (FILE_KEY_LEN + TAG_LEN)];
+        read_exact_or_truncated(&mut source, &mut wrapped)?;
+
+        // the wrapped file keys say nothing about whom they are for, so try every one of them
+        let wrapping_key = recipient_key(
+            identity,
+            &ephemeral_public,
+            &ephemeral_public,
+            &public_key_of(identity)?,
+        )?;
+        let file_key = wrapped
+            .chunks(FILE_KEY_LEN + TAG_LEN)
+            .find_map(|stanza| {
+                decrypt_aead(
+                    Cipher::aes_256_gcm(),
+                    &wrapping_key,
+                    Some(&[0u8; NONCE_LEN]),
+                    &[],
+                    &stanza[..FILE_KEY_LEN],
+                    &stanza[FILE_KEY_LEN..],
+                )
+                .ok()
+            })
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "the ciphertext is not encrypted to this identity",
+                )
+            })?;
+
+        let stanzas = [&ephemeral_public[..], &num_recipients, &wrapped].concat();
+        let prefix = [prefix, &stanzas].concat();
+        Ok(Self {
+            decryptor: match segmented {
+                true => Content::Segmented(StreamDecryptor::with_prefix(
+                    source, &file_key, nonce, &prefix,
+                )?),
+                false => Content::Whole(Decryptor::with_prefix(source, &file_key, nonce, &prefix)?),
+            },
+        })
+    }
+}
+
+impl<R> Read for RecipientDecryptor<R>
+where
+    R: Read,
+{
+    fn read(&mut self, target: &mut [u8]) -> Result<usize, Error> {
+        self.decryptor.read(target)
+    }
+}
+
+impl<R> CryptEncoder<R> for RecipientDecryptor<R> where R: Read {}
+
+/// The content of a recipient ciphertext, under the file key, in either format.
+enum Content<W, S> {
+    Whole(W),     // `Encryptor` or `Decryptor`
+    Segmented(S), // `StreamEncryptor` or `StreamDecryptor`
+}
+
+impl<W, S> Read for Content<W, S>
+where
+    W: Read,
+    S: Read,
+{
+    fn read(&mut self, target: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Content::Whole(whole) => whole.read(target),
+            Content::Segmented(segmented) => segmented.read(target),
+        }
+    }
+}
+
+/// Generate a new X25519 key pair.
+///
+/// # Returns
+///
+/// The secret key, i.e. the identity that decrypts, and the public key, i.e. the recipient that is
+/// encrypted to.
+pub fn generate_x25519() -> Result<([u8; X25519_KEY_LEN], [u8; X25519_KEY_LEN]), Error> {
+    let secret_key = PKey::generate_x25519().map_err(io_err)?;
+    let mut secret = [0u8; X25519_KEY_LEN];
+    let mut public = [0u8; X25519_KEY_LEN];
+    secret.copy_from_slice(&secret_key.raw_private_key().map_err(io_err)?);
+    public.copy_from_slice(&secret_key.raw_public_key().map_err(io_err)?);
+    Ok((secret, public))
+}
+
+/// # Returns
+///
+/// The public key that belongs to the X25519 secret key `secret`.
+pub fn public_key_of(secret: &[u8; X25519_KEY_LEN]) -> Result<[u8; X25519_KEY_LEN], Error> {
+    let secret_key = PKey::private_key_from_raw_bytes(secret, Id::X25519).map_err(io_err)?;
+    let mut public = [0u8; X25519_KEY_LEN];
+    public.copy_from_slice(&secret_key.raw_public_key().map_err(io_err)?);
+    Ok(public)
+}
+
+/// Agree on the key that wraps the file key for `recipient`, which is the same from both ends, i.e.
+/// between the ephemeral secret key and the recipient, or between the secret key of the recipient
+/// and the ephemeral public key.
+fn recipient_key(
+    secret: &[u8; X25519_KEY_LEN],
+    peer: &[u8; X25519_KEY_LEN],
+    ephemeral_public: &[u8; X25519_KEY_LEN],
+    recipient: &[u8; X25519_KEY_LEN],
+) -> Result<[u8; FILE_KEY_LEN], Error> {
+    let secret_key = PKey::private_key_from_raw_bytes(secret, Id::X25519).map_err(io_err)?;
+    let peer_key = PKey::public_key_from_raw_bytes(peer, Id::X25519).map_err(io_err)?;
+
+    let mut deriver = Deriver::new(&secret_key).map_err(io_err)?;
+    deriver.set_peer(&peer_key).map_err(io_err)?;
+    let shared_secret = deriver.derive_to_vec().map_err(io_err)?;
+
+    let salt = [&ephemeral_public[..], &recipient[..]].concat();
+    let mut key = [0u8; FILE_KEY_LEN];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &salt)
+        .extract(&shared_secret)
+        .expand(&[RECIPIENT_KDF_INFO], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut key))
+        .map_err(|_| err!("failed to derive the key of a recipient"))?;
+    Ok(key)
+}
+
+#[inline]
+fn read_exact_or_truncated<R>(source: &mut R, buffer: &mut [u8]) -> Result<(), Error>
+where
+    R: Read,
+{
+    source
+        .read_exact(buffer)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "the ciphertext is truncated"))
+}
+
+/// Move as much of `pending` into `target` as fits.
+///
+/// # Returns
+///
+/// The number of bytes moved.
+fn drain_into(pending: &mut Vec<u8>, target: &mut [u8]) -> usize {
+    let num_bytes = min(pending.len(), target.len());
+    target[..num_bytes].copy_from_slice(&pending[..num_bytes]);
+    pending.drain(..num_bytes);
+    num_bytes
+}
 
 /// Compose multiple CryptEncoders, just like function composing.
 ///
@@ -134,6 +1106,10 @@ macro_rules! compose_encoders {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypt::test_util::*;
+    use crate::hasher::*;
+    use rayon::iter::ParallelBridge;
+    use rayon::prelude::*;
     use std::fs::File;
     use std::path::Path;
 
@@ -185,11 +1161,11 @@ mod tests {
         };
     }
 
-    encoder_pure!(encrypt_pure, Encryptor);
+    encoder_pure!(encrypt_pure, CfbEncryptor);
 
-    encoder_pure!(decrypt_pure, Decryptor);
+    encoder_pure!(decrypt_pure, CfbDecryptor);
 
-    encoder_pure!(identity_pure, Encryptor, Decryptor);
+    encoder_pure!(identity_pure, CfbEncryptor, CfbDecryptor);
 
     #[test]
     fn parametrized_encrypt() {
@@ -200,7 +1176,7 @@ mod tests {
 
                 let ciphertext = encrypt_pure(unhashed_key, data_bytes).unwrap();
                 assert_eq!(expected_ciphertext, ciphertext);
-                if data_bytes.len() > 0 {
+                if !data_bytes.is_empty() {
                     assert_ne!(data_bytes, &ciphertext[..]);
                 }
             });
@@ -212,7 +1188,7 @@ mod tests {
             .into_par_iter()
             .for_each(|(unhashed_key, data, expected_ciphertext)| {
                 let data_bytes = data.as_bytes();
-                if data_bytes.len() > 0 {
+                if !data_bytes.is_empty() {
                     assert_ne!(data_bytes, &expected_ciphertext[..]);
                 }
 
@@ -227,7 +1203,7 @@ mod tests {
             .into_par_iter()
             .for_each(|(unhashed_key, data, expected_ciphertext)| {
                 let data_bytes = data.as_bytes();
-                if data_bytes.len() > 0 {
+                if !data_bytes.is_empty() {
                     assert_ne!(data_bytes, &expected_ciphertext[..]);
                 }
 
@@ -260,9 +1236,198 @@ mod tests {
                 let mut expected = Vec::new();
                 File::open(&src)?.read_to_end(&mut expected)?;
 
-                Ok(assert_eq!(expected, result))
+                assert_eq!(expected, result);
+                Ok(())
             })
             .for_each(Result::unwrap);
         Ok(())
     }
+
+    #[test]
+    fn nonce_is_random() -> Result<(), Error> {
+        let key_hash = test_key_hash();
+        let data = b"the same plaintext";
+
+        let first = Encryptor::new(&data[..], &key_hash)?.as_vec()?;
+        let second = Encryptor::new(&data[..], &key_hash)?.as_vec()?;
+        assert_eq!(NONCE_LEN + data.len() + TAG_LEN, first.len());
+        assert_ne!(first, second);
+        assert_ne!(first[..NONCE_LEN], second[..NONCE_LEN]);
+        Ok(())
+    }
+
+    #[test]
+    fn any_modification_fails() -> Result<(), Error> {
+        let key_hash = test_key_hash();
+        let wrong_key_hash = hash_custom("aoisjfk2".as_bytes(), None, Some(HASH_NUM_ITER));
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let ciphertext = Encryptor::new(&data[..], &key_hash)?.as_vec()?;
+
+        let decrypt = |ciphertext: &[u8], key_hash: &[u8]| -> Result<Vec<u8>, Error> {
+            compose_encoders!(ciphertext, Decryptor => key_hash)?.as_vec()
+        };
+        assert_eq!(data, decrypt(&ciphertext, &key_hash)?);
+        assert!(decrypt(&ciphertext, &wrong_key_hash).is_err());
+
+        // the nonce, somewhere in the middle, and the tag
+        [0, NONCE_LEN + 5000, ciphertext.len() - 1]
+            .iter()
+            .for_each(|&i| {
+                let mut modified = ciphertext.clone();
+                modified[i] ^= 1;
+                let err = decrypt(&modified, &key_hash).unwrap_err();
+                assert_eq!(ErrorKind::InvalidData, err.kind());
+            });
+
+        [
+            0,
+            NONCE_LEN,
+            ciphertext.len() - TAG_LEN,
+            ciphertext.len() - 1,
+        ]
+        .iter()
+        .for_each(|&len| assert!(decrypt(&ciphertext[..len], &key_hash).is_err()));
+        Ok(())
+    }
+
+    #[test]
+    fn only_recipients_can_decrypt() -> Result<(), Error> {
+        let (alice, alice_public) = generate_x25519()?;
+        let (bob, bob_public) = generate_x25519()?;
+        let (mallory, _) = generate_x25519()?;
+        assert_eq!(alice_public, public_key_of(&alice)?);
+
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let ciphertext =
+            RecipientEncryptor::new(&data[..], &[alice_public, bob_public])?.as_vec()?;
+        let decrypt = |ciphertext: &[u8], identity| -> Result<Vec<u8>, Error> {
+            RecipientDecryptor::new(ciphertext, identity)?.as_vec()
+        };
+        assert_eq!(data, decrypt(&ciphertext, &alice)?);
+        assert_eq!(data, decrypt(&ciphertext, &bob)?);
+        assert_eq!(
+            ErrorKind::InvalidData,
+            decrypt(&ciphertext, &mallory).unwrap_err().kind()
+        );
+
+        // the ephemeral key, a wrapped file key, and the content
+        [
+            NONCE_LEN,
+            NONCE_LEN + X25519_KEY_LEN + 1,
+            ciphertext.len() - 1,
+        ]
+        .iter()
+        .for_each(|&i| {
+            let mut modified = ciphertext.clone();
+            modified[i] ^= 1;
+            assert!(decrypt(&modified, &alice).is_err());
+        });
+        assert!(RecipientEncryptor::new(&data[..], &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn stream_detects_truncation_and_reordering() -> Result<(), Error> {
+        let key_hash = test_key_hash();
+        let decrypt = |ciphertext: &[u8]| StreamDecryptor::new(ciphertext, &key_hash)?.as_vec();
+
+        for &len in [0, 1, SEGMENT_LEN, SEGMENT_LEN + 1, 3 * SEGMENT_LEN - 7].iter() {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let ciphertext = StreamEncryptor::new(&data[..], &key_hash)?.as_vec()?;
+            // a segment that is exactly full is the last one if nothing follows it
+            let num_segments = len.div_ceil(SEGMENT_LEN).max(1);
+            assert_eq!(NONCE_LEN + len + num_segments * TAG_LEN, ciphertext.len());
+            assert_eq!(data, decrypt(&ciphertext)?);
+        }
+
+        let data: Vec<u8> = (0..3 * SEGMENT_LEN).map(|i| (i % 251) as u8).collect();
+        let ciphertext = StreamEncryptor::new(&data[..], &key_hash)?.as_vec()?;
+        let segment = |i: usize| {
+            let start = NONCE_LEN + i * (SEGMENT_LEN + TAG_LEN);
+            &ciphertext[start..start + SEGMENT_LEN + TAG_LEN]
+        };
+
+        let mut flipped = ciphertext.clone();
+        flipped[NONCE_LEN + SEGMENT_LEN + TAG_LEN + 1] ^= 1;
+        let truncated = ciphertext[..NONCE_LEN + 2 * (SEGMENT_LEN + TAG_LEN)].to_vec();
+        let reordered = [&ciphertext[..NONCE_LEN], segment(1), segment(0), segment(2)].concat();
+        let other = StreamEncryptor::new(&data[..], &key_hash)?.as_vec()?;
+        let spliced = [
+            &ciphertext[..NONCE_LEN + SEGMENT_LEN + TAG_LEN],
+            &other[NONCE_LEN + SEGMENT_LEN + TAG_LEN..],
+        ]
+        .concat();
+        for modified in [flipped, truncated, reordered, spliced].iter() {
+            let mut decryptor = StreamDecryptor::new(&modified[..], &key_hash)?;
+            assert_eq!(
+                ErrorKind::InvalidData,
+                decryptor.as_vec().unwrap_err().kind()
+            );
+        }
+
+        // what comes before a modified segment can still be read, in constant memory
+        let mut flipped = ciphertext.clone();
+        flipped[ciphertext.len() - 1] ^= 1;
+        let mut decryptor = StreamDecryptor::new(&flipped[..], &key_hash)?;
+        let mut prefix = vec![0u8; 2 * SEGMENT_LEN];
+        decryptor.read_exact(&mut prefix)?;
+        assert_eq!(data[..2 * SEGMENT_LEN], prefix[..]);
+        assert!(decryptor.read(&mut prefix).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn siv_is_deterministic_and_authenticated() -> Result<(), Error> {
+        // RFC 5297, A.1
+        let key: Vec<u8> = (0xf0..=0xffu8).rev().chain(0xf0..=0xff).collect();
+        let associated_data: Vec<u8> = (0x10..0x28).collect();
+        let plaintext = [
+            0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+        ];
+        let expected = vec![
+            0x85, 0x63, 0x2d, 0x07, 0xc6, 0xe8, 0xf3, 0x7f, 0x95, 0x0a, 0xcd, 0x32, 0x0a, 0x2e,
+            0xcc, 0x93, 0x40, 0xc0, 0x2b, 0x96, 0x90, 0xc4, 0xdc, 0x04, 0xda, 0xef, 0x7f, 0x6a,
+            0xfe, 0x5c,
+        ];
+        assert_eq!(
+            expected,
+            siv_encrypt(&key, &[&associated_data], &plaintext)?
+        );
+        assert_eq!(
+            plaintext.to_vec(),
+            siv_decrypt(&key, &[&associated_data], &expected)?
+        );
+
+        let key_hash = test_key_hash();
+        let encrypt = |data: &[u8]| SivEncryptor::new(data, &key_hash)?.as_vec();
+        let decrypt = |data: &[u8]| SivDecryptor::new(data, &key_hash)?.as_vec();
+        for data in [
+            &b""[..],
+            b"a",
+            b"main.rs",
+            b"exactly 16 bytes",
+            b"longer than 16 bytes",
+        ]
+        .iter()
+        {
+            let ciphertext = encrypt(data)?;
+            assert_eq!(ciphertext, encrypt(data)?);
+            assert_eq!(data.to_vec(), decrypt(&ciphertext)?);
+
+            for i in 0..ciphertext.len() {
+                let mut modified = ciphertext.clone();
+                modified[i] ^= 1;
+                assert_eq!(
+                    ErrorKind::InvalidData,
+                    decrypt(&modified).unwrap_err().kind()
+                );
+            }
+        }
+
+        // unlike with `CfbEncryptor`, a shared prefix does not show
+        let (a, b) = (encrypt(b"prefix_a")?, encrypt(b"prefix_b")?);
+        assert_ne!(a[..SIV_LEN], b[..SIV_LEN]);
+        assert_ne!(a[SIV_LEN..SIV_LEN + 6], b[SIV_LEN..SIV_LEN + 6]);
+        Ok(())
+    }
 }
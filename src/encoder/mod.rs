@@ -1,6 +1,7 @@
 #[macro_use]
 pub mod cryptor;
 
+pub mod padder;
 pub mod text_decoder;
 pub mod text_encoder;
 pub mod zstd_decoder;
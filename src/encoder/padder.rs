@@ -0,0 +1,236 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::cmp::min;
+use std::io::Error;
+use std::io::Read;
+use std::str::FromStr;
+
+use crate::crypt::crypt_encoder::*;
+use crate::util::*;
+
+/// The first bytes of a zstd skippable frame, which every zstd decoder skips over.
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184d_2a50;
+
+/// Length of the header of a skippable frame, i.e. its magic and the length of its content.
+const SKIPPABLE_FRAME_HEADER_LEN: u64 = 8;
+
+// most content a single skippable frame can hold
+const MAX_SKIPPABLE_FRAME_LEN: u64 = u32::MAX as u64;
+
+/// How the compressed content of a file is padded before it is encrypted, so that the size of its
+/// ciphertext gives away less about the size of the file.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Padding {
+    // not at all, so every ciphertext is as long as its compressed content, plus a constant
+    #[default]
+    None,
+    // to the next Padmé length, i.e. keeping only the top `floor(log2(log2(len))) + 2` bits of the
+    // length significant, which adds at most 12%
+    Padme,
+    // to the next power of two, which adds up to 100%, but leaves only `log2(len)` to go by
+    PowerOfTwo,
+}
+
+impl Padding {
+    pub const NAMES: [&'static str; 3] = ["none", "padme", "power-of-two"];
+
+    /// # Returns
+    ///
+    /// The length that `len` bytes are padded to, never less than `len`.
+    pub fn padded_len(self, len: u64) -> u64 {
+        match self {
+            Padding::None => len,
+            Padding::Padme if len < 2 => len,
+            Padding::Padme => {
+                let exponent = 63 - u64::from(len.leading_zeros()); // floor(log2(len))
+                let num_significant_bits = 64 - u64::from(exponent.leading_zeros());
+                let mask = (1u64 << (exponent - num_significant_bits)) - 1;
+                len.saturating_add(mask) & !mask
+            }
+            Padding::PowerOfTwo => len.checked_next_power_of_two().unwrap_or(len),
+        }
+    }
+}
+
+impl FromStr for Padding {
+    type Err = Error;
+
+    fn from_str(padding: &str) -> Result<Self, Error> {
+        match padding {
+            "none" => Ok(Padding::None),
+            "padme" => Ok(Padding::Padme),
+            "power-of-two" => Ok(Padding::PowerOfTwo),
+            _ => Err(err!(
+                "`{}` is not one of `{}`",
+                padding,
+                Padding::NAMES.join("`, `")
+            )),
+        }
+    }
+}
+
+/// Pads a zstd stream, as `Padding` says, with skippable frames full of zeros, so that whatever
+/// decompresses it drops the padding along the way, without having to know about it.
+///
+/// The length of the stream is only known once it has been read to the end, so the padding
+/// follows it, and nothing is ever buffered.
+pub struct Padder<R>
+where
+    R: Read,
+{
+    source: R,
+    padding: Padding,
+    len: u64, // of what was read from `source` so far
+    // once `source` is done: the header of the skippable frame being read, the number of zeros
+    // left in it, and the number of bytes of padding left after it
+    opt_frame: Option<(Vec<u8>, u64, u64)>,
+}
+
+impl<R> Padder<R>
+where
+    R: Read,
+{
+    pub fn new(source: R, padding: Padding) -> Result<Self, Error> {
+        Ok(Self {
+            source,
+            padding,
+            len: 0,
+            opt_frame: None,
+        })
+    }
+
+    /// The next skippable frame of the `num_left` bytes of padding that are left, as the header,
+    /// the number of zeros in it, and the number of bytes left after it.
+    fn next_frame(num_left: u64) -> (Vec<u8>, u64, u64) {
+        if num_left == 0 {
+            return (Vec::new(), 0, 0);
+        }
+
+        let num_zeros = match num_left - SKIPPABLE_FRAME_HEADER_LEN {
+            num_zeros if num_zeros <= MAX_SKIPPABLE_FRAME_LEN => num_zeros,
+            // so that whatever is left still has room for a header of its own
+            num_zeros => min(
+                MAX_SKIPPABLE_FRAME_LEN,
+                num_zeros - SKIPPABLE_FRAME_HEADER_LEN,
+            ),
+        };
+        let mut header = SKIPPABLE_FRAME_MAGIC.to_le_bytes().to_vec();
+        header.extend_from_slice(&(num_zeros as u32).to_le_bytes());
+        let num_left_after = num_left - SKIPPABLE_FRAME_HEADER_LEN - num_zeros;
+        (header, num_zeros, num_left_after)
+    }
+}
+
+impl<R> Read for Padder<R>
+where
+    R: Read,
+{
+    fn read(&mut self, target: &mut [u8]) -> Result<usize, Error> {
+        if self.opt_frame.is_none() {
+            match self.source.read(target)? {
+                0 => {
+                    // there must be room for at least one frame header
+                    let num_padding = match self.padding {
+                        Padding::None => 0,
+                        padding => {
+                            padding.padded_len(self.len + SKIPPABLE_FRAME_HEADER_LEN) - self.len
+                        }
+                    };
+                    self.opt_frame = Some(Self::next_frame(num_padding));
+                }
+                bytes_read => {
+                    self.len += bytes_read as u64;
+                    return Ok(bytes_read);
+                }
+            }
+        }
+
+        loop {
+            let (header, num_zeros, num_left) = self.opt_frame.as_mut().unwrap();
+            if !header.is_empty() {
+                let num_bytes = min(header.len(), target.len());
+                target[..num_bytes].copy_from_slice(&header[..num_bytes]);
+                header.drain(..num_bytes);
+                return Ok(num_bytes);
+            }
+            if *num_zeros > 0 {
+                let num_bytes = min(*num_zeros, target.len() as u64) as usize;
+                target[..num_bytes].iter_mut().for_each(|byte| *byte = 0);
+                *num_zeros -= num_bytes as u64;
+                return Ok(num_bytes);
+            }
+            if *num_left == 0 {
+                return Ok(0);
+            }
+            self.opt_frame = Some(Self::next_frame(*num_left));
+        }
+    }
+}
+
+impl<R> CryptEncoder<R> for Padder<R> where R: Read {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::zstd_decoder::*;
+    use crate::encoder::zstd_encoder::*;
+
+    #[test]
+    fn padded_lens() {
+        for &len in [0u64, 1, 2, 9, 100, 1000, 65_537, 1 << 20, 123_456_789].iter() {
+            assert_eq!(len, Padding::None.padded_len(len));
+            assert!(len <= Padding::Padme.padded_len(len));
+            assert!(Padding::Padme.padded_len(len) <= len + len / 8 + 1);
+            assert!(len <= Padding::PowerOfTwo.padded_len(len));
+        }
+        assert_eq!(1024, Padding::PowerOfTwo.padded_len(1000));
+        assert_eq!(1 << 20, Padding::PowerOfTwo.padded_len(1 << 20));
+        // 9 = 0b1001 keeps 3 significant bits, 100 = 0b1100100 keeps 4, 1000 keeps 5
+        assert_eq!(10, Padding::Padme.padded_len(9));
+        assert_eq!(104, Padding::Padme.padded_len(100));
+        assert_eq!(1024, Padding::Padme.padded_len(1000));
+        assert_eq!(
+            Padding::Padme.padded_len(1000),
+            Padding::Padme.padded_len(1001)
+        );
+    }
+
+    #[test]
+    fn padding_is_dropped_by_zstd() -> Result<(), Error> {
+        for &padding in [Padding::None, Padding::Padme, Padding::PowerOfTwo].iter() {
+            for &len in [0u16, 1, 1000, 30_000].iter() {
+                let input_bytes = drng(len);
+                let compressed = ZstdEncoder::new(&input_bytes[..], None)?.as_vec()?;
+                let padded = Padder::new(&compressed[..], padding)?.as_vec()?;
+                match padding {
+                    Padding::None => assert_eq!(compressed, padded),
+                    _ => assert_eq!(
+                        padding.padded_len(compressed.len() as u64 + 8),
+                        padded.len() as u64
+                    ),
+                }
+
+                let decompressed = ZstdDecoder::new(&padded[..], None)?.as_vec()?;
+                assert_eq!(input_bytes, decompressed);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn huge_padding_is_split_into_frames() {
+        let num_padding = 2 * MAX_SKIPPABLE_FRAME_LEN + 20;
+        let (mut num_left, mut total_len, mut num_frames) = (num_padding, 0, 0);
+        while num_left > 0 {
+            let (header, num_zeros, num_left_after) = Padder::<&[u8]>::next_frame(num_left);
+            assert_eq!(SKIPPABLE_FRAME_HEADER_LEN, header.len() as u64);
+            assert!(num_zeros <= MAX_SKIPPABLE_FRAME_LEN);
+            total_len += header.len() as u64 + num_zeros;
+            num_left = num_left_after;
+            num_frames += 1;
+        }
+        assert_eq!(num_padding, total_len);
+        assert_eq!(3, num_frames);
+    }
+}
@@ -1,6 +1,5 @@
 use data_encoding::Encoding;
 use data_encoding_macro::*;
-use rayon::prelude::*;
 use std::io::Error;
 use std::io::Read;
 
@@ -58,7 +57,7 @@ where
                     Some(EncType::BASE64_PATHSAFE) => &BASE64_PATHSAFE,
                 }),
                 Some(Box::new(|encoding, data| {
-                    Ok(Vec::from(encoding.decode(data).map_err(io_err)?))
+                    encoding.decode(data).map_err(io_err)
                 })),
                 Some(Box::new(|encoding| {
                     // check that the encoding has 2^n number of symbols for some n
@@ -88,6 +87,7 @@ impl<R> CryptEncoder<R> for TextDecoder<R> where R: Read {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rayon::prelude::*;
 
     #[cfg(test)]
     mod base16 {
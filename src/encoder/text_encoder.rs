@@ -1,11 +1,8 @@
 use data_encoding::Encoding;
 use data_encoding_macro::*;
-use openssl::error::ErrorStack;
-use openssl::hash::hash;
-use openssl::hash::MessageDigest;
-use rayon::prelude::*;
 use std::cmp::min;
 use std::collections::VecDeque;
+use std::io::BufReader;
 use std::io::Bytes;
 use std::io::Error;
 use std::io::Read;
@@ -13,6 +10,7 @@ use std::io::Read;
 pub use crate::crypt::crypt_encoder::*;
 use crate::util::*;
 
+#[allow(dead_code, non_camel_case_types)]
 pub enum EncType {
     BASE16,
     BASE32,
@@ -46,15 +44,21 @@ const BASE64_PATHSAFE: Encoding = new_encoding! {
     padding: '=',
 };
 
+// encodes a chunk of bytes with the given encoding
+type EncoderFn = Box<dyn Fn(&Encoding, &[u8]) -> Result<Vec<u8>, Error>>;
+
+// computes the block size of the given encoding
+type BlockSizerFn = Box<dyn Fn(&Encoding) -> usize>;
+
 /// Customizable binary-to-text encoding
 pub struct TextEncoder<R>
 where
     R: Read,
 {
     encoding: Encoding, // what does the acutal encoding
-    encoder: Box<dyn Fn(&Encoding, &[u8]) -> Result<Vec<u8>, Error>>,
+    encoder: EncoderFn,
     block_size: usize, // min number of input bytes that encode to a pad-less output
-    source: Bytes<R>,
+    source: Bytes<BufReader<R>>,
 
     // buffers to hold leftovers from ...
     src_buf: VecDeque<u8>, // input bytes from the source
@@ -100,11 +104,11 @@ where
     pub fn new_custom(
         source: R,
         encoding: Option<&Encoding>,
-        encoder: Option<Box<dyn Fn(&Encoding, &[u8]) -> Result<Vec<u8>, Error>>>,
-        block_sizer: Option<Box<dyn Fn(&Encoding) -> usize>>,
+        encoder: Option<EncoderFn>,
+        block_sizer: Option<BlockSizerFn>,
         buf_size: Option<usize>,
     ) -> Result<Self, Error> {
-        let encoding = encoding.map(Encoding::clone).unwrap_or(BASE16.clone());
+        let encoding = encoding.cloned().unwrap_or(BASE16.clone());
         let encoder = encoder.unwrap_or(Box::new(|encoding, data| {
             Ok(Vec::from(encoding.encode(data).as_bytes()))
         }));
@@ -132,7 +136,7 @@ where
             block_size,
             encoding,
             encoder,
-            source: source.bytes(),
+            source: BufReader::new(source).bytes(),
             enc_buf: VecDeque::with_capacity(buf_size),
             src_buf: VecDeque::with_capacity(buf_size),
             src_pull_size,
@@ -194,9 +198,9 @@ where
 {
     fn read(&mut self, target: &mut [u8]) -> Result<usize, Error> {
         // try pushing enc buf
-        if self.enc_buf.len() == 0 {
+        if self.enc_buf.is_empty() {
             // try populating enc_buf
-            if self.src_buf.len() == 0 {
+            if self.src_buf.is_empty() {
                 self.replenish_src_buf()?;
             }
             self.replenish_enc_buf()?;
@@ -224,6 +228,7 @@ impl<R> CryptEncoder<R> for TextEncoder<R> where R: Read {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rayon::prelude::*;
 
     #[cfg(test)]
     mod base16 {
@@ -4,7 +4,6 @@ use std::io::Read;
 use zstd::stream::read::Decoder;
 
 use crate::crypt::crypt_encoder::*;
-use crate::encoder::zstd_encoder::*;
 
 pub struct ZstdDecoder<R>
 where
@@ -22,7 +22,7 @@ where
 {
     pub fn new(source: R, opt_level: Option<u8>) -> Result<Self, Error> {
         let level = opt_level.unwrap_or(DEFAULT_ZSTD_LEVEL);
-        assert!(0 <= level && level <= 22);
+        assert!(level <= 22);
         Ok(Self {
             encoder: Encoder::new(source, level as i32)?,
         })
@@ -1,38 +1,55 @@
-use rayon::prelude::*;
+use argon2::Algorithm;
+use argon2::Argon2;
+use argon2::Params;
+use argon2::Version;
+use data_encoding::HEXLOWER;
+use openssl::pkcs5::scrypt;
 use ring::digest;
 use ring::pbkdf2;
-use std::collections::HashMap;
-use std::collections::HashSet;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs::File;
 use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
 use std::num::NonZeroU32;
-
-use crate::encoder::text_decoder::*;
-use crate::util::*;
+use std::path::Path;
 
 const CREDENTIAL_LEN: usize = digest::SHA512_OUTPUT_LEN;
 
-const PBKDF2_NUM_ITER: u32 = 1 << 17; // 2^17 = 131,072
+pub const PBKDF2_NUM_ITER: u32 = 1 << 17; // 2^17 = 131,072
+
+pub const ARGON2_MEMORY_KIB: u32 = 1 << 16; // 64 MiB
+pub const ARGON2_TIME_COST: u32 = 3;
+pub const ARGON2_PARALLELISM: u32 = 4;
+
+pub const SCRYPT_LOG_N: u8 = 17; // 128 MiB with `SCRYPT_R`
+pub const SCRYPT_R: u32 = 8;
+pub const SCRYPT_P: u32 = 1;
+
+// upper bounds on the parameters, so that a tampered config cannot make hashing a key take
+// unbounded memory or time
+pub const KDF_MAX_MEMORY_KIB: u32 = 1 << 22; // 4 GiB
+pub const ARGON2_MAX_TIME_COST: u32 = 1 << 6;
+pub const ARGON2_MAX_PARALLELISM: u32 = 1 << 6;
+pub const SCRYPT_MAX_P: u32 = 1 << 6;
+pub const PBKDF2_MAX_NUM_ITER: u32 = 1 << 24;
+
+// lower bounds on the parameters, a quarter of the defaults, so that neither `init` nor a tampered
+// config can make a key cheap to guess
+pub const ARGON2_MIN_MEMORY_KIB: u32 = ARGON2_MEMORY_KIB / 4; // 16 MiB
+pub const ARGON2_MIN_TIME_COST: u32 = 1;
+pub const SCRYPT_MIN_MEMORY_KIB: u32 = 1 << 15; // 32 MiB
+pub const PBKDF2_MIN_NUM_ITER: u32 = PBKDF2_NUM_ITER / 4;
 
-const DEFAULT_SALT: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+/// The salt of every encrypted tree that was synced before `RepoConfig` existed; new trees get a
+/// random salt instead.
+pub const DEFAULT_SALT: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
 
 static PBKDF2_ALG: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA512;
 
 const_assert!(CREDENTIAL_LEN == 64);
 
-/// Hash input with default configs; calls `hash_custom` internally.
-///
-/// # Parameters
-///
-/// 1. `key`: the input bytes to hash
-///
-/// # Returns
-///
-/// `key` hashed with `DEFAULT_SALT` as salt, iterating `PBKDF2_NUM_ITER` times.
-#[inline]
-pub fn hash(key: &[u8]) -> Vec<u8> {
-    hash_custom(key, None, None)
-}
-
 /// Hash input with custom configs, using PBKDF2 with SHA512 internally.
 ///
 /// # Parameters
@@ -69,20 +86,311 @@ pub fn hash_custom(key: &[u8], opt_salt: Option<&[u8]>, opt_num_iter: Option<u32
     Vec::from(&to_store[..])
 }
 
-/// Hash input with default configs and encode it with path-safe BASE64; calls `hash` internally.
+/// Hash input with Argon2id, which is memory-hard, so guessing keys on GPUs gains little.
+///
+/// # Parameters
+///
+/// 1. `key`: the input bytes to hash
+/// 1. `salt`: salt to use, at least 8 bytes
+/// 1. `memory_kib`: memory to use, in KiB
+/// 1. `time_cost`: number of passes over that memory
+/// 1. `parallelism`: number of lanes
+///
+/// # Returns
+///
+/// `key` hashed into `CREDENTIAL_LEN` bytes, or an `ErrorKind::InvalidInput` error if the
+/// parameters are out of range.
+pub fn hash_argon2id(
+    key: &[u8],
+    salt: &[u8],
+    memory_kib: u32,
+    time_cost: u32,
+    parallelism: u32,
+) -> Result<Vec<u8>, Error> {
+    let invalid = |err| Error::new(ErrorKind::InvalidInput, format!("argon2id: {}", err));
+    let params =
+        Params::new(memory_kib, time_cost, parallelism, Some(CREDENTIAL_LEN)).map_err(invalid)?;
+
+    let mut to_store = [0u8; CREDENTIAL_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(key, salt, &mut to_store[..])
+        .map_err(invalid)?;
+    Ok(Vec::from(&to_store[..]))
+}
+
+/// Hash input with scrypt, which is memory-hard, so guessing keys on GPUs gains little.
+///
+/// # Parameters
+///
+/// 1. `key`: the input bytes to hash
+/// 1. `salt`: salt to use
+/// 1. `log_n`: log2 of the CPU/memory cost; uses `128 * r * 2^log_n` bytes of memory
+/// 1. `r`: block size
+/// 1. `p`: parallelism
+///
+/// # Returns
+///
+/// `key` hashed into `CREDENTIAL_LEN` bytes, or an `ErrorKind::InvalidInput` error if the
+/// parameters are out of range.
+pub fn hash_scrypt(key: &[u8], salt: &[u8], log_n: u8, r: u32, p: u32) -> Result<Vec<u8>, Error> {
+    let out_of_range = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("scrypt: log_n={}, r={}, p={} is out of range", log_n, r, p),
+        )
+    };
+    let n = 1u64.checked_shl(log_n.into()).ok_or_else(out_of_range)?;
+    let max_mem = scrypt_max_mem(log_n, r, p).ok_or_else(out_of_range)?;
+
+    let mut to_store = [0u8; CREDENTIAL_LEN];
+    scrypt(key, salt, n, r.into(), p.into(), max_mem, &mut to_store[..])
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, format!("scrypt: {}", err)))?;
+    Ok(Vec::from(&to_store[..]))
+}
+
+/// # Returns
+///
+/// The memory scrypt needs with these parameters, in bytes, plus a little more, since openssl
+/// refuses to use more than that; `None` if it does not fit in a `u64`.
+fn scrypt_max_mem(log_n: u8, r: u32, p: u32) -> Option<u64> {
+    let n = 1u64.checked_shl(log_n.into()).filter(|n| *n != 0)?;
+    let blocks = n.checked_add(p.into())?.checked_add(2)?;
+    128u64.checked_mul(r.into())?.checked_mul(blocks)
+}
+
+/// A KDF along with its parameters, i.e. everything needed to hash a key besides the salt.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "algorithm", rename_all = "kebab-case")]
+pub enum Kdf {
+    Argon2id {
+        memory_kib: u32,
+        time_cost: u32,
+        parallelism: u32,
+    },
+    Scrypt {
+        log_n: u8,
+        r: u32,
+        p: u32,
+    },
+    Pbkdf2Sha512 {
+        num_iter: u32,
+    },
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Kdf::Argon2id {
+            memory_kib: ARGON2_MEMORY_KIB,
+            time_cost: ARGON2_TIME_COST,
+            parallelism: ARGON2_PARALLELISM,
+        }
+    }
+}
+
+impl Kdf {
+    /// Names of the KDFs, as accepted by `with_params`.
+    pub const NAMES: [&'static str; 3] = ["argon2id", "scrypt", "pbkdf2"];
+
+    /// # Returns
+    ///
+    /// The name of the KDF, as accepted by `with_params`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kdf::Argon2id { .. } => Self::NAMES[0],
+            Kdf::Scrypt { .. } => Self::NAMES[1],
+            Kdf::Pbkdf2Sha512 { .. } => Self::NAMES[2],
+        }
+    }
+
+    /// A KDF with the default parameters, except for the ones that are given.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `name`: one of `NAMES`
+    /// 1. `opt_memory_mib`: memory to use, in MiB; for scrypt, rounded down to a power of two
+    /// 1. `opt_time_cost`: passes over the memory for argon2id, iterations for pbkdf2
+    /// 1. `opt_parallelism`: lanes for argon2id, `p` for scrypt
+    ///
+    /// # Returns
+    ///
+    /// The KDF, or an `ErrorKind::InvalidInput` error if `name` is unknown, or if some parameter
+    /// does not apply to it.
+    pub fn with_params(
+        name: &str,
+        opt_memory_mib: Option<u32>,
+        opt_time_cost: Option<u32>,
+        opt_parallelism: Option<u32>,
+    ) -> Result<Self, Error> {
+        let does_not_apply = |param: &str| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} does not take a {} parameter", name, param),
+            )
+        };
+
+        let kdf = match name {
+            "argon2id" => Kdf::Argon2id {
+                memory_kib: opt_memory_mib
+                    .map_or(ARGON2_MEMORY_KIB, |mib| mib.saturating_mul(1024)),
+                time_cost: opt_time_cost.unwrap_or(ARGON2_TIME_COST),
+                parallelism: opt_parallelism.unwrap_or(ARGON2_PARALLELISM),
+            },
+            "scrypt" if opt_time_cost.is_some() => return Err(does_not_apply("time")),
+            "scrypt" => Kdf::Scrypt {
+                // `128 * r * 2^log_n` bytes is `2^(log_n - 10)` MiB with `r` being 8
+                log_n: opt_memory_mib.map_or(SCRYPT_LOG_N, |mib| {
+                    (31 - mib.max(1).leading_zeros() + 10) as u8
+                }),
+                r: SCRYPT_R,
+                p: opt_parallelism.unwrap_or(SCRYPT_P),
+            },
+            "pbkdf2" if opt_memory_mib.is_some() => return Err(does_not_apply("memory")),
+            "pbkdf2" if opt_parallelism.is_some() => return Err(does_not_apply("parallelism")),
+            "pbkdf2" => Kdf::Pbkdf2Sha512 {
+                num_iter: opt_time_cost.unwrap_or(PBKDF2_NUM_ITER),
+            },
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("`{}` is not one of {:?}", name, Self::NAMES),
+                ))
+            }
+        };
+
+        kdf.check()?;
+        Ok(kdf)
+    }
+
+    /// # Returns
+    ///
+    /// An `ErrorKind::InvalidInput` error if the parameters are out of range, without hashing
+    /// anything.
+    pub fn check(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::new(ErrorKind::InvalidInput, message));
+        match *self {
+            Kdf::Argon2id {
+                memory_kib,
+                time_cost,
+                parallelism,
+            } if memory_kib > KDF_MAX_MEMORY_KIB
+                || time_cost > ARGON2_MAX_TIME_COST
+                || parallelism > ARGON2_MAX_PARALLELISM =>
+            {
+                invalid(format!(
+                    "argon2id: memory_kib={}, time_cost={}, parallelism={} exceeds the maximum \
+                     of {}, {}, {}",
+                    memory_kib,
+                    time_cost,
+                    parallelism,
+                    KDF_MAX_MEMORY_KIB,
+                    ARGON2_MAX_TIME_COST,
+                    ARGON2_MAX_PARALLELISM
+                ))
+            }
+            Kdf::Argon2id {
+                memory_kib,
+                time_cost,
+                ..
+            } if memory_kib < ARGON2_MIN_MEMORY_KIB || time_cost < ARGON2_MIN_TIME_COST => {
+                invalid(format!(
+                    "argon2id: memory_kib={}, time_cost={} is below the minimum of {}, {}",
+                    memory_kib, time_cost, ARGON2_MIN_MEMORY_KIB, ARGON2_MIN_TIME_COST
+                ))
+            }
+            Kdf::Argon2id {
+                memory_kib,
+                time_cost,
+                parallelism,
+            } => match Params::new(memory_kib, time_cost, parallelism, Some(CREDENTIAL_LEN)) {
+                Ok(_) => Ok(()),
+                Err(err) => invalid(format!("argon2id: {}", err)),
+            },
+            Kdf::Scrypt { log_n, r, p } => {
+                // `128 * r * 2^log_n` bytes, as in `hash_scrypt`
+                let memory = 1u64
+                    .checked_shl(log_n.into())
+                    .and_then(|n| n.checked_mul(128 * u64::from(r)));
+                let within_bounds = log_n > 0
+                    && r > 0
+                    && p > 0
+                    && p <= SCRYPT_MAX_P
+                    && memory.is_some_and(|mem| {
+                        mem >= u64::from(SCRYPT_MIN_MEMORY_KIB) * 1024
+                            && mem <= u64::from(KDF_MAX_MEMORY_KIB) * 1024
+                    });
+                if within_bounds {
+                    Ok(())
+                } else {
+                    invalid(format!(
+                        "scrypt: log_n={}, r={}, p={} is out of range; between {} and {} MiB of \
+                         memory, and at most p={}, are allowed",
+                        log_n,
+                        r,
+                        p,
+                        SCRYPT_MIN_MEMORY_KIB >> 10,
+                        KDF_MAX_MEMORY_KIB >> 10,
+                        SCRYPT_MAX_P
+                    ))
+                }
+            }
+            Kdf::Pbkdf2Sha512 { num_iter }
+                if !(PBKDF2_MIN_NUM_ITER..=PBKDF2_MAX_NUM_ITER).contains(&num_iter) =>
+            {
+                invalid(format!(
+                    "pbkdf2: needs between {} and {} iterations",
+                    PBKDF2_MIN_NUM_ITER, PBKDF2_MAX_NUM_ITER
+                ))
+            }
+            Kdf::Pbkdf2Sha512 { .. } => Ok(()),
+        }
+    }
+
+    /// Hash `key` with this KDF.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `key`: the input bytes to hash
+    /// 1. `salt`: salt to use, at least 16 bytes
+    pub fn hash(&self, key: &[u8], salt: &[u8]) -> Result<Vec<u8>, Error> {
+        self.check()?;
+        match *self {
+            Kdf::Argon2id {
+                memory_kib,
+                time_cost,
+                parallelism,
+            } => hash_argon2id(key, salt, memory_kib, time_cost, parallelism),
+            Kdf::Scrypt { log_n, r, p } => hash_scrypt(key, salt, log_n, r, p),
+            Kdf::Pbkdf2Sha512 { num_iter } => Ok(hash_custom(key, Some(salt), Some(num_iter))),
+        }
+    }
+}
+
+/// Hash the content of a file with SHA256, without reading it into memory all at once.
+///
+/// # Returns
 ///
-/// Path-safe encoding here is BASE64 that conforms to RFC4648, https://tools.ietf.org/search/rfc4648,
-/// with `/` replaced with `-`.
-#[inline]
-pub fn hash_base64_pathsafe(key: &[u8]) -> Result<String, Error> {
-    let hash = hash(key);
-    let encoding_type = Some(EncType::BASE64_PATHSAFE);
-    TextEncoder::new(&hash[..], encoding_type)?.as_string()
+/// The hash, encoded as lowercase hex.
+pub fn hash_file(path: &Path) -> Result<String, Error> {
+    const BUFFER_SIZE: usize = 1 << 16;
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+
+    let mut file = File::open(path)?;
+    let mut context = digest::Context::new(&digest::SHA256);
+    loop {
+        match file.read(&mut buffer[..])? {
+            0 => break,
+            bytes_read => context.update(&buffer[..bytes_read]),
+        }
+    }
+
+    Ok(HEXLOWER.encode(context.finish().as_ref()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rayon::prelude::*;
+    use std::collections::HashSet;
 
     fn test_data() -> Vec<&'static str> {
         vec!["", "a", "asf", "123", "asfoij123r98!@$%#@$Q%#$T"]
@@ -94,8 +402,130 @@ mod tests {
             let set: HashSet<_> = (0..4)
                 .map(|_| hash_custom(key.as_bytes(), None, Some(32)))
                 .collect();
-            assert_eq!(64, set.iter().nth(0).unwrap().len());
+            assert_eq!(64, set.iter().next().unwrap().len());
             assert_eq!(1, set.len());
         });
     }
+
+    #[test]
+    fn kdfs_are_deterministic_and_salted() -> Result<(), Error> {
+        let kdfs = vec![
+            Kdf::with_params("argon2id", Some(16), Some(1), Some(1))?,
+            Kdf::with_params("scrypt", Some(32), None, None)?,
+            Kdf::with_params("pbkdf2", None, Some(PBKDF2_MIN_NUM_ITER), None)?,
+        ];
+        for kdf in kdfs {
+            let hash = kdf.hash(b"password", &[0u8; 16])?;
+            assert_eq!(CREDENTIAL_LEN, hash.len());
+            assert_eq!(hash, kdf.hash(b"password", &[0u8; 16])?);
+            assert_ne!(hash, kdf.hash(b"password", &[1u8; 16])?);
+        }
+
+        // from RFC 7914
+        assert_eq!(
+            "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162\
+             2eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640",
+            HEXLOWER.encode(&hash_scrypt(b"password", b"NaCl", 10, 8, 16)?)
+        );
+
+        assert!(Kdf::with_params("bcrypt", None, None, None).is_err());
+        assert!(Kdf::with_params("pbkdf2", Some(64), None, None).is_err());
+        assert!(Kdf::with_params("argon2id", Some(0), None, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn huge_kdf_params_are_rejected() {
+        let kdfs = vec![
+            Kdf::Argon2id {
+                memory_kib: KDF_MAX_MEMORY_KIB + 1,
+                time_cost: 1,
+                parallelism: 1,
+            },
+            Kdf::Argon2id {
+                memory_kib: ARGON2_MEMORY_KIB,
+                time_cost: u32::MAX,
+                parallelism: 1,
+            },
+            Kdf::Scrypt {
+                log_n: 63,
+                r: u32::MAX,
+                p: u32::MAX,
+            },
+            Kdf::Scrypt {
+                log_n: 23,
+                r: SCRYPT_R,
+                p: 1,
+            },
+            Kdf::Scrypt {
+                log_n: SCRYPT_LOG_N,
+                r: SCRYPT_R,
+                p: SCRYPT_MAX_P + 1,
+            },
+            Kdf::Pbkdf2Sha512 {
+                num_iter: PBKDF2_MAX_NUM_ITER + 1,
+            },
+        ];
+        for kdf in kdfs {
+            let err = kdf.hash(b"password", &[0u8; 16]).unwrap_err();
+            assert_eq!(ErrorKind::InvalidInput, err.kind());
+        }
+
+        // errors instead of overflowing, even without `Kdf::check`
+        for &(log_n, r, p) in &[(63, u32::MAX, u32::MAX), (64, 1, 1), (u8::MAX, 1, 1)] {
+            let err = hash_scrypt(b"password", b"NaCl", log_n, r, p).unwrap_err();
+            assert_eq!(ErrorKind::InvalidInput, err.kind());
+        }
+        assert!(Kdf::with_params("scrypt", Some(KDF_MAX_MEMORY_KIB >> 10), None, None).is_ok());
+        assert!(Kdf::with_params("scrypt", Some(u32::MAX), None, None).is_err());
+    }
+
+    #[test]
+    fn tiny_kdf_params_are_rejected() {
+        let kdfs = vec![
+            Kdf::Argon2id {
+                memory_kib: ARGON2_MIN_MEMORY_KIB - 1,
+                time_cost: ARGON2_TIME_COST,
+                parallelism: ARGON2_PARALLELISM,
+            },
+            Kdf::Argon2id {
+                memory_kib: ARGON2_MEMORY_KIB,
+                time_cost: 0,
+                parallelism: ARGON2_PARALLELISM,
+            },
+            Kdf::Scrypt {
+                log_n: SCRYPT_LOG_N - 3,
+                r: SCRYPT_R,
+                p: SCRYPT_P,
+            },
+            Kdf::Scrypt {
+                log_n: SCRYPT_LOG_N,
+                r: 1,
+                p: SCRYPT_P,
+            },
+            Kdf::Pbkdf2Sha512 {
+                num_iter: PBKDF2_MIN_NUM_ITER - 1,
+            },
+        ];
+        for kdf in kdfs {
+            assert_eq!(ErrorKind::InvalidInput, kdf.check().unwrap_err().kind());
+        }
+
+        // as `csync init --kdf-*` would have them
+        assert!(Kdf::with_params("argon2id", Some(15), None, None).is_err());
+        assert!(Kdf::with_params("scrypt", Some(16), None, None).is_err());
+        assert!(Kdf::with_params("pbkdf2", None, Some(1), None).is_err());
+    }
+
+    #[test]
+    fn hash_file_is_sha256() {
+        let file = crate::util::mktemp_file("", "", None).unwrap();
+        std::fs::write(file.path(), "abc").unwrap();
+
+        // generated with sha256sum in GNU coreutils
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            hash_file(file.path()).unwrap()
+        );
+    }
 }
@@ -0,0 +1,271 @@
+use data_encoding::HEXLOWER;
+use std::env;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+use crate::encoder::cryptor::*;
+
+/// Where the key comes from; whatever the source, the key goes through the same KDF, so the same
+/// key results in the same encrypted tree.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeySource {
+    Prompt,          // typed in on the tty
+    File(PathBuf),   // the content of a file
+    Env(String),     // the value of an environment variable
+    Fd(RawFd),       // whatever can be read from an inherited file descriptor
+    Command(String), // the stdout of a shell command, e.g. `pass show backup`
+}
+
+impl KeySource {
+    /// Read the key, without the trailing newline that most files and commands end with, so that
+    /// e.g. `echo password > key_file` gives the same key as typing `password` in.
+    ///
+    /// # Parameters
+    ///
+    /// 1. `confirm`: whether to ask for the key twice, if it is typed in
+    ///
+    /// # Returns
+    ///
+    /// The key, which is never empty.
+    pub fn read_key(&self, confirm: bool) -> Result<Vec<u8>, Error> {
+        self.read("password", confirm)
+    }
+
+    /// Like `read_key`, but for a key that replaces the current one, so it is always confirmed if
+    /// it is typed in.
+    pub fn read_new_key(&self) -> Result<Vec<u8>, Error> {
+        self.read("new password", true)
+    }
+
+    fn read(&self, what: &str, confirm: bool) -> Result<Vec<u8>, Error> {
+        let key = match self {
+            KeySource::Prompt => return prompt_password(what, confirm).map(String::into_bytes),
+            KeySource::File(path) => read_all(File::open(path)?)?,
+            KeySource::Env(var) => env::var_os(var)
+                .map(|value| value.into_vec())
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("`{}` is not set", var)))?,
+            KeySource::Fd(fd) => {
+                if *fd < 0 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("`{}` is not a file descriptor", fd),
+                    ));
+                }
+                // the fd is inherited for the sole purpose of passing the key, so it is ours to
+                // close once it has been read
+                read_all(unsafe { File::from_raw_fd(*fd) })?
+            }
+            KeySource::Command(command) => run_command(command)?,
+        };
+
+        let key = strip_newline(key);
+        match key.is_empty() {
+            true => Err(Error::new(ErrorKind::InvalidData, "the key is empty")),
+            false => Ok(key),
+        }
+    }
+}
+
+/// Generate an identity, i.e. an X25519 keypair, and write its secret key to a new file at `path`
+/// that only its owner can read, along with its public key as a comment.
+///
+/// # Returns
+///
+/// The public key, hex encoded, to pass to `--recipient` when syncing.
+pub fn write_identity(path: &Path) -> Result<String, Error> {
+    let (secret, public) = generate_x25519()?;
+    let public = HEXLOWER.encode(&public);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(
+            format!("# public key: {}\n{}\n", public, HEXLOWER.encode(&secret)).as_bytes(),
+        )?;
+    Ok(public)
+}
+
+/// Read the secret key of an identity from a file written by `write_identity`; lines starting with
+/// `#` are ignored.
+pub fn read_identity(path: &Path) -> Result<[u8; X25519_KEY_LEN], Error> {
+    let content = String::from_utf8(read_all(File::open(path)?)?).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("`{:?}` is not an identity file", path),
+        )
+    })?;
+    let lines: Vec<&str> = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    match lines[..] {
+        [secret] => parse_x25519_key(secret),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("`{:?}` is not an identity file", path),
+        )),
+    }
+}
+
+/// Parse a hex encoded X25519 key, e.g. a public key given to `--recipient`.
+pub fn parse_x25519_key(hex: &str) -> Result<[u8; X25519_KEY_LEN], Error> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "`{}` is not a key; expected {} hex digits",
+                hex,
+                2 * X25519_KEY_LEN
+            ),
+        )
+    };
+    let bytes = HEXLOWER
+        .decode(hex.to_ascii_lowercase().as_bytes())
+        .map_err(|_| invalid())?;
+    match bytes.len() == X25519_KEY_LEN {
+        true => {
+            let mut key = [0u8; X25519_KEY_LEN];
+            key.copy_from_slice(&bytes);
+            Ok(key)
+        }
+        false => Err(invalid()),
+    }
+}
+
+/// Prompt for `what` on the tty without echoing it, optionally asking for it again as a
+/// confirmation.
+fn prompt_password(what: &str, confirm: bool) -> Result<String, Error> {
+    let password = rpassword::read_password_from_tty(Some(&format!("Enter your {}: ", what)))?;
+    if password.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "the password is empty"));
+    }
+
+    if confirm {
+        let confirmation =
+            rpassword::read_password_from_tty(Some(&format!("Confirm your {}: ", what)))?;
+        if password != confirmation {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the passwords do not match",
+            ));
+        }
+    }
+
+    Ok(password)
+}
+
+/// Run `command` with `sh -c`, letting it talk to the user through stderr, e.g. for a pinentry.
+fn run_command(command: &str) -> Result<Vec<u8>, Error> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+
+    match output.status.success() {
+        true => Ok(output.stdout),
+        false => Err(err!("`{}` failed with {}", command, output.status)),
+    }
+}
+
+#[inline]
+fn read_all<R>(mut source: R) -> Result<Vec<u8>, Error>
+where
+    R: Read,
+{
+    let mut buffer = Vec::new();
+    source.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Strip a single trailing `\n` or `\r\n`, if any.
+fn strip_newline(mut key: Vec<u8>) -> Vec<u8> {
+    if key.ends_with(b"\n") {
+        key.pop();
+        if key.ends_with(b"\r") {
+            key.pop();
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::*;
+    use std::fs::write;
+    use std::os::unix::io::IntoRawFd;
+
+    #[test]
+    fn every_source_gives_the_same_key() -> Result<(), Error> {
+        let dir = mktemp_dir("", "", None)?;
+        let key_file = dir.path().join("key");
+        write(&key_file, "password\n")?;
+        env::set_var("CSYNC_TEST_KEY", "password");
+
+        let sources = vec![
+            KeySource::File(key_file.clone()),
+            KeySource::Env(String::from("CSYNC_TEST_KEY")),
+            KeySource::Fd(File::open(&key_file)?.into_raw_fd()),
+            KeySource::Command(String::from("printf 'password\\r\\n'")),
+        ];
+        for source in sources {
+            assert_eq!(b"password".to_vec(), source.read_key(true)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bad_sources_fail() -> Result<(), Error> {
+        let dir = mktemp_dir("", "", None)?;
+        let empty_file = dir.path().join("empty");
+        write(&empty_file, "\n")?;
+
+        let sources = vec![
+            KeySource::File(dir.path().join("missing")),
+            KeySource::File(empty_file),
+            KeySource::Env(String::from("CSYNC_TEST_KEY_THAT_IS_NOT_SET")),
+            KeySource::Fd(-1),
+            KeySource::Command(String::from("echo password; exit 1")),
+        ];
+        for source in sources {
+            assert!(source.read_key(false).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn identity_round_trip() -> Result<(), Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = mktemp_dir("", "", None)?;
+        let path = dir.path().join("identity");
+        let public = write_identity(&path)?;
+        assert_eq!(0o600, path.metadata()?.permissions().mode() & 0o777);
+        assert_eq!(
+            parse_x25519_key(&public)?,
+            public_key_of(&read_identity(&path)?)?
+        );
+
+        // never overwritten, and only keys of the right length parse
+        assert!(write_identity(&path).is_err());
+        assert!(parse_x25519_key(&public[2..]).is_err());
+        assert!(parse_x25519_key("not hex").is_err());
+        Ok(())
+    }
+}
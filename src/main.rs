@@ -10,37 +10,426 @@ mod encoder;
 mod clargs;
 mod crypt;
 mod hasher;
+mod key_source;
+
+use std::fs::create_dir_all;
+use std::io::stdout;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::exit;
+use structopt::StructOpt;
+
+use crate::clargs::*;
+use crate::crypt::crypt_lister::*;
+use crate::crypt::crypt_rekeyer::*;
+use crate::crypt::crypt_restorer::*;
+use crate::crypt::crypt_syncer::*;
+use crate::crypt::crypt_verifier::*;
+use crate::crypt::crypt_watcher::*;
+use crate::crypt::index::*;
+use crate::crypt::integrity::*;
+use crate::crypt::key_schedule::*;
+use crate::crypt::repo_config::*;
+use crate::encoder::cryptor::X25519_KEY_LEN;
+use crate::encoder::padder::Padding;
+use crate::hasher::*;
+use crate::key_source::*;
 
 assert_cfg!(unix, "Only Unix systems are supported");
 
+// exit codes, following the conventions of `sysexits.h`
+const EX_USAGE: i32 = 64; // the command was used incorrectly
+const EX_DATAERR: i32 = 65; // the input data was incorrect in some way
+const EX_NOINPUT: i32 = 66; // an input file did not exist or was not readable
+const EX_CANTCREAT: i32 = 73; // an output file cannot be created
+const EX_IOERR: i32 = 74; // an error occurred while doing I/O
+const EX_NOPERM: i32 = 77; // insufficient permission to perform the operation
+
 fn main() {
-    /*
-    let file = File::open("/bigfile.txt");
-    let mut filesize = file.size();
-    while filesize > 0 {
-        aes_siv.encrypt(&file.read(1024));
-        filesize -= 1024;
+    let opts = Opts::from_args();
+    if let Err(err) = run(&opts) {
+        eprintln!("csync: {}", err);
+        exit(exit_code(&err));
+    }
+}
+
+fn run(opts: &Opts) -> Result<(), Error> {
+    match (&opts.command, &opts.source, &opts.out_dir) {
+        (
+            Some(Command::Init {
+                out_dir,
+                kdf,
+                memory_mib,
+                time_cost,
+                parallelism,
+                padding,
+                layout,
+            }),
+            _,
+            _,
+        ) => init(
+            out_dir,
+            Kdf::with_params(kdf, *memory_mib, *time_cost, *parallelism)?,
+            padding.parse()?,
+            layout.parse()?,
+            opts,
+        ),
+        (
+            Some(Command::Restore {
+                source,
+                out_dir,
+                path,
+            }),
+            _,
+            _,
+        ) => restore(source, out_dir, path.as_deref(), opts),
+        (Some(Command::Keygen { out }), _, _) => {
+            println!("{}", write_identity(out)?);
+            Ok(())
+        }
+        (Some(Command::Passwd { out_dir, new_key }), _, _) => {
+            passwd(out_dir, &new_key.key_source(), opts)
+        }
+        (Some(Command::Rekey { out_dir, new_key }), _, _) => {
+            check_exists(out_dir)?;
+            let key = opts.key_source().read_key(false)?;
+            rekey(out_dir, &key, &new_key.key_source())
+        }
+        (Some(Command::Slot { command }), _, _) => slot(command, opts),
+        (Some(Command::Verify { source, out_dir }), _, _) => verify(source, out_dir, opts),
+        (Some(Command::Root { out_dir }), _, _) => root(out_dir, opts),
+        (Some(Command::Ls { source, long }), _, _) => ls(source, *long, opts),
+        (Some(Command::Cat { source, path }), _, _) => cat_file(source, path, opts),
+        (None, Some(source), Some(out_dir)) => sync(source, out_dir, opts),
+        (None, _, _) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "both `<source>` and `--out <out_dir>` are required; see `csync --help`",
+        )),
+    }
+}
+
+fn init(
+    out_dir: &Path,
+    kdf: Kdf,
+    padding: Padding,
+    layout: Layout,
+    opts: &Opts,
+) -> Result<(), Error> {
+    let key = opts.key_source().read_key(true)?;
+    RepoConfig::init(out_dir, kdf, &key, padding, layout)?;
+    println!("initialized `{}`", out_dir.display());
+    Ok(())
+}
+
+fn passwd(out_dir: &Path, new_key_source: &KeySource, opts: &Opts) -> Result<(), Error> {
+    check_not_rekeying(out_dir)?;
+    let mut config = load_config(out_dir)?;
+    let key = opts.key_source().read_key(false)?;
+    let new_key = new_key_source.read_new_key()?;
+    config.change_key(&key, &new_key)?;
+    config.store(out_dir)
+}
+
+fn rekey(out_dir: &Path, key: &[u8], new_key_source: &KeySource) -> Result<(), Error> {
+    let config = RepoConfig::load_or_legacy(out_dir)?;
+    let rekeyer = CryptRekeyer::new(out_dir)?
+        .with_integrity(config.integrity)
+        .with_name_cipher(config.names)
+        .with_content_cipher(config.contents)
+        .with_layout(config.layout);
+    let new_key = new_key_source.read_new_key()?;
+    let opt_pending = rekeyer.pending()?;
+    let new_config = match &opt_pending {
+        Some(pending) => pending.clone(),
+        None => config.rekey(key, &new_key)?,
+    };
+
+    let keys = config.derive_keys(key)?;
+    let new_keys =
+        new_config
+            .derive_keys(&new_key)
+            .map_err(|err| match (err.kind(), &opt_pending) {
+                (ErrorKind::InvalidData, Some(_)) => Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                    "the rekey of `{:?}` in progress is under another new key; give the same one \
+                     to resume it",
+                    out_dir
+                ),
+                ),
+                _ => err,
+            })?;
+    rekeyer.rekey(&keys, &new_config, &new_keys)?;
+    println!("rekeyed `{}`", out_dir.display());
+
+    let dropped: Vec<String> = config
+        .slots
+        .iter()
+        .filter(|slot| slot.id != new_config.slots[0].id)
+        .map(|slot| slot.id.to_string())
+        .collect();
+    if !dropped.is_empty() {
+        eprintln!(
+            "csync: key slot(s) {} no longer unlock `{}`; add them again with `slot add`",
+            dropped.join(", "),
+            out_dir.display()
+        );
+    }
+    Ok(())
+}
+
+fn slot(command: &SlotCommand, opts: &Opts) -> Result<(), Error> {
+    match command {
+        SlotCommand::Add {
+            out_dir,
+            label,
+            new_key,
+        } => {
+            check_not_rekeying(out_dir)?;
+            let mut config = load_config(out_dir)?;
+            let key = opts.key_source().read_key(false)?;
+            let new_key = new_key.key_source().read_new_key()?;
+            let id = config.add_slot(&key, &new_key, label)?;
+            config.store(out_dir)?;
+            println!("added key slot {}", id);
+        }
+        SlotCommand::List { out_dir } => {
+            for slot in load_config(out_dir)?.slots {
+                println!("{:>4}  {:<8}  {}", slot.id, slot.kdf.name(), slot.label);
+            }
+        }
+        SlotCommand::Remove {
+            out_dir,
+            id,
+            rotate,
+            new_key,
+        } => {
+            check_not_rekeying(out_dir)?;
+            let mut config = load_config(out_dir)?;
+            let key = opts.key_source().read_key(false)?;
+            config.remove_slot(&key, *id)?;
+            config.store(out_dir)?;
+            println!("removed key slot {}", id);
+            if *rotate {
+                return rekey(out_dir, &key, &new_key.key_source());
+            }
+            eprintln!(
+                "csync: the master key is unchanged, so whoever held key slot {} can still decrypt \
+                 `{}` if they kept a copy of the master key or of the config; to rotate it, run \
+                 `csync rekey {}`",
+                id,
+                out_dir.display(),
+                out_dir.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn sync(source: &Path, out_dir: &Path, opts: &Opts) -> Result<(), Error> {
+    check_exists(source)?;
+    check_not_rekeying(out_dir)?;
+
+    let syncer = CryptSyncer::new(source, out_dir)?
+        .with_delete_policy(opts.delete_policy)
+        .with_recipients(opts.recipients.clone());
+    let key = opts.key_source().read_key(true)?;
+    let config = RepoConfig::load_or_init(out_dir, &key, !opts.dry_run)?;
+    let keys = config.derive_keys(&key)?;
+    let syncer = syncer
+        .with_integrity(config.integrity)
+        .with_name_cipher(config.names)
+        .with_content_cipher(config.contents)
+        .with_padding(config.padding)
+        .with_layout(config.layout);
+    match (opts.dry_run, opts.watch) {
+        (true, _) => {
+            println!("{}", syncer.plan(&keys)?);
+            Ok(())
+        }
+        (false, true) => CryptWatcher::new(source)?.watch(&syncer, &keys),
+        (false, false) => syncer.sync(&keys),
+    }
+}
+
+fn restore(
+    source: &Path,
+    out_dir: &Path,
+    opt_path: Option<&Path>,
+    opts: &Opts,
+) -> Result<(), Error> {
+    check_exists(source)?;
+
+    create_dir_all(out_dir)?;
+    let config = RepoConfig::load_or_legacy(source)?;
+    let mut restorer = CryptRestorer::new(source, out_dir)?
+        .with_name_cipher(config.names)
+        .with_content_cipher(config.contents)
+        .with_layout(config.layout);
+    if let Some(path) = opt_path {
+        restorer = restorer.with_path(path);
+    }
+    if let Some(identity) = opt_identity(opts)? {
+        restorer = restorer.with_identity(identity);
+    }
+    let keys = keys(opts, &config, false)?;
+    restorer.restore(&keys)
+}
+
+fn verify(source: &Path, out_dir: &Path, opts: &Opts) -> Result<(), Error> {
+    check_exists(source)?;
+    check_exists(out_dir)?;
+
+    let config = RepoConfig::load_or_legacy(out_dir)?;
+    let mut verifier = CryptVerifier::new(source, out_dir)?
+        .with_integrity(config.integrity)
+        .with_name_cipher(config.names)
+        .with_content_cipher(config.contents)
+        .with_layout(config.layout);
+    if let Some(identity) = opt_identity(opts)? {
+        verifier = verifier.with_identity(identity);
     }
-    */
+    let keys = keys(opts, &config, false)?;
+    let report = verifier.verify(&keys)?;
+    println!("{}", report);
+    match report.num_problems() {
+        0 => Ok(()),
+        num_problems => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("found {} problem(s) in `{:?}`", num_problems, out_dir),
+        )),
+    }
+}
 
-    // https://docs.rs/openssl/0.10.26/openssl/symm/index.html
-    /*
-        eprintln!("Enter your password:");
-        let key: String = read!("{}\n");
-        let key_bytes = key.as_bytes();
-        // TODO also ask for confirmation
-        let data = b"Some Crypto Text";
-        let encrypted = util::encrypt(&key_hash[..], data);
-    */
+fn root(out_dir: &Path, opts: &Opts) -> Result<(), Error> {
+    check_exists(out_dir)?;
+
+    let config = RepoConfig::load_or_legacy(out_dir)?;
+    let keys = keys(opts, &config, false)?;
+    let integrity = IntegrityManifest::load_expected(out_dir, &keys, config.integrity)?
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "`{:?}` has no integrity manifest yet; sync to it to create one",
+                    out_dir
+                ),
+            )
+        })?;
+    let tampered = integrity.check(out_dir)?;
+    for (target, tampering) in &tampered {
+        println!("tampered  {} ({})", target.display(), tampering.name());
+    }
+    println!("{}", integrity.root());
+    match tampered.len() {
+        0 => Ok(()),
+        num_tampered => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} ciphertext(s) in `{:?}` do not match its integrity manifest",
+                num_tampered, out_dir
+            ),
+        )),
+    }
+}
+
+fn ls(source: &Path, long: bool, opts: &Opts) -> Result<(), Error> {
+    check_exists(source)?;
+
+    let config = RepoConfig::load_or_legacy(source)?;
+    let lister = CryptLister::new(source)?
+        .with_name_cipher(config.names)
+        .with_layout(config.layout);
+    let keys = keys(opts, &config, false)?;
+    let entries = lister.list(&keys)?;
+    match long {
+        true => println!("{}", format_long(&entries)),
+        false => println!("{}", format_tree(&entries)),
+    }
+    Ok(())
+}
+
+fn cat_file(source: &Path, path: &Path, opts: &Opts) -> Result<(), Error> {
+    check_exists(source)?;
+
+    let config = RepoConfig::load_or_legacy(source)?;
+    let keys = keys(opts, &config, false)?;
+    let opt_identity = opt_identity(opts)?;
+    cat(
+        source,
+        path,
+        &keys,
+        config.names,
+        config.contents,
+        config.layout,
+        opt_identity.as_ref(),
+        &mut stdout().lock(),
+    )
+}
 
-    todo!();
+/// Load the config of an existing encrypted directory.
+fn load_config(out_dir: &Path) -> Result<RepoConfig, Error> {
+    check_exists(out_dir)?;
+    RepoConfig::load(out_dir)?.ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "`{:?}` has no config; is it an encrypted directory?",
+                out_dir
+            ),
+        )
+    })
+}
+
+fn check_exists(path: &Path) -> Result<(), Error> {
+    match path.exists() {
+        true => Ok(()),
+        false => Err(Error::new(
+            ErrorKind::NotFound,
+            format!("`{:?}` does not exist", path),
+        )),
+    }
+}
+
+/// Refuse to change an encrypted directory while a rekey of it is in progress, as the rekey would
+/// either undo the change or be undone by it.
+fn check_not_rekeying(out_dir: &Path) -> Result<(), Error> {
+    match rekey_in_progress(out_dir) {
+        false => Ok(()),
+        true => Err(err!(
+            "a rekey of `{:?}` is in progress; resume it with `rekey` first",
+            out_dir
+        )),
+    }
+}
+
+/// Read the key from wherever `opts` says, and derive the keys of the tree from it as `config`
+/// says.
+fn keys(opts: &Opts, config: &RepoConfig, confirm: bool) -> Result<Keys, Error> {
+    config.derive_keys(&opts.key_source().read_key(confirm)?)
+}
+
+/// Read the identity from the file given to `--identity`, if any.
+fn opt_identity(opts: &Opts) -> Result<Option<[u8; X25519_KEY_LEN]>, Error> {
+    opts.identity_file.as_deref().map(read_identity).transpose()
+}
+
+fn exit_code(err: &Error) -> i32 {
+    match err.kind() {
+        ErrorKind::InvalidInput => EX_USAGE,
+        ErrorKind::InvalidData => EX_DATAERR,
+        ErrorKind::NotFound => EX_NOINPUT,
+        ErrorKind::PermissionDenied => EX_NOPERM,
+        ErrorKind::AlreadyExists => EX_CANTCREAT,
+        _ => EX_IOERR,
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn os_is_unix() {
         assert!(cfg!(unix));
     }
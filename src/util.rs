@@ -3,19 +3,17 @@ use rand_chacha::rand_core::SeedableRng;
 use rand_chacha::ChaCha8Rng;
 use rayon::iter::ParallelBridge;
 use rayon::prelude::*;
-use rayon::prelude::*;
 use std::collections::HashSet;
 use std::env;
 use std::fmt::Debug;
+use std::fs::remove_dir_all;
+use std::fs::remove_file;
 use std::io::Bytes;
 use std::io::Read;
-use std::ops::Deref;
-use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
 use std::result::Result;
 use std::str;
-use tempfile;
 use tempfile::NamedTempFile;
 use tempfile::TempDir;
 use walkdir::WalkDir;
@@ -53,6 +51,7 @@ macro_rules! eprintln_then_none {
 /// # Returns
 ///
 /// `num_bytes` number of bytes in the range [32, 126].
+#[allow(dead_code)]
 pub fn drng(num_bytes: u16) -> Vec<u8> {
     let seed: [u8; 32] = [0; 32];
     let mut rng = ChaCha8Rng::from_seed(seed);
@@ -68,7 +67,7 @@ pub fn drng(num_bytes: u16) -> Vec<u8> {
 
     buffer
         .into_iter()
-        .map(|byte| byte as f64 / std::u8::MAX as f64)   // [0, 255] -> [0,1]
+        .map(|byte| byte as f64 / u8::MAX as f64)        // [0, 255] -> [0,1]
         .map(|ratio| width * ratio)                      // [0, 1] -> [0, 94]
         .map(|adjusted| (adjusted + left).round() as u8) // [0, 94] -> [32, 126]
         .collect()
@@ -83,6 +82,7 @@ pub fn drng(num_bytes: u16) -> Vec<u8> {
 /// The "minimum" set of directory paths in a sense that calling `mkdir -p` on each element in the
 /// set results in the minimum number of `mkdir` calls in order to create every directory in the
 /// set.
+#[allow(dead_code)]
 pub fn min_mkdir_set(root: &Path) -> HashSet<PathBuf> {
     // only select directories
     let all_dirs: HashSet<_> = find(root)
@@ -109,6 +109,52 @@ pub fn min_mkdir_set(root: &Path) -> HashSet<PathBuf> {
         .collect()
 }
 
+/// # Returns
+///
+/// The paths in `paths` that have no ancestor in `paths`, i.e. the roots of the subtrees that
+/// together contain every path in `paths`.
+pub fn outermost_paths(paths: &HashSet<PathBuf>) -> HashSet<PathBuf> {
+    paths
+        .par_iter()
+        .filter(|path| {
+            !path
+                .ancestors()
+                .skip(1)
+                .any(|ancestor| paths.contains(ancestor))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Remove `path`, be it a file or a directory; like `rm -rf`, it is not an error if `path` does
+/// not exist.
+pub fn remove_path(path: &Path) -> Result<(), Error> {
+    let result = match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => remove_dir_all(path),
+        Ok(_) => remove_file(path),
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        _ => result,
+    }
+}
+
+/// # Returns
+///
+/// The total size of the files at or below `path`, like `du -b`, or 0 if there are none.
+pub fn disk_usage(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .follow_links(false)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
 // trying to avoid bs buffer logic with this
 // try to pull `size` number of bytes from source
 // returns None instead of an empty vec because it looks cleaner when
@@ -202,4 +248,15 @@ mod tests {
             assert_eq!(1, rands.len());
         });
     }
+
+    #[test]
+    pub fn outermost_paths_drops_descendants() {
+        let paths: HashSet<_> = ["a/b", "a/b/c", "a/bc", "d", "d/e/f"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let expected: HashSet<_> = ["a/b", "a/bc", "d"].iter().map(PathBuf::from).collect();
+
+        assert_eq!(expected, outermost_paths(&paths));
+    }
 }